@@ -1,13 +1,48 @@
 pub use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, Statement};
 
 mod m20220101_000001_create_table;
+mod m20220801_000002_artist_optout;
+mod m20220805_000003_tags;
+mod m20220810_000004_search;
+mod m20220815_000005_gallery_visibility;
+mod m20220820_000006_web_sessions;
+mod m20220825_000007_admin;
+mod m20220901_000008_backfill_cursor;
+mod m20220905_000009_post_source_key;
+mod m20220910_000010_post_hashes;
+
+pub use m20220905_000009_post_source_key::attachment_id_from_url;
+
 pub struct Migrator;
 
+/// Drops an index by name. SeaQuery writes `DROP INDEX .. ON ..` for SQLite, which SQLite rejects.
+async fn drop_index(manager: &SchemaManager<'_>, name: &str) -> Result<(), DbErr> {
+    let stmt = Statement::from_string(manager.get_database_backend(), format!(r#"DROP INDEX "{}""#, name));
+    manager.get_connection().execute(stmt).await.map(|_| ())
+}
+
+/// Drops a column. SeaQuery refuses to for SQLite, which can since 3.35 as long as the column isn't indexed.
+async fn drop_column(manager: &SchemaManager<'_>, table: impl Iden, column: impl Iden) -> Result<(), DbErr> {
+    let sql = format!(r#"ALTER TABLE "{}" DROP COLUMN "{}""#, table.to_string(), column.to_string());
+    let stmt = Statement::from_string(manager.get_database_backend(), sql);
+    manager.get_connection().execute(stmt).await.map(|_| ())
+}
+
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
         vec![
             Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20220801_000002_artist_optout::Migration),
+            Box::new(m20220805_000003_tags::Migration),
+            Box::new(m20220810_000004_search::Migration),
+            Box::new(m20220815_000005_gallery_visibility::Migration),
+            Box::new(m20220820_000006_web_sessions::Migration),
+            Box::new(m20220825_000007_admin::Migration),
+            Box::new(m20220901_000008_backfill_cursor::Migration),
+            Box::new(m20220905_000009_post_source_key::Migration),
+            Box::new(m20220910_000010_post_hashes::Migration),
         ]
     }
 }
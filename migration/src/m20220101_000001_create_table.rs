@@ -1,4 +1,4 @@
-use sea_orm_migration::{prelude::*, sea_orm::{ConnectionTrait, Statement}};
+use sea_orm_migration::prelude::*;
 
 pub struct Migration;
 
@@ -8,54 +8,85 @@ impl MigrationName for Migration {
     }
 }
 
+#[derive(Iden)]
+enum Gallery {
+    Table,
+    Pk,
+    Name,
+    DiscordChannelId,
+    DateCreated
+}
+
+#[derive(Iden)]
+enum GalleryPost {
+    Table,
+    Pk,
+    Gallery,
+    DiscordMessageId,
+    SourceUrl,
+    MediaUrl,
+    MediaWidth,
+    MediaHeight,
+    ThumbnailUrl,
+    ThumbnailWidth,
+    ThumbnailHeight,
+    DateCreated
+}
+
 #[async_trait::async_trait]
 impl MigrationTrait for Migration {
     async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
-        let gallery_table_sql = r#"
-            CREATE TABLE "gallery" (
-                "pk" UUID NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
-                "name" TEXT NOT NULL,
-                "discord_channel_id" BIGINT NOT NULL,
-                "date_created" TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
-            );
-        "#;
-
-        let posts_table_sql = r#"
-            CREATE TABLE "gallery_post" (
-                "pk" UUID NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
-                "gallery" UUID NOT NULL,
-                "discord_message_id" BIGINT NOT NULL,
-                "source_url" TEXT,
-                "media_url" TEXT,
-                "media_width" INTEGER,
-                "media_height" INTEGER,
-                "thumbnail_url" TEXT,
-                "thumbnail_width" INTEGER,
-                "thumbnail_height" INTEGER,
-                "date_created" TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
-                CONSTRAINT fk_gallery FOREIGN KEY("gallery") REFERENCES "gallery"("pk")
-                    ON DELETE CASCADE
-                    ON UPDATE CASCADE
-            );
-        "#;
-
-        let gallery_index_sql = r#"CREATE INDEX "idx_gallery_discord_channel_id" ON gallery(discord_channel_id);"#;
-        let posts_index_sql = r#"CREATE INDEX "idk_gallery_post_discord_message_id" ON gallery_post(discord_message_id);"#;
-
-        let gallery_table_stmt = Statement::from_string(manager.get_database_backend(), gallery_table_sql.to_owned());
-        let posts_table_stmt = Statement::from_string(manager.get_database_backend(), posts_table_sql.to_owned());
-        let gallery_index_stmt = Statement::from_string(manager.get_database_backend(), gallery_index_sql.to_owned());
-        let posts_index_stmt = Statement::from_string(manager.get_database_backend(), posts_index_sql.to_owned());
-
-        manager.get_connection().execute(gallery_table_stmt).await?;
-        manager.get_connection().execute(posts_table_stmt).await?;
-        manager.get_connection().execute(gallery_index_stmt).await?;
-        manager.get_connection().execute(posts_index_stmt).await.map(|_| ())
+        // Primary keys are generated by galleria, not the database, since SQLite has no uuid function
+        manager.create_table(Table::create()
+            .table(Gallery::Table)
+            .col(ColumnDef::new(Gallery::Pk).uuid().not_null().primary_key())
+            .col(ColumnDef::new(Gallery::Name).text().not_null())
+            .col(ColumnDef::new(Gallery::DiscordChannelId).big_integer().not_null())
+            .col(ColumnDef::new(Gallery::DateCreated).timestamp_with_time_zone().not_null().extra("DEFAULT CURRENT_TIMESTAMP".to_owned()))
+            .to_owned()
+        ).await?;
+
+        manager.create_table(Table::create()
+            .table(GalleryPost::Table)
+            .col(ColumnDef::new(GalleryPost::Pk).uuid().not_null().primary_key())
+            .col(ColumnDef::new(GalleryPost::Gallery).uuid().not_null())
+            .col(ColumnDef::new(GalleryPost::DiscordMessageId).big_integer().not_null())
+            .col(ColumnDef::new(GalleryPost::SourceUrl).text())
+            .col(ColumnDef::new(GalleryPost::MediaUrl).text())
+            .col(ColumnDef::new(GalleryPost::MediaWidth).integer())
+            .col(ColumnDef::new(GalleryPost::MediaHeight).integer())
+            .col(ColumnDef::new(GalleryPost::ThumbnailUrl).text())
+            .col(ColumnDef::new(GalleryPost::ThumbnailWidth).integer())
+            .col(ColumnDef::new(GalleryPost::ThumbnailHeight).integer())
+            .col(ColumnDef::new(GalleryPost::DateCreated).timestamp_with_time_zone().not_null().extra("DEFAULT CURRENT_TIMESTAMP".to_owned()))
+            .foreign_key(ForeignKey::create()
+                .name("fk_gallery")
+                .from(GalleryPost::Table, GalleryPost::Gallery)
+                .to(Gallery::Table, Gallery::Pk)
+                .on_delete(ForeignKeyAction::Cascade)
+                .on_update(ForeignKeyAction::Cascade)
+            )
+            .to_owned()
+        ).await?;
+
+        manager.create_index(Index::create()
+            .name("idx_gallery_discord_channel_id")
+            .table(Gallery::Table)
+            .col(Gallery::DiscordChannelId)
+            .to_owned()
+        ).await?;
+
+        manager.create_index(Index::create()
+            .name("idk_gallery_post_discord_message_id")
+            .table(GalleryPost::Table)
+            .col(GalleryPost::DiscordMessageId)
+            .to_owned()
+        ).await
     }
 
     async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
-        manager.drop_table(Table::drop().table(Alias::new("gallery")).to_owned()).await?;
+        manager.drop_table(Table::drop().table(GalleryPost::Table).to_owned()).await?;
 
-        manager.drop_table(Table::drop().table(Alias::new("gallery_post")).to_owned()).await
+        manager.drop_table(Table::drop().table(Gallery::Table).to_owned()).await
     }
 }
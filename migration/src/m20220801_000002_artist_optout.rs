@@ -0,0 +1,72 @@
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220801_000002_artist_optout"
+    }
+}
+
+#[derive(Iden)]
+enum Gallery {
+    Table,
+    DiscordGuildId
+}
+
+#[derive(Iden)]
+enum GalleryPost {
+    Table,
+    DiscordAuthorId
+}
+
+#[derive(Iden)]
+enum ArtistOptout {
+    Table,
+    DiscordGuildId,
+    DiscordUserId,
+    DateCreated
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.alter_table(Table::alter()
+            .table(Gallery::Table)
+            .add_column(ColumnDef::new(Gallery::DiscordGuildId).big_integer())
+            .to_owned()
+        ).await?;
+
+        manager.alter_table(Table::alter()
+            .table(GalleryPost::Table)
+            .add_column(ColumnDef::new(GalleryPost::DiscordAuthorId).big_integer())
+            .to_owned()
+        ).await?;
+
+        manager.create_table(Table::create()
+            .table(ArtistOptout::Table)
+            .col(ColumnDef::new(ArtistOptout::DiscordGuildId).big_integer().not_null())
+            .col(ColumnDef::new(ArtistOptout::DiscordUserId).big_integer().not_null())
+            .col(ColumnDef::new(ArtistOptout::DateCreated).timestamp_with_time_zone().not_null().extra("DEFAULT CURRENT_TIMESTAMP".to_owned()))
+            .primary_key(Index::create().col(ArtistOptout::DiscordGuildId).col(ArtistOptout::DiscordUserId))
+            .to_owned()
+        ).await?;
+
+        manager.create_index(Index::create()
+            .name("idx_gallery_post_discord_author_id")
+            .table(GalleryPost::Table)
+            .col(GalleryPost::DiscordAuthorId)
+            .to_owned()
+        ).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table(Table::drop().table(ArtistOptout::Table).to_owned()).await?;
+
+        crate::drop_index(manager, "idx_gallery_post_discord_author_id").await?;
+
+        crate::drop_column(manager, GalleryPost::Table, GalleryPost::DiscordAuthorId).await?;
+
+        crate::drop_column(manager, Gallery::Table, Gallery::DiscordGuildId).await
+    }
+}
@@ -0,0 +1,78 @@
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220805_000003_tags"
+    }
+}
+
+#[derive(Iden)]
+enum GalleryPost {
+    Table,
+    Pk
+}
+
+#[derive(Iden)]
+enum Tag {
+    Table,
+    Pk,
+    Name,
+    DateCreated
+}
+
+#[derive(Iden)]
+enum GalleryPostTag {
+    Table,
+    Post,
+    Tag
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.create_table(Table::create()
+            .table(Tag::Table)
+            .col(ColumnDef::new(Tag::Pk).uuid().not_null().primary_key())
+            .col(ColumnDef::new(Tag::Name).text().not_null().unique_key())
+            .col(ColumnDef::new(Tag::DateCreated).timestamp_with_time_zone().not_null().extra("DEFAULT CURRENT_TIMESTAMP".to_owned()))
+            .to_owned()
+        ).await?;
+
+        manager.create_table(Table::create()
+            .table(GalleryPostTag::Table)
+            .col(ColumnDef::new(GalleryPostTag::Post).uuid().not_null())
+            .col(ColumnDef::new(GalleryPostTag::Tag).uuid().not_null())
+            .primary_key(Index::create().col(GalleryPostTag::Post).col(GalleryPostTag::Tag))
+            .foreign_key(ForeignKey::create()
+                .name("fk_post")
+                .from(GalleryPostTag::Table, GalleryPostTag::Post)
+                .to(GalleryPost::Table, GalleryPost::Pk)
+                .on_delete(ForeignKeyAction::Cascade)
+                .on_update(ForeignKeyAction::Cascade)
+            )
+            .foreign_key(ForeignKey::create()
+                .name("fk_tag")
+                .from(GalleryPostTag::Table, GalleryPostTag::Tag)
+                .to(Tag::Table, Tag::Pk)
+                .on_delete(ForeignKeyAction::Cascade)
+                .on_update(ForeignKeyAction::Cascade)
+            )
+            .to_owned()
+        ).await?;
+
+        manager.create_index(Index::create()
+            .name("idx_gallery_post_tag_tag")
+            .table(GalleryPostTag::Table)
+            .col(GalleryPostTag::Tag)
+            .to_owned()
+        ).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table(Table::drop().table(GalleryPostTag::Table).to_owned()).await?;
+
+        manager.drop_table(Table::drop().table(Tag::Table).to_owned()).await
+    }
+}
@@ -0,0 +1,128 @@
+use sea_orm_migration::{prelude::*, sea_orm::{ConnectionTrait, DbBackend, Statement}};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220810_000004_search"
+    }
+}
+
+#[derive(Iden)]
+enum GalleryPost {
+    Table,
+    Content,
+    AuthorName
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [GalleryPost::Content, GalleryPost::AuthorName] {
+            manager.alter_table(Table::alter()
+                .table(GalleryPost::Table)
+                .add_column(ColumnDef::new(column).text())
+                .to_owned()
+            ).await?;
+        }
+
+        // Full-text search is built on Postgres' text search, other databases search with LIKE instead
+        if manager.get_database_backend() != DbBackend::Postgres {
+            return Ok(());
+        }
+
+        let search_vector_column_sql = r#"ALTER TABLE "gallery_post" ADD COLUMN "search_vector" TSVECTOR;"#;
+
+        // Tags live in their own table, so the vector can't be a generated column.
+        // It is kept up to date by triggers on both gallery_post and gallery_post_tag instead.
+        let search_vector_function_sql = r#"
+            CREATE FUNCTION gallery_post_update_search_vector() RETURNS TRIGGER AS $$
+            DECLARE
+                source_domain TEXT := substring(NEW.source_url from '^[A-Za-z][A-Za-z0-9+.-]*://(?:www\.)?([^/:?#]+)');
+                post_tags TEXT := (
+                    SELECT string_agg("tag"."name", ' ')
+                    FROM "gallery_post_tag" JOIN "tag" ON "tag"."pk" = "gallery_post_tag"."tag"
+                    WHERE "gallery_post_tag"."post" = NEW.pk
+                );
+            BEGIN
+                NEW.search_vector :=
+                    setweight(to_tsvector('simple', coalesce(post_tags, '')), 'A') ||
+                    setweight(to_tsvector('simple', coalesce(NEW.author_name, '')), 'A') ||
+                    setweight(to_tsvector('simple', coalesce(NEW.content, '')), 'B') ||
+                    setweight(to_tsvector('simple', coalesce(source_domain || ' ' || replace(source_domain, '.', ' '), '')), 'C');
+                RETURN NEW;
+            END
+            $$ LANGUAGE plpgsql;
+        "#;
+
+        let post_trigger_sql = r#"
+            CREATE TRIGGER "gallery_post_search_vector_trigger"
+                BEFORE INSERT OR UPDATE OF "content", "author_name", "source_url" ON "gallery_post"
+                FOR EACH ROW EXECUTE FUNCTION gallery_post_update_search_vector();
+        "#;
+
+        let post_tag_function_sql = r#"
+            CREATE FUNCTION gallery_post_tag_refresh_search_vector() RETURNS TRIGGER AS $$
+            BEGIN
+                IF TG_OP = 'DELETE' THEN
+                    UPDATE "gallery_post" SET "content" = "content" WHERE "pk" = OLD.post;
+                ELSE
+                    UPDATE "gallery_post" SET "content" = "content" WHERE "pk" = NEW.post;
+                END IF;
+                RETURN NULL;
+            END
+            $$ LANGUAGE plpgsql;
+        "#;
+
+        let post_tag_trigger_sql = r#"
+            CREATE TRIGGER "gallery_post_tag_search_vector_trigger"
+                AFTER INSERT OR DELETE ON "gallery_post_tag"
+                FOR EACH ROW EXECUTE FUNCTION gallery_post_tag_refresh_search_vector();
+        "#;
+
+        let search_index_sql = r#"CREATE INDEX "idx_gallery_post_search_vector" ON gallery_post USING GIN(search_vector);"#;
+
+        // Fill in the vector for posts that were ingested before this migration
+        let backfill_sql = r#"UPDATE "gallery_post" SET "content" = "content";"#;
+
+        let statements = [
+            search_vector_column_sql,
+            search_vector_function_sql,
+            post_trigger_sql,
+            post_tag_function_sql,
+            post_tag_trigger_sql,
+            search_index_sql,
+            backfill_sql
+        ];
+
+        for sql in statements {
+            let stmt = Statement::from_string(manager.get_database_backend(), sql.to_owned());
+            manager.get_connection().execute(stmt).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if manager.get_database_backend() == DbBackend::Postgres {
+            let statements = [
+                r#"DROP TRIGGER "gallery_post_tag_search_vector_trigger" ON "gallery_post_tag";"#,
+                r#"DROP TRIGGER "gallery_post_search_vector_trigger" ON "gallery_post";"#,
+                r#"DROP FUNCTION gallery_post_tag_refresh_search_vector();"#,
+                r#"DROP FUNCTION gallery_post_update_search_vector();"#,
+                r#"ALTER TABLE "gallery_post" DROP COLUMN "search_vector";"#
+            ];
+
+            for sql in statements {
+                let stmt = Statement::from_string(manager.get_database_backend(), sql.to_owned());
+                manager.get_connection().execute(stmt).await?;
+            }
+        }
+
+        for column in [GalleryPost::AuthorName, GalleryPost::Content] {
+            crate::drop_column(manager, GalleryPost::Table, column).await?;
+        }
+
+        Ok(())
+    }
+}
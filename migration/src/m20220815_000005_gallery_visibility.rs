@@ -0,0 +1,43 @@
+use sea_orm_migration::{prelude::*, sea_orm::{ConnectionTrait, DbBackend, Statement}};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220815_000005_gallery_visibility"
+    }
+}
+
+#[derive(Iden)]
+enum Gallery {
+    Table,
+    Visibility
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.alter_table(Table::alter()
+            .table(Gallery::Table)
+            .add_column(ColumnDef::new(Gallery::Visibility).text().not_null().default("public"))
+            .to_owned()
+        ).await?;
+
+        // SQLite can't change constraints later on, so only Postgres checks the value, galleria always does
+        if manager.get_database_backend() == DbBackend::Postgres {
+            let constraint_sql = r#"
+                ALTER TABLE "gallery"
+                    ADD CONSTRAINT "chk_gallery_visibility" CHECK ("visibility" IN ('public', 'unlisted', 'private'));
+            "#;
+
+            let stmt = Statement::from_string(manager.get_database_backend(), constraint_sql.to_owned());
+            manager.get_connection().execute(stmt).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        crate::drop_column(manager, Gallery::Table, Gallery::Visibility).await
+    }
+}
@@ -0,0 +1,96 @@
+use sea_orm_migration::{prelude::*, sea_orm::{ConnectionTrait, DbBackend, Statement}};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220820_000006_web_sessions"
+    }
+}
+
+#[derive(Iden)]
+enum Gallery {
+    Table,
+    Visibility,
+    RequiredRoleId
+}
+
+#[derive(Iden)]
+enum WebSession {
+    Table,
+    Pk,
+    DiscordUserId,
+    DiscordUsername,
+    AccessToken,
+    DateExpires,
+    DateCreated
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.create_table(Table::create()
+            .table(WebSession::Table)
+            .col(ColumnDef::new(WebSession::Pk).text().not_null().primary_key())
+            .col(ColumnDef::new(WebSession::DiscordUserId).big_integer().not_null())
+            .col(ColumnDef::new(WebSession::DiscordUsername).text().not_null())
+            .col(ColumnDef::new(WebSession::AccessToken).text().not_null())
+            .col(ColumnDef::new(WebSession::DateExpires).timestamp_with_time_zone().not_null())
+            .col(ColumnDef::new(WebSession::DateCreated).timestamp_with_time_zone().not_null().extra("DEFAULT CURRENT_TIMESTAMP".to_owned()))
+            .to_owned()
+        ).await?;
+
+        manager.alter_table(Table::alter()
+            .table(Gallery::Table)
+            .add_column(ColumnDef::new(Gallery::RequiredRoleId).big_integer())
+            .to_owned()
+        ).await?;
+
+        manager.create_index(Index::create()
+            .name("idx_web_session_date_expires")
+            .table(WebSession::Table)
+            .col(WebSession::DateExpires)
+            .to_owned()
+        ).await?;
+
+        if manager.get_database_backend() == DbBackend::Postgres {
+            let visibility_sql = r#"
+                ALTER TABLE "gallery"
+                    DROP CONSTRAINT "chk_gallery_visibility",
+                    ADD CONSTRAINT "chk_gallery_visibility" CHECK ("visibility" IN ('public', 'unlisted', 'members', 'private'));
+            "#;
+
+            let stmt = Statement::from_string(manager.get_database_backend(), visibility_sql.to_owned());
+            manager.get_connection().execute(stmt).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table(Table::drop().table(WebSession::Table).to_owned()).await?;
+
+        // Member-only galleries fall back to private, the closest level that still exists
+        let members_to_private = Query::update()
+            .table(Gallery::Table)
+            .value(Gallery::Visibility, "private".into())
+            .and_where(Expr::col(Gallery::Visibility).eq("members"))
+            .to_owned();
+        manager.get_connection().execute(manager.get_database_backend().build(&members_to_private)).await?;
+
+        crate::drop_column(manager, Gallery::Table, Gallery::RequiredRoleId).await?;
+
+        if manager.get_database_backend() == DbBackend::Postgres {
+            let visibility_sql = r#"
+                ALTER TABLE "gallery"
+                    DROP CONSTRAINT "chk_gallery_visibility",
+                    ADD CONSTRAINT "chk_gallery_visibility" CHECK ("visibility" IN ('public', 'unlisted', 'private'));
+            "#;
+
+            let stmt = Statement::from_string(manager.get_database_backend(), visibility_sql.to_owned());
+            manager.get_connection().execute(stmt).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220825_000007_admin"
+    }
+}
+
+#[derive(Iden)]
+enum Gallery {
+    Table,
+    DateLastIngested,
+    IngestErrorCount,
+    LastIngestError
+}
+
+#[derive(Iden)]
+enum GalleryPost {
+    Table,
+    Hidden
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only takes one column per ALTER TABLE
+        let gallery_columns = [
+            ColumnDef::new(Gallery::DateLastIngested).timestamp_with_time_zone().to_owned(),
+            ColumnDef::new(Gallery::IngestErrorCount).integer().not_null().default(0).to_owned(),
+            ColumnDef::new(Gallery::LastIngestError).text().to_owned()
+        ];
+
+        for mut column in gallery_columns {
+            manager.alter_table(Table::alter()
+                .table(Gallery::Table)
+                .add_column(&mut column)
+                .to_owned()
+            ).await?;
+        }
+
+        manager.alter_table(Table::alter()
+            .table(GalleryPost::Table)
+            .add_column(ColumnDef::new(GalleryPost::Hidden).boolean().not_null().default(false))
+            .to_owned()
+        ).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [Gallery::DateLastIngested, Gallery::IngestErrorCount, Gallery::LastIngestError] {
+            crate::drop_column(manager, Gallery::Table, column).await?;
+        }
+
+        crate::drop_column(manager, GalleryPost::Table, GalleryPost::Hidden).await
+    }
+}
@@ -0,0 +1,31 @@
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220901_000008_backfill_cursor"
+    }
+}
+
+#[derive(Iden)]
+enum Gallery {
+    Table,
+    BackfillBefore
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // The oldest message an unfinished backfill got to, so it can continue from there
+        manager.alter_table(Table::alter()
+            .table(Gallery::Table)
+            .add_column(ColumnDef::new(Gallery::BackfillBefore).big_integer())
+            .to_owned()
+        ).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        crate::drop_column(manager, Gallery::Table, Gallery::BackfillBefore).await
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::{HashMap, HashSet};
+
+use sea_orm_migration::{prelude::*, sea_orm::{ConnectionTrait, prelude::Uuid}};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220905_000009_post_source_key"
+    }
+}
+
+#[derive(Iden)]
+enum GalleryPost {
+    Table,
+    Pk,
+    Gallery,
+    DiscordMessageId,
+    SourceUrl,
+    MediaUrl,
+    ThumbnailUrl,
+    DateCreated,
+    SourceKey
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Which attachment or embed of its message a post was made from, `attachment:<id>` or `embed:<index>`
+        manager.alter_table(Table::alter()
+            .table(GalleryPost::Table)
+            .add_column(ColumnDef::new(GalleryPost::SourceKey).text().not_null().default(""))
+            .to_owned()
+        ).await?;
+
+        fill_source_keys(manager).await?;
+
+        manager.create_index(Index::create()
+            .name("idx_gallery_post_source")
+            .table(GalleryPost::Table)
+            .col(GalleryPost::Gallery)
+            .col(GalleryPost::DiscordMessageId)
+            .col(GalleryPost::SourceKey)
+            .unique()
+            .to_owned()
+        ).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        crate::drop_index(manager, "idx_gallery_post_source").await?;
+
+        crate::drop_column(manager, GalleryPost::Table, GalleryPost::SourceKey).await
+    }
+}
+
+/// Works out the keys of existing posts, removing the duplicates that replayed messages left behind.
+///
+/// Attachment ids are part of their urls. Embeds are numbered in the order they were stored,
+/// which is the order Discord sent them in.
+async fn fill_source_keys(manager: &SchemaManager<'_>) -> Result<(), DbErr> {
+    let db = manager.get_connection();
+    let backend = manager.get_database_backend();
+
+    let select = Query::select()
+        .columns([
+            GalleryPost::Pk,
+            GalleryPost::Gallery,
+            GalleryPost::DiscordMessageId,
+            GalleryPost::SourceUrl,
+            GalleryPost::MediaUrl,
+            GalleryPost::ThumbnailUrl
+        ])
+        .from(GalleryPost::Table)
+        .order_by(GalleryPost::DateCreated, Order::Asc)
+        .order_by(GalleryPost::Pk, Order::Asc)
+        .to_owned();
+
+    let mut keys = HashMap::new();
+    let mut duplicates = Vec::new();
+    let mut seen = HashSet::new();
+    let mut embed_counts = HashMap::new();
+
+    for row in db.query_all(backend.build(&select)).await? {
+        let pk: Uuid = row.try_get("", "pk")?;
+        let gallery: Uuid = row.try_get("", "gallery")?;
+        let message_id: i64 = row.try_get("", "discord_message_id")?;
+        let source_url: Option<String> = row.try_get("", "source_url")?;
+        let media_url: Option<String> = row.try_get("", "media_url")?;
+        let thumbnail_url: Option<String> = row.try_get("", "thumbnail_url")?;
+
+        let attachment_id = match &source_url {
+            None => media_url.as_deref().and_then(attachment_id_from_url),
+            Some(_) => None
+        };
+        let identity = match attachment_id {
+            Some(attachment_id) => format!("attachment:{}", attachment_id),
+            None => format!("embed {:?} {:?} {:?}", source_url, media_url, thumbnail_url)
+        };
+
+        if !seen.insert((gallery, message_id, identity.clone())) {
+            duplicates.push(pk);
+            continue;
+        }
+
+        let key = match attachment_id {
+            Some(_) => identity,
+            None => {
+                let index = embed_counts.entry((gallery, message_id)).or_insert(0);
+                *index += 1;
+                format!("embed:{}", *index - 1)
+            }
+        };
+        keys.insert(pk, key);
+    }
+
+    if !duplicates.is_empty() {
+        let delete = Query::delete()
+            .from_table(GalleryPost::Table)
+            .and_where(Expr::col(GalleryPost::Pk).is_in(duplicates))
+            .to_owned();
+        db.execute(backend.build(&delete)).await?;
+    }
+
+    for (pk, key) in keys {
+        let update = Query::update()
+            .table(GalleryPost::Table)
+            .value(GalleryPost::SourceKey, key.into())
+            .and_where(Expr::col(GalleryPost::Pk).eq(pk))
+            .to_owned();
+        db.execute(backend.build(&update)).await?;
+    }
+
+    Ok(())
+}
+
+/// Attachment urls look like `https://cdn.discordapp.com/attachments/<channel id>/<attachment id>/<file name>`.
+/// Also used to key posts of archives exported before source keys existed.
+pub fn attachment_id_from_url(url: &str) -> Option<u64> {
+    let mut segments = url.split('/').skip_while(|segment| *segment != "attachments").skip(2);
+    segments.next()?.parse().ok()
+}
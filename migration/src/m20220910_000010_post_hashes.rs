@@ -0,0 +1,65 @@
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220910_000010_post_hashes"
+    }
+}
+
+#[derive(Iden)]
+enum GalleryPost {
+    Table,
+    ContentHash,
+    PerceptualHash,
+    DuplicateGroup
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // The SHA-256 of a post's image, its 64 bit difference hash, and the pk of the first post of the image
+        // when it was posted more than once. SQLite only takes one column per ALTER TABLE.
+        let columns = [
+            ColumnDef::new(GalleryPost::ContentHash).text().to_owned(),
+            ColumnDef::new(GalleryPost::PerceptualHash).big_integer().to_owned(),
+            ColumnDef::new(GalleryPost::DuplicateGroup).uuid().to_owned()
+        ];
+
+        for mut column in columns {
+            manager.alter_table(Table::alter()
+                .table(GalleryPost::Table)
+                .add_column(&mut column)
+                .to_owned()
+            ).await?;
+        }
+
+        manager.create_index(Index::create()
+            .name("idx_gallery_post_duplicate_group")
+            .table(GalleryPost::Table)
+            .col(GalleryPost::DuplicateGroup)
+            .to_owned()
+        ).await?;
+
+        // Exact re-uploads are looked up by their hash before any perceptual comparison
+        manager.create_index(Index::create()
+            .name("idx_gallery_post_content_hash")
+            .table(GalleryPost::Table)
+            .col(GalleryPost::ContentHash)
+            .to_owned()
+        ).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for index in ["idx_gallery_post_content_hash", "idx_gallery_post_duplicate_group"] {
+            crate::drop_index(manager, index).await?;
+        }
+
+        for column in [GalleryPost::ContentHash, GalleryPost::PerceptualHash, GalleryPost::DuplicateGroup] {
+            crate::drop_column(manager, GalleryPost::Table, column).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 //! SeaORM Entity. Generated by sea-orm-codegen 0.8.0
 
-use sea_orm::entity::prelude::*;
+use super::sea_orm_active_enums::Visibility;
+use chrono::Utc;
+use sea_orm::{entity::prelude::*, ActiveValue::Set};
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "gallery")]
@@ -10,7 +12,15 @@ pub struct Model {
     #[sea_orm(column_type = "Text")]
     pub name: String,
     pub discord_channel_id: i64,
+    pub discord_guild_id: Option<i64>,
     pub date_created: DateTimeUtc,
+    pub visibility: Visibility,
+    pub required_role_id: Option<i64>,
+    pub date_last_ingested: Option<DateTimeUtc>,
+    pub ingest_error_count: i32,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub last_ingest_error: Option<String>,
+    pub backfill_before: Option<i64>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -25,4 +35,13 @@ impl Related<super::gallery_post::Entity> for Entity {
     }
 }
 
-impl ActiveModelBehavior for ActiveModel {}
+/// Keys and creation dates are set here rather than by the database, which can't generate uuids on SQLite.
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            pk: Set(Uuid::new_v4()),
+            date_created: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
@@ -1,6 +1,7 @@
 //! SeaORM Entity. Generated by sea-orm-codegen 0.8.0
 
-use sea_orm::entity::prelude::*;
+use chrono::Utc;
+use sea_orm::{entity::prelude::*, ActiveValue::Set};
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "gallery_post")]
@@ -9,6 +10,11 @@ pub struct Model {
     pub pk: Uuid,
     pub gallery: Uuid,
     pub discord_message_id: i64,
+    pub discord_author_id: Option<i64>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub author_name: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub content: Option<String>,
     #[sea_orm(column_type = "Text", nullable)]
     pub source_url: Option<String>,
     #[sea_orm(column_type = "Text", nullable)]
@@ -20,6 +26,13 @@ pub struct Model {
     pub thumbnail_width: Option<i32>,
     pub thumbnail_height: Option<i32>,
     pub date_created: DateTimeUtc,
+    pub hidden: bool,
+    #[sea_orm(column_type = "Text")]
+    pub source_key: String,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub content_hash: Option<String>,
+    pub perceptual_hash: Option<i64>,
+    pub duplicate_group: Option<Uuid>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -32,6 +45,8 @@ pub enum Relation {
         on_delete = "Cascade"
     )]
     Gallery,
+    #[sea_orm(has_many = "super::gallery_post_tag::Entity")]
+    GalleryPostTag,
 }
 
 impl Related<super::gallery::Entity> for Entity {
@@ -40,4 +55,29 @@ impl Related<super::gallery::Entity> for Entity {
     }
 }
 
-impl ActiveModelBehavior for ActiveModel {}
+impl Related<super::gallery_post_tag::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::GalleryPostTag.def()
+    }
+}
+
+impl Related<super::tag::Entity> for Entity {
+    fn to() -> RelationDef {
+        super::gallery_post_tag::Relation::Tag.def()
+    }
+
+    fn via() -> Option<RelationDef> {
+        Some(super::gallery_post_tag::Relation::GalleryPost.def().rev())
+    }
+}
+
+/// Keys and creation dates are set here rather than by the database, which can't generate uuids on SQLite.
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            pk: Set(Uuid::new_v4()),
+            date_created: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
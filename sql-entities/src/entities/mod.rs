@@ -2,6 +2,11 @@
 
 pub mod prelude;
 
+pub mod artist_optout;
 pub mod gallery;
 pub mod gallery_post;
+pub mod gallery_post_tag;
+pub mod sea_orm_active_enums;
 pub mod seaql_migrations;
+pub mod tag;
+pub mod web_session;
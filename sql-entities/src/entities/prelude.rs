@@ -1,5 +1,9 @@
 //! SeaORM Entity. Generated by sea-orm-codegen 0.8.0
 
+pub use super::artist_optout::Entity as ArtistOptout;
 pub use super::gallery::Entity as Gallery;
 pub use super::gallery_post::Entity as GalleryPost;
+pub use super::gallery_post_tag::Entity as GalleryPostTag;
 pub use super::seaql_migrations::Entity as SeaqlMigrations;
+pub use super::tag::Entity as Tag;
+pub use super::web_session::Entity as WebSession;
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use futures::StreamExt;
+use sea_orm::prelude::Uuid;
+use serde::Serialize;
+use sql_entities::{gallery, gallery_post};
+use tokio::sync::mpsc;
+use tracing::{debug, warn, instrument};
+use warp::hyper::body::Bytes;
+
+/// Chunks of an archive on their way to the client. Closed once the archive is complete.
+pub type ArchiveSender = mpsc::Sender<Result<Bytes, std::io::Error>>;
+
+/// How many chunks can wait for a slow client before fetching media pauses.
+pub const ARCHIVE_CHANNEL_CAPACITY: usize = 8;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+/// Sizes and CRCs follow the data in a data descriptor, and names are UTF-8.
+const ENTRY_FLAGS: u16 = 0x0008 | 0x0800;
+const ZIP_VERSION: u16 = 20;
+const DATA_DESCRIPTOR_LENGTH: u64 = 16;
+
+/// Writes a ZIP archive as a sequence of chunks, so it can be streamed while its entries are still being fetched.
+/// Entries are stored uncompressed, the media in a gallery is compressed already.
+/// ZIP64 isn't supported, so archives are limited to 4 GiB and 65535 entries.
+#[derive(Default)]
+pub struct ZipWriter {
+    offset: u64,
+    entries: Vec<CentralEntry>
+}
+
+struct CentralEntry {
+    name: String,
+    dos_time: u16,
+    dos_date: u16,
+    crc32: u32,
+    size: u32,
+    offset: u32
+}
+
+/// An entry whose data is being written.
+pub struct ZipEntry {
+    name: String,
+    dos_time: u16,
+    dos_date: u16,
+    offset: u64,
+    hasher: crc32fast::Hasher,
+    size: u64
+}
+
+impl ZipEntry {
+    /// Accounts for a chunk of the entry's data, which is written to the archive as is.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+    }
+}
+
+impl ZipWriter {
+    /// Starts an entry, returning it along with its local header.
+    pub fn start_entry(&mut self, name: &str, modified: DateTime<Utc>) -> Result<(ZipEntry, Vec<u8>)> {
+        if self.entries.len() >= u16::MAX as usize {
+            anyhow::bail!("ZIP archives without ZIP64 can't hold more than {} entries.", u16::MAX);
+        }
+
+        let (dos_time, dos_date) = to_dos_date_time(modified);
+        let mut header = Vec::with_capacity(30 + name.len());
+        put_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut header, ZIP_VERSION);
+        put_u16(&mut header, ENTRY_FLAGS);
+        put_u16(&mut header, 0); // Stored
+        put_u16(&mut header, dos_time);
+        put_u16(&mut header, dos_date);
+        put_u32(&mut header, 0); // CRC, compressed and uncompressed size are in the data descriptor
+        put_u32(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 0); // No extra fields
+        header.extend_from_slice(name.as_bytes());
+
+        let entry = ZipEntry {
+            name: name.to_owned(),
+            dos_time,
+            dos_date,
+            offset: self.offset,
+            hasher: crc32fast::Hasher::new(),
+            size: 0
+        };
+        self.offset += header.len() as u64;
+
+        Ok((entry, header))
+    }
+
+    /// Finishes an entry once all its data was written, returning its data descriptor.
+    pub fn finish_entry(&mut self, entry: ZipEntry) -> Result<Vec<u8>> {
+        let offset = u32::try_from(entry.offset)
+            .map_err(|_| anyhow::anyhow!("ZIP archives without ZIP64 can't be larger than 4 GiB."))?;
+        let size = u32::try_from(entry.size)
+            .map_err(|_| anyhow::anyhow!("{} is too large for a ZIP archive without ZIP64.", entry.name))?;
+        let crc32 = entry.hasher.finalize();
+
+        let mut descriptor = Vec::with_capacity(DATA_DESCRIPTOR_LENGTH as usize);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, crc32);
+        put_u32(&mut descriptor, size);
+        put_u32(&mut descriptor, size);
+
+        self.offset += entry.size + DATA_DESCRIPTOR_LENGTH;
+        self.entries.push(CentralEntry {
+            name: entry.name,
+            dos_time: entry.dos_time,
+            dos_date: entry.dos_date,
+            crc32,
+            size,
+            offset
+        });
+
+        Ok(descriptor)
+    }
+
+    /// Writes an entry whose data is already at hand.
+    pub fn write_entry(&mut self, name: &str, modified: DateTime<Utc>, data: &[u8]) -> Result<Vec<u8>> {
+        let (mut entry, mut chunk) = self.start_entry(name, modified)?;
+        entry.update(data);
+        chunk.extend_from_slice(data);
+        chunk.extend(self.finish_entry(entry)?);
+        Ok(chunk)
+    }
+
+    /// Returns the central directory, which ends the archive.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        let directory_offset = u32::try_from(self.offset)
+            .map_err(|_| anyhow::anyhow!("ZIP archives without ZIP64 can't be larger than 4 GiB."))?;
+
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            put_u32(&mut directory, CENTRAL_HEADER_SIGNATURE);
+            put_u16(&mut directory, ZIP_VERSION); // Made by
+            put_u16(&mut directory, ZIP_VERSION); // Needed to extract
+            put_u16(&mut directory, ENTRY_FLAGS);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, entry.dos_time);
+            put_u16(&mut directory, entry.dos_date);
+            put_u32(&mut directory, entry.crc32);
+            put_u32(&mut directory, entry.size);
+            put_u32(&mut directory, entry.size);
+            put_u16(&mut directory, entry.name.len() as u16);
+            put_u16(&mut directory, 0); // Extra field length
+            put_u16(&mut directory, 0); // Comment length
+            put_u16(&mut directory, 0); // Disk number
+            put_u16(&mut directory, 0); // Internal attributes
+            put_u32(&mut directory, 0); // External attributes
+            put_u32(&mut directory, entry.offset);
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let directory_size = directory.len() as u32;
+        put_u32(&mut directory, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        put_u16(&mut directory, 0); // This disk
+        put_u16(&mut directory, 0); // Disk with the central directory
+        put_u16(&mut directory, self.entries.len() as u16);
+        put_u16(&mut directory, self.entries.len() as u16);
+        put_u32(&mut directory, directory_size);
+        put_u32(&mut directory, directory_offset);
+        put_u16(&mut directory, 0); // Comment length
+
+        Ok(directory)
+    }
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+/// MS-DOS dates start in 1980 and have a two second resolution.
+fn to_dos_date_time(date: DateTime<Utc>) -> (u16, u16) {
+    let year = date.year().clamp(1980, 2107) as u16;
+    let time = ((date.hour() as u16) << 11) | ((date.minute() as u16) << 5) | (date.second() as u16 / 2);
+    let date = ((year - 1980) << 9) | ((date.month() as u16) << 5) | date.day() as u16;
+    (time, date)
+}
+
+/// A line of the archive's manifest, describing one post.
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    /// The media's name in the archive. Missing when it couldn't be downloaded.
+    file: Option<String>,
+    post: Uuid,
+    post_url: String,
+    author: Option<String>,
+    content: Option<String>,
+    tags: Vec<String>,
+    source_url: Option<String>,
+    media_url: String,
+    date_created: String,
+    error: Option<String>
+}
+
+const MANIFEST_CSV_HEADER: &str = "file,post,post_url,author,date_created,tags,source_url,media_url,content,error\r\n";
+
+impl ManifestEntry {
+    fn to_csv_row(&self) -> String {
+        let fields = [
+            self.file.clone().unwrap_or_default(),
+            self.post.to_string(),
+            self.post_url.clone(),
+            self.author.clone().unwrap_or_default(),
+            self.date_created.clone(),
+            self.tags.join(" "),
+            self.source_url.clone().unwrap_or_default(),
+            self.media_url.clone(),
+            self.content.clone().unwrap_or_default(),
+            self.error.clone().unwrap_or_default()
+        ];
+
+        let mut row = fields.iter().map(|field| csv_field(field)).collect::<Vec<String>>().join(",");
+        row.push_str("\r\n");
+        row
+    }
+}
+
+/// Quotes a CSV field when it needs to be, as RFC 4180 describes.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Streams a ZIP archive of the media of `posts`, oldest first, into `sender`, followed by
+/// `manifest.json` and `manifest.csv` describing each post. Media is passed through as it downloads.
+/// Stops early when the client goes away.
+#[instrument(skip_all, fields(gallery_id = %gallery_model.pk, posts = posts.len()))]
+pub async fn write_gallery_archive(
+    base_url: &str,
+    gallery_model: &gallery::Model,
+    posts: Vec<gallery_post::Model>,
+    tags_by_post: HashMap<Uuid, Vec<String>>,
+    sender: ArchiveSender
+) {
+    if let Err(why) = write_archive_entries(base_url, gallery_model, posts, tags_by_post, &sender).await {
+        warn!("Archive of gallery {} was cut short: {:?}", gallery_model.pk, why);
+        // The client sees an incomplete download rather than an archive that looks whole
+        let _ = sender.send(Err(std::io::Error::other(why.to_string()))).await;
+    }
+}
+
+async fn write_archive_entries(
+    base_url: &str,
+    gallery_model: &gallery::Model,
+    posts: Vec<gallery_post::Model>,
+    tags_by_post: HashMap<Uuid, Vec<String>>,
+    sender: &ArchiveSender
+) -> Result<()> {
+    let client = reqwest::Client::new();
+    let mut zip = ZipWriter::default();
+    let mut manifest = Vec::with_capacity(posts.len());
+
+    for (index, post) in posts.iter().enumerate() {
+        let media_url = match post.media_url.as_ref().or(post.thumbnail_url.as_ref()) {
+            Some(media_url) => media_url,
+            None => continue
+        };
+
+        let file_name = archive_file_name(index + 1, post, media_url);
+        let mut entry = ManifestEntry {
+            file: Some(file_name.clone()),
+            post: post.pk,
+            post_url: format!("{}/gallery/{}/post/{}", base_url, gallery_model.pk, post.pk),
+            author: post.author_name.clone(),
+            content: post.content.clone(),
+            tags: tags_by_post.get(&post.pk).cloned().unwrap_or_default(),
+            source_url: post.source_url.clone(),
+            media_url: media_url.clone(),
+            date_created: post.date_created.to_rfc3339(),
+            error: None
+        };
+
+        // Media that can't be fetched is left out, the manifest says why
+        let response = match client.get(media_url).send().await.and_then(|response| response.error_for_status()) {
+            Ok(response) => response,
+            Err(why) => {
+                debug!("Could not download {} for the archive of gallery {}: {:?}", media_url, gallery_model.pk, why);
+                entry.file = None;
+                entry.error = Some(why.to_string());
+                manifest.push(entry);
+                continue;
+            }
+        };
+
+        let (mut zip_entry, header) = zip.start_entry(&file_name, post.date_created)?;
+        send_chunk(sender, header).await?;
+
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    zip_entry.update(&chunk);
+                    send_chunk(sender, chunk).await?;
+                },
+                // The entry is already under way, so it ends with what was received
+                Err(why) => {
+                    warn!("Download of {} for the archive of gallery {} failed midway: {:?}", media_url, gallery_model.pk, why);
+                    entry.error = Some(format!("Incomplete download: {}", why));
+                    break;
+                }
+            }
+        }
+
+        send_chunk(sender, zip.finish_entry(zip_entry)?).await?;
+        manifest.push(entry);
+    }
+
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    send_chunk(sender, zip.write_entry("manifest.json", Utc::now(), &manifest_json)?).await?;
+
+    let manifest_csv = std::iter::once(MANIFEST_CSV_HEADER.to_owned())
+        .chain(manifest.iter().map(ManifestEntry::to_csv_row))
+        .collect::<String>();
+    send_chunk(sender, zip.write_entry("manifest.csv", Utc::now(), manifest_csv.as_bytes())?).await?;
+
+    send_chunk(sender, zip.finish()?).await
+}
+
+async fn send_chunk(sender: &ArchiveSender, chunk: impl Into<Bytes>) -> Result<()> {
+    sender.send(Ok(chunk.into())).await
+        .map_err(|_| anyhow::anyhow!("The client stopped downloading."))
+}
+
+/// Names media `<number>_<artist>_<post>.<extension>`, so files sort in posting order and stay unique.
+pub fn archive_file_name(number: usize, post: &gallery_post::Model, media_url: &str) -> String {
+    let author = post.author_name.as_deref()
+        .map(sanitize_file_name)
+        .filter(|author| !author.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    let path = media_url.split(['?', '#']).next().unwrap_or_default();
+    let extension = path.rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .filter(|extension| extension.len() <= 5 && extension.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or_else(|| "bin".to_owned());
+
+    let post_id = post.pk.to_string();
+    format!("{:04}_{}_{}.{}", number, author, &post_id[..8], extension)
+}
+
+/// Keeps letters, digits, `-` and `_`, replacing anything else with dashes.
+pub fn sanitize_file_name(name: &str) -> String {
+    let sanitized = name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .take(32)
+        .collect::<String>();
+
+    sanitized.trim_matches('-').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn zip_archives_can_be_read_back() {
+        let modified = Utc.with_ymd_and_hms(2022, 9, 14, 18, 30, 43).unwrap();
+        let mut writer = ZipWriter::default();
+        let mut archive = Vec::new();
+
+        // Streamed in chunks, like downloaded media
+        let (mut entry, header) = writer.start_entry("0001_artist_1a2b3c4d.png", modified).unwrap();
+        archive.extend(header);
+        for chunk in [&b"first "[..], &b"chunk"[..]] {
+            entry.update(chunk);
+            archive.extend_from_slice(chunk);
+        }
+        archive.extend(writer.finish_entry(entry).unwrap());
+
+        archive.extend(writer.write_entry("manifest.json", modified, b"[]").unwrap());
+        archive.extend(writer.finish().unwrap());
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(zip.len(), 2);
+
+        let mut contents = String::new();
+        let mut media = zip.by_index(0).unwrap();
+        assert_eq!(media.name(), "0001_artist_1a2b3c4d.png");
+        media.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "first chunk");
+
+        let modified = media.last_modified();
+        assert_eq!((modified.year(), modified.month(), modified.day()), (2022, 9, 14));
+        // DOS times round down to even seconds
+        assert_eq!((modified.hour(), modified.minute(), modified.second()), (18, 30, 42));
+        drop(media);
+
+        contents.clear();
+        zip.by_name("manifest.json").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "[]");
+    }
+
+    #[test]
+    fn dos_dates_are_clamped_to_1980() {
+        let (time, date) = to_dos_date_time(Utc.with_ymd_and_hms(1970, 1, 1, 0, 0, 0).unwrap());
+        assert_eq!(time, 0);
+        assert_eq!(date >> 9, 0);
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("plain text"), "plain text");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("fox, in snow"), "\"fox, in snow\"");
+        assert_eq!(csv_field("the \"fox\""), "\"the \"\"fox\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("carriage\rreturn"), "\"carriage\rreturn\"");
+    }
+}
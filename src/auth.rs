@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration as StdDuration, Instant};
+
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, prelude::Uuid};
+use serde::Deserialize;
+use serenity::http::Http;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use sha2::Sha256;
+use sql_entities::{gallery, web_session};
+use tracing::{debug, error, info, instrument};
+
+use crate::bot::member_permissions_in;
+
+/// How long a login lasts, unless Discord expires the access token sooner.
+const SESSION_DAYS: i64 = 7;
+
+/// How long the result of a membership check is reused before Discord is asked again.
+const MEMBERSHIP_CACHE_TTL: StdDuration = StdDuration::from_secs(5 * 60);
+
+/// How many membership checks are remembered. Expired ones are dropped once it's reached, then the oldest.
+const MEMBERSHIP_CACHE_LIMIT: usize = 10_000;
+
+/// Only the user's identity and their membership in guilds are needed.
+const OAUTH_SCOPES: &str = "identify guilds.members.read";
+
+/// Where to reach the OAuth2 provider. The urls default to Discord's, but can point to a mock provider for testing.
+pub struct DiscordAuthConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub authorize_url: String,
+    pub token_url: String,
+    pub api_url: String
+}
+
+impl DiscordAuthConfig {
+    pub const DEFAULT_AUTHORIZE_URL: &'static str = "https://discord.com/oauth2/authorize";
+    pub const DEFAULT_TOKEN_URL: &'static str = "https://discord.com/api/oauth2/token";
+    pub const DEFAULT_API_URL: &'static str = "https://discord.com/api/v10";
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<i64>
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordUser {
+    id: String,
+    username: String
+}
+
+#[derive(Debug, Deserialize)]
+struct GuildMember {
+    roles: Vec<String>
+}
+
+/// Signs viewers in with Discord and checks whether they may view member-only galleries.
+pub struct DiscordAuth {
+    config: DiscordAuthConfig,
+    redirect_url: String,
+    client: reqwest::Client,
+    bot_http: Arc<Http>,
+    db: Arc<DatabaseConnection>,
+    membership_cache: Mutex<HashMap<(String, Uuid), (bool, Instant)>>
+}
+
+impl DiscordAuth {
+    pub fn new(config: DiscordAuthConfig, base_url: &str, bot_http: Arc<Http>, db: Arc<DatabaseConnection>) -> Self {
+        DiscordAuth {
+            config,
+            redirect_url: format!("{}/auth/callback", base_url),
+            client: reqwest::Client::new(),
+            bot_http,
+            db,
+            membership_cache: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Where the provider sends viewers back to, on this instance.
+    pub fn redirect_url(&self) -> &str {
+        &self.redirect_url
+    }
+
+    /// The url of the provider's consent screen. `state` is echoed back to the callback.
+    pub fn authorize_url(&self, state: &str) -> String {
+        format!(
+            "{}?response_type=code&client_id={}&scope={}&state={}&redirect_uri={}",
+            self.config.authorize_url,
+            urlencoding::encode(&self.config.client_id),
+            urlencoding::encode(OAUTH_SCOPES),
+            urlencoding::encode(state),
+            urlencoding::encode(&self.redirect_url)
+        )
+    }
+
+    /// Exchanges the code the provider sent to the callback for an access token and starts a session for its user.
+    #[instrument(skip_all)]
+    pub async fn login(&self, code: &str) -> Result<web_session::Model> {
+        let token: TokenResponse = self.client.post(&self.config.token_url)
+            .form(&[
+                ("client_id", self.config.client_id.as_str()),
+                ("client_secret", self.config.client_secret.as_str()),
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.redirect_url.as_str())
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let user: DiscordUser = self.client.get(format!("{}/users/@me", self.config.api_url))
+            .bearer_auth(&token.access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let session_length = token.expires_in
+            .map(|seconds| Duration::seconds(seconds).min(Duration::days(SESSION_DAYS)))
+            .unwrap_or_else(|| Duration::days(SESSION_DAYS));
+
+        // Expired sessions are cleaned up as new ones are made
+        web_session::Entity::delete_many()
+            .filter(web_session::Column::DateExpires.lte(Utc::now()))
+            .exec(self.db.as_ref())
+            .await?;
+
+        let session = web_session::ActiveModel {
+            pk: ActiveValue::Set(random_token()),
+            discord_user_id: ActiveValue::Set(user.id.parse()?),
+            discord_username: ActiveValue::Set(user.username),
+            access_token: ActiveValue::Set(token.access_token),
+            date_expires: ActiveValue::Set(Utc::now() + session_length),
+            date_created: ActiveValue::Set(Utc::now())
+        }.insert(self.db.as_ref()).await?;
+
+        info!("User {} signed in.", session.discord_user_id);
+        Ok(session)
+    }
+
+    /// Loads an unexpired session.
+    pub async fn find_session(&self, session_id: &str) -> Result<Option<web_session::Model>> {
+        Ok(web_session::Entity::find_by_id(session_id.to_owned())
+            .filter(web_session::Column::DateExpires.gt(Utc::now()))
+            .one(self.db.as_ref())
+            .await?)
+    }
+
+    pub async fn logout(&self, session_id: &str) -> Result<()> {
+        web_session::Entity::delete_by_id(session_id.to_owned())
+            .exec(self.db.as_ref())
+            .await?;
+        self.membership_cache.lock().unwrap().retain(|(cached_session, _), _| cached_session != session_id);
+
+        Ok(())
+    }
+
+    /// Whether the session's user is a member of the gallery's guild, has its required role if any,
+    /// and can read the gallery's channel. Failed checks count as not allowed.
+    pub async fn can_view(&self, session: &web_session::Model, gallery_model: &gallery::Model) -> bool {
+        let cache_key = (session.pk.clone(), gallery_model.pk);
+        if let Some((allowed, checked)) = self.membership_cache.lock().unwrap().get(&cache_key) {
+            if checked.elapsed() < MEMBERSHIP_CACHE_TTL {
+                return *allowed;
+            }
+        }
+
+        match self.check_membership(session, gallery_model).await {
+            Ok(allowed) => {
+                debug!("User {} can view gallery {}: {}", session.discord_user_id, gallery_model.pk, allowed);
+                remember_membership(&mut self.membership_cache.lock().unwrap(), cache_key, allowed);
+                allowed
+            },
+            // Not remembered, so a failed lookup doesn't lock the viewer out until the cache expires
+            Err(why) => {
+                error!("Error checking if user {} can view gallery {}: {:?}", session.discord_user_id, gallery_model.pk, why);
+                false
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip_all, fields(gallery_id = %gallery_model.pk))]
+    async fn check_membership(&self, session: &web_session::Model, gallery_model: &gallery::Model) -> Result<bool> {
+        let guild_id = match gallery_model.discord_guild_id {
+            Some(guild_id) => guild_id as u64,
+            None => return Ok(false)
+        };
+
+        let response = self.client.get(format!("{}/users/@me/guilds/{}/member", self.config.api_url, guild_id))
+            .bearer_auth(&session.access_token)
+            .send()
+            .await?;
+
+        // Discord answers 404 Unknown Guild for guilds the user isn't in
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let member: GuildMember = response.error_for_status()?.json().await?;
+
+        if let Some(role_id) = gallery_model.required_role_id {
+            if !member.roles.contains(&role_id.to_string()) {
+                return Ok(false);
+            }
+        }
+
+        let permissions = member_permissions_in(
+            &self.bot_http,
+            GuildId(guild_id),
+            ChannelId(gallery_model.discord_channel_id as u64),
+            UserId(session.discord_user_id as u64)
+        ).await?;
+
+        Ok(permissions.view_channel() && permissions.read_message_history())
+    }
+}
+
+fn remember_membership(cache: &mut HashMap<(String, Uuid), (bool, Instant)>, key: (String, Uuid), allowed: bool) {
+    if cache.len() >= MEMBERSHIP_CACHE_LIMIT && !cache.contains_key(&key) {
+        cache.retain(|_, (_, checked)| checked.elapsed() < MEMBERSHIP_CACHE_TTL);
+    }
+    if cache.len() >= MEMBERSHIP_CACHE_LIMIT && !cache.contains_key(&key) {
+        let oldest = cache.iter()
+            .min_by_key(|(_, (_, checked))| *checked)
+            .map(|(oldest, _)| oldest.clone());
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+
+    cache.insert(key, (allowed, Instant::now()));
+}
+
+/// The token admin forms carry to show they were sent from a page of the admin area.
+/// It's an HMAC keyed with the session id, which other sites can't read from the HttpOnly cookie.
+pub fn csrf_token(session: &web_session::Model) -> String {
+    base64::encode_config(csrf_mac(session).finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+pub fn verify_csrf_token(session: &web_session::Model, token: &str) -> bool {
+    match base64::decode_config(token, base64::URL_SAFE_NO_PAD) {
+        Ok(token) => csrf_mac(session).verify_slice(&token).is_ok(),
+        Err(_) => false
+    }
+}
+
+fn csrf_mac(session: &web_session::Model) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(session.pk.as_bytes())
+        .expect("HMAC accepts keys of any length.");
+    mac.update(b"galleria admin form");
+    mac
+}
+
+/// An unguessable, url-safe token for session ids and OAuth2 states.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::Filter;
+
+    use super::*;
+    use crate::test_db::{self, insert_gallery};
+
+    /// An OAuth2 provider that hands out `token` for the code `good-code` and knows a single user.
+    fn mock_provider() -> DiscordAuthConfig {
+        let token = warp::post()
+            .and(warp::path("token"))
+            .and(warp::body::form::<HashMap<String, String>>())
+            .map(|form: HashMap<String, String>| match form.get("code").map(String::as_str) {
+                Some("good-code") => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({ "access_token": "token", "expires_in": 3600 })),
+                    warp::http::StatusCode::OK
+                ),
+                _ => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({ "error": "invalid_grant" })),
+                    warp::http::StatusCode::BAD_REQUEST
+                )
+            });
+        let user = warp::path!("api" / "users" / "@me")
+            .and(warp::header::exact("authorization", "Bearer token"))
+            .map(|| warp::reply::json(&serde_json::json!({ "id": "100", "username": "viewer" })));
+
+        let (addr, server) = warp::serve(token.or(user)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        DiscordAuthConfig {
+            client_id: "client".to_owned(),
+            client_secret: "secret".to_owned(),
+            authorize_url: format!("http://{}/authorize", addr),
+            token_url: format!("http://{}/token", addr),
+            api_url: format!("http://{}/api", addr)
+        }
+    }
+
+    async fn discord_auth() -> DiscordAuth {
+        let (db, _) = test_db::sqlite().await;
+        DiscordAuth::new(mock_provider(), "http://galleria.test", Arc::new(Http::new("token")), Arc::new(db))
+    }
+
+    #[tokio::test]
+    async fn logins_start_sessions_for_the_provider_user() {
+        let discord_auth = discord_auth().await;
+
+        let session = discord_auth.login("good-code").await.unwrap();
+        assert_eq!(session.discord_user_id, 100);
+        assert_eq!(session.discord_username, "viewer");
+        // The session ends with the access token, not after SESSION_DAYS
+        assert!(session.date_expires <= Utc::now() + Duration::hours(1));
+
+        let found = discord_auth.find_session(&session.pk).await.unwrap();
+        assert_eq!(found.map(|found| found.pk), Some(session.pk.clone()));
+
+        discord_auth.logout(&session.pk).await.unwrap();
+        assert!(discord_auth.find_session(&session.pk).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejected_codes_start_no_session() {
+        let discord_auth = discord_auth().await;
+
+        assert!(discord_auth.login("bad-code").await.is_err());
+        assert_eq!(web_session::Entity::find().all(discord_auth.db.as_ref()).await.unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn csrf_tokens_belong_to_their_session() {
+        let discord_auth = discord_auth().await;
+        let session = discord_auth.login("good-code").await.unwrap();
+        let other_session = discord_auth.login("good-code").await.unwrap();
+
+        let token = csrf_token(&session);
+        assert!(verify_csrf_token(&session, &token));
+        assert!(!verify_csrf_token(&other_session, &token));
+        assert!(!verify_csrf_token(&session, ""));
+        assert!(!verify_csrf_token(&session, "not base64!"));
+    }
+
+    #[tokio::test]
+    async fn failed_membership_checks_are_not_cached() {
+        let mut discord_auth = discord_auth().await;
+        let session = discord_auth.login("good-code").await.unwrap();
+        let gallery_model = insert_gallery(discord_auth.db.as_ref(), 10, Some(20)).await;
+
+        // Nothing listens there, so the lookup fails
+        discord_auth.config.api_url = "http://127.0.0.1:1/api".to_owned();
+        assert!(!discord_auth.can_view(&session, &gallery_model).await);
+        assert!(discord_auth.membership_cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_membership_cache_drops_expired_checks_first() {
+        let expired = Instant::now() - MEMBERSHIP_CACHE_TTL * 2;
+        let mut cache = (0..MEMBERSHIP_CACHE_LIMIT)
+            .map(|session| ((session.to_string(), Uuid::nil()), (true, expired)))
+            .collect::<HashMap<(String, Uuid), (bool, Instant)>>();
+
+        remember_membership(&mut cache, ("new".to_owned(), Uuid::nil()), false);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn the_membership_cache_drops_the_oldest_check_when_full() {
+        let now = Instant::now();
+        let mut cache = (0..MEMBERSHIP_CACHE_LIMIT)
+            .map(|session| ((session.to_string(), Uuid::nil()), (true, now - StdDuration::from_secs(session as u64 % 60))))
+            .collect::<HashMap<(String, Uuid), (bool, Instant)>>();
+        cache.insert(("0".to_owned(), Uuid::nil()), (true, now - StdDuration::from_secs(120)));
+
+        remember_membership(&mut cache, ("new".to_owned(), Uuid::nil()), false);
+        assert_eq!(cache.len(), MEMBERSHIP_CACHE_LIMIT);
+        assert!(!cache.contains_key(&("0".to_owned(), Uuid::nil())));
+        assert_eq!(cache.get(&("new".to_owned(), Uuid::nil())).map(|(allowed, _)| *allowed), Some(false));
+    }
+}
@@ -1,77 +1,453 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use anyhow::Result;
-use sea_orm::{DatabaseConnection, EntityTrait, QueryFilter, ColumnTrait, ActiveValue, ActiveModelTrait, DbErr, InsertResult, TransactionTrait};
-use serenity::{async_trait, client::{EventHandler, Context}, model::{channel::{Message, Channel, Attachment, Embed, EmbedThumbnail, EmbedImage}, gateway::Ready, id::ChannelId, event::MessageUpdateEvent}};
-use tracing::{info, debug, warn, error, span, Level};
-use sql_entities::{gallery, gallery_post};
+use sea_orm::{ConnectionTrait, DatabaseConnection, DatabaseTransaction, EntityTrait, QueryFilter, QueryTrait, ColumnTrait, ActiveValue, ActiveModelTrait, IntoActiveModel, DbErr, TransactionTrait, prelude::Uuid, sea_query::{Expr, OnConflict}};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serenity::{async_trait, client::{EventHandler, Context, bridge::gateway::event::ShardStageUpdateEvent}, gateway::ConnectionStage, http::Http, model::{channel::{Message, Channel, Attachment, Embed, EmbedThumbnail, EmbedImage, ReactionType}, gateway::Ready, id::{ChannelId, GuildId, MessageId, UserId}, event::MessageUpdateEvent, permissions::Permissions, interactions::{Interaction, InteractionResponseType, application_command::{ApplicationCommand, ApplicationCommandInteraction, ApplicationCommandOptionType}}}};
+use tracing::{info, debug, warn, error, instrument, Span, field::display};
+use sql_entities::{artist_optout, gallery, gallery_post, sea_orm_active_enums::Visibility};
 
+use crate::config::{Features, GalleryDefaults};
+use crate::duplicates::DuplicateDetector;
+use crate::events::{EventNotifier, GalleryEvent};
+use crate::metrics::{GatewayStatus, Metrics};
+use crate::share::ShareSigner;
+use crate::shutdown::{Shutdown, TaskGuard};
+use crate::tags::{self, TagParser};
+
+/// Cloning is cheap, everything large is shared.
+#[derive(Clone)]
 pub struct Handler {
     pub base_url: String,
-    pub db_connection: Arc<DatabaseConnection>
+    pub command_prefix: String,
+    pub db_connection: Arc<DatabaseConnection>,
+    pub tag_parser: TagParser,
+    pub gallery_defaults: GalleryDefaults,
+    pub features: Features,
+    pub events: EventNotifier,
+    pub share_signer: Option<Arc<ShareSigner>>,
+    pub shutdown: Shutdown,
+    pub metrics: Arc<Metrics>,
+    pub gateway: Arc<GatewayStatus>,
+    /// Set when duplicate detection is enabled.
+    pub duplicates: Option<DuplicateDetector>,
+    pub repost_reaction: Option<ReactionType>
 }
 
 #[async_trait]
 impl EventHandler for Handler{
+    #[instrument(skip_all, fields(message_id = msg.id.0, channel_id = msg.channel_id.0))]
     async fn message(&self, ctx: Context, msg: Message) {
+        let _task = match self.begin_event() {
+            Some(task) => task,
+            None => return
+        };
+
         // Copy the channel_id for later usage, since msg is moved to the handler methods
         let channel_id = msg.channel_id;
         
-        if msg.content == "~ping" {
-            send_message(&ctx, &channel_id, "Pong!").await;
-        } else if msg.content == "~gallery" {
-            if let Err(why) = self.handle_gallery_command(&ctx, msg).await {
-                error!("Error executing gallery command: {:?}", why);
-                send_message(&ctx, &channel_id, "An error occured while running the command.").await;
+        match parse_command(&msg.content, &self.command_prefix) {
+            Some(command) => {
+                let _timer = self.metrics.time_event("command");
+                if let Err(why) = self.handle_command(&ctx, command, msg).await {
+                    error!("Error executing {:?} command: {:?}", command, why);
+                    self.metrics.count_error("command");
+                    send_message(&ctx, &channel_id, "An error occured while running the command.").await;
+                }
             }
-        } else {
-            if let Err(why) = self.handle_new_message(&ctx, msg).await {
-                error!("Error handling new message: {:?}", why);
+            None => {
+                let _timer = self.metrics.time_event("message");
+                if let Err(why) = self.handle_new_message(&ctx.http, msg, true).await {
+                    error!("Error handling new message: {:?}", why);
+                    self.metrics.count_error("message");
+                    self.record_ingest_error(channel_id, &why).await;
+                }
             }
         }
     }
 
+    #[instrument(skip_all, fields(message_id = event.id.0, channel_id = event.channel_id.0))]
     async fn message_update(&self, ctx: Context, event: MessageUpdateEvent) {
-        if let Err(why) = self.handle_message_update(&ctx, event).await {
+        let _task = match self.begin_event() {
+            Some(task) => task,
+            None => return
+        };
+
+        let _timer = self.metrics.time_event("message_update");
+        let channel_id = event.channel_id;
+        if let Err(why) = self.handle_message_update(&ctx.http, event).await {
             error!("Error handling message update: {:?}", why);
+            self.metrics.count_error("message_update");
+            self.record_ingest_error(channel_id, &why).await;
+        }
+    }
+
+    #[instrument(skip_all, fields(message_id = deleted_message_id.0, channel_id = channel_id.0))]
+    async fn message_delete(&self, _ctx: Context, channel_id: ChannelId, deleted_message_id: MessageId, _guild_id: Option<GuildId>) {
+        let _task = match self.begin_event() {
+            Some(task) => task,
+            None => return
+        };
+
+        let _timer = self.metrics.time_event("message_delete");
+        if let Err(why) = self.handle_message_delete(channel_id, vec![deleted_message_id]).await {
+            error!("Error handling message delete: {:?}", why);
+            self.metrics.count_error("message_delete");
+            self.record_ingest_error(channel_id, &why).await;
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    #[instrument(skip_all, fields(messages = deleted_message_ids.len(), channel_id = channel_id.0))]
+    async fn message_delete_bulk(&self, _ctx: Context, channel_id: ChannelId, deleted_message_ids: Vec<MessageId>, _guild_id: Option<GuildId>) {
+        let _task = match self.begin_event() {
+            Some(task) => task,
+            None => return
+        };
+
+        let _timer = self.metrics.time_event("message_delete_bulk");
+        if let Err(why) = self.handle_message_delete(channel_id, deleted_message_ids).await {
+            error!("Error handling bulk message delete: {:?}", why);
+            self.metrics.count_error("message_delete");
+            self.record_ingest_error(channel_id, &why).await;
+        }
+    }
+
+    #[instrument(skip_all, fields(shard_id = ctx.shard_id))]
+    async fn ready(&self, ctx: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
+        self.gateway.update(ctx.shard_id, ConnectionStage::Connected);
+
+        if let Err(why) = register_slash_commands(&ctx.http).await {
+            error!("Error registering slash commands: {:?}", why);
+        }
+
+        if let Err(why) = self.resume_backfills(&ctx.http).await {
+            error!("Error resuming backfills: {:?}", why);
+            self.metrics.count_error("backfill");
+        }
+    }
+
+    #[instrument(skip_all, fields(interaction_id = interaction.id().0))]
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let command = match interaction {
+            Interaction::ApplicationCommand(command) => command,
+            _ => return
+        };
+        let _task = match self.begin_event() {
+            Some(task) => task,
+            None => return
+        };
+
+        let _timer = self.metrics.time_event("command");
+        let reply = match self.handle_slash_command(&command).await {
+            Ok(reply) => reply,
+            Err(why) => {
+                error!("Error executing /{} command: {:?}", command.data.name, why);
+                self.metrics.count_error("command");
+                "An error occured while running the command."
+            }
+        };
+
+        // Only the member who ran the command sees the reply
+        let response = command.create_interaction_response(&ctx.http, |response| response
+            .kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data.content(reply).ephemeral(true))
+        ).await;
+        if let Err(why) = response {
+            error!("Error responding to /{} command: {:?}", command.data.name, why);
+        }
+    }
+
+    async fn shard_stage_update(&self, _ctx: Context, event: ShardStageUpdateEvent) {
+        debug!("Shard {} is now {:?}.", event.shard_id.0, event.new);
+        self.gateway.update(event.shard_id.0, event.new);
+    }
+}
+
+/// How long share links last when no duration is given, in hours.
+const DEFAULT_SHARE_HOURS: u32 = 24;
+/// The longest a share link can last, in hours.
+const MAX_SHARE_HOURS: u32 = 24 * 30;
+
+/// Chat commands understood by the bot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Ping,
+    CreateGallery,
+    OptOut,
+    OptIn,
+    SetVisibility(Visibility),
+    /// Limits a member-only gallery to members with a role, or lifts the limit.
+    RequireRole(Option<u64>),
+    Share(u32),
+    Download,
+}
+
+fn parse_command(content: &str, prefix: &str) -> Option<Command> {
+    let mut words = content.split_whitespace();
+    let command = words.next()?.strip_prefix(prefix)?;
+
+    match (Some(command), words.next(), words.next(), words.next()) {
+        (Some("ping"), None, None, None) => Some(Command::Ping),
+        (Some("gallery"), None, None, None) => Some(Command::CreateGallery),
+        (Some("gallery"), Some("optout"), None, None) => Some(Command::OptOut),
+        (Some("gallery"), Some("optin"), None, None) => Some(Command::OptIn),
+        (Some("gallery"), Some("visibility"), Some(visibility), None) => match visibility {
+            "public" => Some(Command::SetVisibility(Visibility::Public)),
+            "unlisted" => Some(Command::SetVisibility(Visibility::Unlisted)),
+            "members" => Some(Command::SetVisibility(Visibility::Members)),
+            "private" => Some(Command::SetVisibility(Visibility::Private)),
+            _ => None
+        },
+        (Some("gallery"), Some("role"), Some("none"), None) => Some(Command::RequireRole(None)),
+        (Some("gallery"), Some("role"), Some(role), None) => parse_role_mention(role).map(|role_id| Command::RequireRole(Some(role_id))),
+        (Some("gallery"), Some("share"), None, None) => Some(Command::Share(DEFAULT_SHARE_HOURS)),
+        (Some("gallery"), Some("share"), Some(hours), None) => hours.parse().ok().map(Command::Share),
+        (Some("gallery"), Some("download"), None, None) => Some(Command::Download),
+        _ => None
     }
 }
 
+/// Accepts a role mention (`<@&1234>`) or a bare role id.
+fn parse_role_mention(role: &str) -> Option<u64> {
+    role.strip_prefix("<@&")
+        .and_then(|role| role.strip_suffix('>'))
+        .unwrap_or(role)
+        .parse()
+        .ok()
+}
+
 impl Handler {
-    async fn handle_gallery_command(&self, ctx: &Context, msg: Message) -> Result<()> {
-        let span = span!(Level::TRACE, "create_gallery");
-        let _enter = span.enter();
+    #[instrument(skip(self, ctx, msg))]
+    async fn handle_command(&self, ctx: &Context, command: Command, msg: Message) -> Result<()> {
+        match command {
+            Command::Ping => {
+                send_message(ctx, &msg.channel_id, "Pong!").await;
+                Ok(())
+            }
+            Command::CreateGallery => self.handle_gallery_command(ctx, msg).await,
+            Command::OptOut => self.handle_optout_command(ctx, msg).await,
+            Command::OptIn => self.handle_optin_command(ctx, msg).await,
+            Command::SetVisibility(visibility) => self.handle_visibility_command(ctx, msg, visibility).await,
+            Command::RequireRole(role_id) => self.handle_role_command(ctx, msg, role_id).await,
+            Command::Share(hours) => self.handle_share_command(ctx, msg, hours).await,
+            Command::Download => self.handle_download_command(ctx, msg).await,
+        }
+    }
 
+    #[instrument(name = "create_gallery", skip_all)]
+    async fn handle_gallery_command(&self, ctx: &Context, msg: Message) -> Result<()> {
         debug!("Starting gallery creation.");
         
         // Check if the channel already exists
         if self.find_gallery_from_channel_id(msg.channel_id).await?.is_some() {
             warn!("Gallery for {} already exists.", msg.channel_id.0);
-            send_message(&ctx, &msg.channel_id, "A gallery for this channel already exists.").await;
+            send_message(ctx, &msg.channel_id, "A gallery for this channel already exists.").await;
             return Ok(())
         }
 
-        let new_gallery = self.create_gallery(msg.channel(&ctx.http).await?).await?;
+        let new_gallery = self.create_gallery(msg.channel(&ctx.http).await?, msg.guild_id).await?;
         info!("Successfully created a new gallery: {}.", new_gallery.pk);
 
-        send_message(&ctx, &&msg.channel_id, format!("New gallery created at {}/gallery/{}", &self.base_url, &new_gallery.pk)).await;
+        send_message(ctx, &msg.channel_id, format!("New gallery created at {}/gallery/{}", &self.base_url, &new_gallery.pk)).await;
+
+        // Going through a channel's history can take a while, so it doesn't hold up the gateway's other events
+        let handler = self.clone();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            match handler.backfill_gallery(&ctx.http, &new_gallery).await {
+                Ok(summary) if summary.ingested_messages > 0 => {
+                    send_message(&ctx, &msg.channel_id, format!("Added art from {} earlier messages.", summary.ingested_messages)).await;
+                },
+                Ok(_) => {},
+                Err(why) => {
+                    error!("Error backfilling new gallery {}: {:?}", new_gallery.pk, why);
+                    handler.metrics.count_error("backfill");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn handle_optout_command(&self, ctx: &Context, msg: Message) -> Result<()> {
+        let reply = self.opt_out(msg.guild_id, msg.author.id).await?;
+        send_message(ctx, &msg.channel_id, reply).await;
+        Ok(())
+    }
+
+    async fn handle_optin_command(&self, ctx: &Context, msg: Message) -> Result<()> {
+        let reply = self.opt_in(msg.guild_id, msg.author.id).await?;
+        send_message(ctx, &msg.channel_id, reply).await;
+        Ok(())
+    }
+
+    /// Runs the slash commands, `/gallery optout` and `/gallery optin`, returning the reply.
+    async fn handle_slash_command(&self, command: &ApplicationCommandInteraction) -> Result<&'static str> {
+        let subcommand = command.data.options.first().map(|option| option.name.as_str());
+
+        match (command.data.name.as_str(), subcommand) {
+            ("gallery", Some("optout")) => self.opt_out(command.guild_id, command.user.id).await,
+            ("gallery", Some("optin")) => self.opt_in(command.guild_id, command.user.id).await,
+            _ => {
+                warn!("Unknown slash command /{} {:?}", command.data.name, subcommand);
+                Ok("Unknown command.")
+            }
+        }
+    }
+
+    /// Hides a member's posts in every gallery of the guild, now and in the future.
+    async fn opt_out(&self, guild_id: Option<GuildId>, user_id: UserId) -> Result<&'static str> {
+        let guild_id = match guild_id {
+            Some(guild_id) => guild_id,
+            None => return Ok("Opting out can only be done from inside a server.")
+        };
+
+        // Running the command twice at once inserts nothing the second time, rather than failing
+        let mut insert = artist_optout::Entity::insert(artist_optout::ActiveModel {
+            discord_guild_id: ActiveValue::Set(guild_id.0 as i64),
+            discord_user_id: ActiveValue::Set(user_id.0 as i64),
+            ..Default::default()
+        });
+        insert.query().on_conflict(
+            OnConflict::columns([artist_optout::Column::DiscordGuildId, artist_optout::Column::DiscordUserId])
+                .do_nothing()
+                .to_owned()
+        );
+        let db = self.db_connection.as_ref();
+        let result = db.execute(db.get_database_backend().build(insert.query())).await?;
+
+        if result.rows_affected() == 0 {
+            return Ok("You have already opted out of galleries in this server.");
+        }
+        info!("User {} opted out of galleries in guild {}.", user_id.0, guild_id.0);
+        Ok("You have opted out. Your posts in this server will no longer appear in any gallery.")
+    }
+
+    async fn opt_in(&self, guild_id: Option<GuildId>, user_id: UserId) -> Result<&'static str> {
+        let guild_id = match guild_id {
+            Some(guild_id) => guild_id,
+            None => return Ok("Opting in can only be done from inside a server.")
+        };
+
+        let del_result = artist_optout::Entity::delete_many()
+            .filter(artist_optout::Column::DiscordGuildId.eq(guild_id.0 as i64))
+            .filter(artist_optout::Column::DiscordUserId.eq(user_id.0 as i64))
+            .exec(self.db_connection.as_ref())
+            .await?;
+
+        if del_result.rows_affected == 0 {
+            return Ok("You are not opted out of galleries in this server.");
+        }
+        info!("User {} opted back into galleries in guild {}.", user_id.0, guild_id.0);
+        Ok("You have opted back in. Your posts in this server will appear in galleries again.")
+    }
+
+    async fn handle_visibility_command(&self, ctx: &Context, msg: Message, visibility: Visibility) -> Result<()> {
+        let gallery_model = match self.find_gallery_from_channel_id(msg.channel_id).await? {
+            Some(gallery_model) => gallery_model,
+            None => {
+                send_message(ctx, &msg.channel_id, "There is no gallery for this channel.").await;
+                return Ok(())
+            }
+        };
+
+        if !author_can_manage_channel(ctx, &msg).await? {
+            send_message(ctx, &msg.channel_id, "Only members who can manage this channel can change the gallery's visibility.").await;
+            return Ok(())
+        }
+
+        let mut gallery_active_model: gallery::ActiveModel = gallery_model.into();
+        gallery_active_model.visibility = ActiveValue::Set(visibility);
+        let gallery_model = gallery_active_model.update(self.db_connection.as_ref()).await?;
+        info!("Gallery {} is now {:?}.", gallery_model.pk, visibility);
+
+        let reply = match visibility {
+            Visibility::Public => "The gallery is now public.".to_owned(),
+            Visibility::Unlisted => "The gallery is now unlisted. Anyone with the link can view it, but search engines are asked not to index it.".to_owned(),
+            Visibility::Members => format!("The gallery is now limited to members of this server who can read this channel. They sign in with Discord to view it. Use `{}gallery role` to also require a role.", self.command_prefix),
+            Visibility::Private => format!("The gallery is now private. Use `{}gallery share` to create links that can view it.", self.command_prefix)
+        };
+        send_message(ctx, &msg.channel_id, reply).await;
+
+        Ok(())
+    }
+
+    async fn handle_role_command(&self, ctx: &Context, msg: Message, role_id: Option<u64>) -> Result<()> {
+        let gallery_model = match self.find_gallery_from_channel_id(msg.channel_id).await? {
+            Some(gallery_model) => gallery_model,
+            None => {
+                send_message(ctx, &msg.channel_id, "There is no gallery for this channel.").await;
+                return Ok(())
+            }
+        };
+
+        if !author_can_manage_channel(ctx, &msg).await? {
+            send_message(ctx, &msg.channel_id, "Only members who can manage this channel can change who can view the gallery.").await;
+            return Ok(())
+        }
+
+        let mut gallery_active_model: gallery::ActiveModel = gallery_model.into();
+        gallery_active_model.required_role_id = ActiveValue::Set(role_id.map(|role_id| role_id as i64));
+        let gallery_model = gallery_active_model.update(self.db_connection.as_ref()).await?;
+        info!("Gallery {} now requires role {:?}.", gallery_model.pk, role_id);
+
+        // The role isn't mentioned in the reply, that would ping everyone who has it
+        let reply = match (role_id, gallery_model.visibility) {
+            (None, _) => "Viewing the gallery no longer requires a role.".to_owned(),
+            (Some(_), Visibility::Members) => "Only members with that role can view the gallery now.".to_owned(),
+            (Some(_), _) => format!("Once the gallery is limited to members with `{}gallery visibility members`, only members with that role can view it.", self.command_prefix)
+        };
+        send_message(ctx, &msg.channel_id, reply).await;
 
-        // TODO: Grab all previous messages 
-        
         Ok(())
     }
 
-    async fn handle_new_message(&self, _ctx: &Context, msg: Message) -> Result<()> {
-        let span = span!(Level::TRACE, "handle_new_message");
-        let _enter = span.enter();
+    async fn handle_share_command(&self, ctx: &Context, msg: Message, hours: u32) -> Result<()> {
+        let share_signer = match &self.share_signer {
+            Some(share_signer) => share_signer,
+            None => {
+                send_message(ctx, &msg.channel_id, "Share links are not enabled on this instance.").await;
+                return Ok(())
+            }
+        };
+
+        let gallery_model = match self.find_gallery_from_channel_id(msg.channel_id).await? {
+            Some(gallery_model) => gallery_model,
+            None => {
+                send_message(ctx, &msg.channel_id, "There is no gallery for this channel.").await;
+                return Ok(())
+            }
+        };
 
+        if !author_can_manage_channel(ctx, &msg).await? {
+            send_message(ctx, &msg.channel_id, "Only members who can manage this channel can create share links.").await;
+            return Ok(())
+        }
+
+        if hours == 0 || hours > MAX_SHARE_HOURS {
+            send_message(ctx, &msg.channel_id, format!("Share links can last between 1 and {} hours.", MAX_SHARE_HOURS)).await;
+            return Ok(())
+        }
+
+        let expires = Utc::now() + Duration::hours(hours as i64);
+        let token = share_signer.sign(gallery_model.pk, expires);
+        info!("Created a share link for gallery {} expiring at {}.", gallery_model.pk, expires);
+
+        send_message(ctx, &msg.channel_id, format!(
+            "Share link, valid until <t:{}:f>: {}/gallery/{}?token={}",
+            expires.timestamp(), &self.base_url, &gallery_model.pk, token
+        )).await;
+
+        Ok(())
+    }
+
+    /// Reposts get the repost reaction when `react_to_reposts` is set.
+    #[instrument(skip_all, fields(gallery_id))]
+    async fn handle_new_message(&self, http: &Http, msg: Message, react_to_reposts: bool) -> Result<()> {
         // Optimization: Return if no attachements or embeds before querying the database
-        if msg.attachments.len() == 0 && msg.embeds.len() == 0 {
+        if msg.attachments.is_empty() && msg.embeds.is_empty() {
             debug!("Message {} has no embeds or attachments.", msg.id.0);
             return Ok(())
         }
@@ -83,20 +459,54 @@ impl Handler {
                 return Ok(())
             }
         };
+        Span::current().record("gallery_id", &display(gallery_model.pk));
+
+        if let Some(guild_id) = msg.guild_id {
+            if self.is_opted_out(guild_id, msg.author.id).await? {
+                debug!("Author {} of message {} has opted out.", msg.author.id.0, msg.id.0);
+                self.fill_missing_author_id(&gallery_model, msg.id, msg.author.id).await?;
+                return Ok(())
+            }
+
+            // Galleries created before guild ids were recorded get theirs filled in here
+            if gallery_model.discord_guild_id.is_none() {
+                self.set_gallery_guild_id(&gallery_model, guild_id).await?;
+            }
+        }
 
         // Grab all attachments and embeds into posts
-        let new_posts = attachments_to_db(msg.attachments.into_iter(), &gallery_model, msg.id.0)
-            .chain(embeds_to_db(msg.embeds.into_iter(), &gallery_model, msg.id.0))
+        let message_tags = self.tag_parser.parse(&msg.content);
+        let message_id = msg.id.0;
+        let channel_id = msg.channel_id;
+        let source = PostSource {
+            discord_message_id: msg.id.0,
+            discord_author_id: Some(msg.author.id.0),
+            author_name: Some(msg.author.name),
+            content: Some(msg.content),
+            date_created: Utc.timestamp_opt(msg.timestamp.unix_timestamp(), 0).single()
+        };
+        let new_posts = attachments_to_db(msg.attachments.into_iter(), &gallery_model, &source)
+            .chain(embeds_to_db(msg.embeds.into_iter(), &gallery_model, &source))
             .collect::<Vec<gallery_post::ActiveModel>>();
 
-        gallery_post::Entity::insert_many(new_posts).exec(self.db_connection.as_ref()).await?;
+        let gallery_id = gallery_model.pk;
+        let upserted = self.db_connection.transaction::<_, UpsertedPosts, DbErr>(|txn| {
+            Box::pin(async move {
+                upsert_message_posts(txn, gallery_id, message_id, new_posts, &message_tags).await
+            })
+        }).await?;
+
+        self.mark_ingested(&gallery_model).await?;
+        self.check_duplicates(http, channel_id, MessageId(message_id), &upserted.created, react_to_reposts).await;
+        self.metrics.count_ingested(gallery_model.pk, upserted.created.len());
+        self.publish(GalleryEvent::PostsCreated { gallery: gallery_model.pk, posts: upserted.created }).await;
+        self.publish(GalleryEvent::PostsUpdated { gallery: gallery_model.pk, posts: upserted.updated }).await;
 
         Ok(())
     }
 
-    async fn handle_message_update(&self, _ctx: &Context, event: MessageUpdateEvent) -> Result<()> {
-        let span = span!(Level::TRACE, "handle_message_update");
-        let _enter = span.enter();
+    #[instrument(skip_all, fields(gallery_id))]
+    async fn handle_message_update(&self, http: &Http, event: MessageUpdateEvent) -> Result<()> {
         debug!("handle_message_update() - MessageUpdateEvent: {:?}", event);
 
         let gallery_model = match self.find_gallery_from_channel_id(event.channel_id).await? {
@@ -106,37 +516,434 @@ impl Handler {
                 return Ok(());
             }
         };
+        Span::current().record("gallery_id", &display(gallery_model.pk));
+
+        // Partial updates don't always carry the author or content, so fall back to what was stored on ingestion
+        let old_posts = self.find_message_posts(event.id).await?;
+
+        let has_media = event.attachments.as_ref().is_some_and(|attachments| !attachments.is_empty())
+            || event.embeds.as_ref().is_some_and(|embeds| !embeds.is_empty());
+        if old_posts.is_empty() && !has_media {
+            debug!("Message {} has no posts and its update adds no embeds or attachments.", event.id.0);
+            return Ok(());
+        }
 
-        // Handle the update by removing all rows associated with the message and re-adding them.
-        // Probably not very efficient, but I don't expect more than a few embeds per message.
-        let attachments = event.attachments.unwrap_or_default();
-        let embeds = event.embeds.unwrap_or_default();
-        let new_posts = attachments_to_db(attachments.into_iter(), &gallery_model, event.id.0)
-            .chain(embeds_to_db(embeds.into_iter(), &gallery_model, event.id.0))
+        // Embeds for links arrive in an update carrying only the embeds. When the message had no media before
+        // there's nothing stored to fall back on, so fetch it. Without its author the opt-out can't be checked.
+        let fetched = if old_posts.is_empty() && (event.author.is_none() || event.content.is_none()) {
+            debug!("Fetching message {} to fill in its partial update.", event.id.0);
+            Some(event.channel_id.message(http, event.id).await?)
+        } else {
+            None
+        };
+
+        let source = PostSource {
+            discord_message_id: event.id.0,
+            discord_author_id: event.author.as_ref()
+                .or_else(|| fetched.as_ref().map(|msg| &msg.author))
+                .map(|author| author.id.0)
+                .or_else(|| old_posts.iter().find_map(|p| p.discord_author_id).map(|id| id as u64)),
+            author_name: event.author.as_ref()
+                .or_else(|| fetched.as_ref().map(|msg| &msg.author))
+                .map(|author| author.name.clone())
+                .or_else(|| old_posts.iter().find_map(|p| p.author_name.clone())),
+            content: event.content.clone()
+                .or_else(|| fetched.as_ref().map(|msg| msg.content.clone()))
+                .or_else(|| old_posts.iter().find_map(|p| p.content.clone())),
+            // Attachments and embeds added by the edit go next to the message's other posts
+            date_created: old_posts.iter().map(|p| p.date_created).min()
+                .or_else(|| event.timestamp.or_else(|| fetched.as_ref().map(|msg| msg.timestamp))
+                    .and_then(|timestamp| Utc.timestamp_opt(timestamp.unix_timestamp(), 0).single()))
+        };
+
+        if let (Some(guild_id), Some(author_id)) = (event.guild_id, source.discord_author_id) {
+            if self.is_opted_out(guild_id, UserId(author_id)).await? {
+                debug!("Author {} of message {} has opted out.", author_id, event.id.0);
+                return Ok(());
+            }
+        }
+
+        // Fields the event leaves out haven't changed. Their posts are kept, only picking up changes to the message itself.
+        let mut new_posts = Vec::new();
+        let mut kept_prefixes = Vec::new();
+        match event.attachments {
+            Some(attachments) => new_posts.extend(attachments_to_db(attachments.into_iter(), &gallery_model, &source)),
+            None => kept_prefixes.push(ATTACHMENT_KEY_PREFIX)
+        }
+        match event.embeds {
+            Some(embeds) => new_posts.extend(embeds_to_db(embeds.into_iter(), &gallery_model, &source)),
+            None => kept_prefixes.push(EMBED_KEY_PREFIX)
+        }
+
+        // Diff against the stored posts by source key, so the ones that stay keep their pk and place in the gallery
+        let mut removed_posts = Vec::new();
+        let mut changed_posts = Vec::new();
+        for old_post in &old_posts {
+            let new_post = if kept_prefixes.iter().any(|prefix| old_post.source_key.starts_with(prefix)) {
+                Some(source.to_message_update())
+            } else {
+                new_posts.iter().find(|p| p.source_key.as_ref() == &old_post.source_key).cloned()
+            };
+
+            match new_post {
+                Some(new_post) => changed_posts.extend(changed_post(old_post, &new_post)),
+                None => removed_posts.push(old_post.pk)
+            }
+        }
+        let created_posts = new_posts.into_iter()
+            .filter(|p| !old_posts.iter().any(|old_post| &old_post.source_key == p.source_key.as_ref()))
             .collect::<Vec<gallery_post::ActiveModel>>();
 
-        self.db_connection.transaction::<_, (), DbErr>(|txn| {
+        // Tags only change when the content does. Otherwise keep the ones the old rows were linked to.
+        let old_content = old_posts.iter().find_map(|p| p.content.clone());
+        let content_changed = event.content.is_some() && event.content != old_content;
+        let kept_posts = old_posts.iter()
+            .map(|p| p.pk)
+            .filter(|pk| !removed_posts.contains(pk))
+            .collect::<Vec<Uuid>>();
+        if created_posts.is_empty() && changed_posts.is_empty() && removed_posts.is_empty() && !content_changed {
+            debug!("Message {} was updated, but none of its posts changed.", event.id.0);
+            return Ok(());
+        }
+
+        // Without posts to take the tags from, they come from the content, wherever it was found
+        let message_tags = if event.content.is_some() || kept_posts.is_empty() {
+            self.tag_parser.parse(source.content.as_deref().unwrap_or_default())
+        } else {
+            tags::find_post_tags(self.db_connection.as_ref(), &kept_posts).await?
+        };
+
+        debug!(
+            "Message {}: {} posts created, {} changed, {} removed.",
+            event.id.0, created_posts.len(), changed_posts.len(), removed_posts.len()
+        );
+
+        let gallery_id = gallery_model.pk;
+        let message_id = event.id.0;
+        let deleted = removed_posts.clone();
+        let (upserted, updated_posts) = self.db_connection.transaction::<_, (UpsertedPosts, Vec<Uuid>), DbErr>(|txn| {
             Box::pin(async move {
-                let del_result = gallery_post::Entity::delete_many()
-                    .filter(gallery_post::Column::DiscordMessageId.eq(event.id.0 as i64))
-                    .exec(txn)
-                    .await?;
-                
-                debug!("Removed {} rows.", del_result.rows_affected);
-                
-                if new_posts.len() > 0 {
-                    gallery_post::Entity::insert_many(new_posts).exec(txn).await?;
-                } else {
-                    debug!("No new posts to insert.");
+                if !deleted.is_empty() {
+                    gallery_post::Entity::delete_many()
+                        .filter(gallery_post::Column::Pk.is_in(deleted))
+                        .exec(txn)
+                        .await?;
                 }
-                
-                Ok(())
+
+                let mut updated_posts = Vec::with_capacity(changed_posts.len());
+                for changed_post in changed_posts {
+                    updated_posts.push(changed_post.update(txn).await?.pk);
+                }
+
+                // The tags of every kept post follow the new content, even if nothing else about them changed
+                if content_changed {
+                    tags::replace_tags(txn, &kept_posts, &message_tags).await?;
+                    for pk in kept_posts {
+                        if !updated_posts.contains(&pk) {
+                            updated_posts.push(pk);
+                        }
+                    }
+                }
+
+                let upserted = upsert_message_posts(txn, gallery_id, message_id, created_posts, &message_tags).await?;
+                Ok((upserted, updated_posts))
             })
         }).await?;
 
+        self.mark_ingested(&gallery_model).await?;
+        self.check_duplicates(http, event.channel_id, event.id, &upserted.created, true).await;
+        self.publish(GalleryEvent::PostsDeleted { gallery: gallery_model.pk, posts: removed_posts }).await;
+        self.publish(GalleryEvent::PostsUpdated { gallery: gallery_model.pk, posts: updated_posts.into_iter().chain(upserted.updated).collect() }).await;
+        self.metrics.count_ingested(gallery_model.pk, upserted.created.len());
+        self.publish(GalleryEvent::PostsCreated { gallery: gallery_model.pk, posts: upserted.created }).await;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(gallery_id))]
+    async fn handle_message_delete(&self, channel_id: ChannelId, message_ids: Vec<MessageId>) -> Result<()> {
+        let gallery_model = match self.find_gallery_from_channel_id(channel_id).await? {
+            Some(gallery_model) => gallery_model,
+            None => {
+                debug!("No gallery found with associated channel_id {}", channel_id.0);
+                return Ok(());
+            }
+        };
+        Span::current().record("gallery_id", &display(gallery_model.pk));
+
+        let message_ids = message_ids.into_iter().map(|id| id.0 as i64).collect::<Vec<i64>>();
+        let deleted_posts = gallery_post::Entity::find()
+            .filter(gallery_post::Column::Gallery.eq(gallery_model.pk))
+            .filter(gallery_post::Column::DiscordMessageId.is_in(message_ids.clone()))
+            .all(self.db_connection.as_ref())
+            .await?
+            .into_iter()
+            .map(|p| p.pk)
+            .collect::<Vec<Uuid>>();
+
+        if deleted_posts.is_empty() {
+            return Ok(());
+        }
+
+        let del_result = gallery_post::Entity::delete_many()
+            .filter(gallery_post::Column::Pk.is_in(deleted_posts.clone()))
+            .exec(self.db_connection.as_ref())
+            .await?;
+        debug!("Removed {} rows for deleted messages {:?}.", del_result.rows_affected, message_ids);
+
+        self.publish(GalleryEvent::PostsDeleted { gallery: gallery_model.pk, posts: deleted_posts }).await;
+
         Ok(())
     }
 
+    async fn handle_download_command(&self, ctx: &Context, msg: Message) -> Result<()> {
+        if !self.features.downloads {
+            send_message(ctx, &msg.channel_id, "Downloading galleries is turned off.").await;
+            return Ok(())
+        }
+
+        let gallery_model = match self.find_gallery_from_channel_id(msg.channel_id).await? {
+            Some(gallery_model) => gallery_model,
+            None => {
+                send_message(ctx, &msg.channel_id, "There is no gallery for this channel.").await;
+                return Ok(())
+            }
+        };
+
+        let download_url = format!("{}/gallery/{}/download.zip", &self.base_url, &gallery_model.pk);
+        let reply = match gallery_model.visibility {
+            Visibility::Public | Visibility::Unlisted => format!("Download all the art in this gallery: {}", download_url),
+            Visibility::Members => format!("Download all the art in this gallery, after signing in with Discord: {}", download_url),
+            Visibility::Private => format!(
+                "This gallery is private. Add the `?token=` of a share link from `{}gallery share` to download it: {}",
+                self.command_prefix, download_url
+            )
+        };
+        send_message(ctx, &msg.channel_id, reply).await;
+
+        Ok(())
+    }
+
+    /// Work on events is waited for when shutting down. Events that arrive after that are left to the next backfill.
+    fn begin_event(&self) -> Option<TaskGuard> {
+        let task = self.shutdown.begin_task();
+        if task.is_none() {
+            debug!("Shutting down, ignoring the event.");
+        }
+        task
+    }
+
+    /// Continues backfills that were interrupted by a shutdown.
+    #[instrument(skip_all)]
+    async fn resume_backfills(&self, http: &Http) -> Result<()> {
+        let unfinished = gallery::Entity::find()
+            .filter(gallery::Column::BackfillBefore.is_not_null())
+            .all(self.db_connection.as_ref())
+            .await?;
+
+        for gallery_model in unfinished {
+            if let Err(why) = self.backfill_gallery(http, &gallery_model).await {
+                error!("Error resuming the backfill of gallery {}: {:?}", gallery_model.pk, why);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ingests the messages in a gallery's channel that were never ingested, without removing anything.
+    /// Continues where an interrupted backfill left off.
+    #[instrument(skip_all, fields(gallery_id = %gallery_model.pk))]
+    pub async fn backfill_gallery(&self, http: &Http, gallery_model: &gallery::Model) -> Result<ResyncSummary> {
+        let before = gallery_model.backfill_before.map(|message_id| MessageId(message_id as u64));
+        match before {
+            Some(before) => info!("Continuing the backfill of gallery {} before message {}.", gallery_model.pk, before.0),
+            None => info!("Backfilling gallery {} from channel {}.", gallery_model.pk, gallery_model.discord_channel_id)
+        }
+
+        let mut summary = ResyncSummary::default();
+        self.ingest_history(http, gallery_model, before, true, &mut summary).await?;
+
+        info!("Backfilled gallery {}: {:?}", gallery_model.pk, summary);
+        Ok(summary)
+    }
+
+    /// Brings a gallery up to date with its channel's history: messages that were never ingested are,
+    /// and posts whose messages are gone are removed. Messages that were already ingested are left as they are.
+    #[instrument(skip_all, fields(gallery_id = %gallery_model.pk))]
+    pub async fn resync_gallery(&self, http: &Http, gallery_model: &gallery::Model) -> Result<ResyncSummary> {
+        info!("Resyncing gallery {} from channel {}.", gallery_model.pk, gallery_model.discord_channel_id);
+
+        // Pruning needs to see every message, so resyncs start from the newest one and aren't resumed
+        let mut summary = ResyncSummary::default();
+        let seen_messages = self.ingest_history(http, gallery_model, None, false, &mut summary).await?;
+        if summary.interrupted {
+            info!("Resync of gallery {} was interrupted, no posts were removed: {:?}", gallery_model.pk, summary);
+            return Ok(summary);
+        }
+
+        let stale_posts = gallery_post::Entity::find()
+            .filter(gallery_post::Column::Gallery.eq(gallery_model.pk))
+            .all(self.db_connection.as_ref())
+            .await?
+            .into_iter()
+            .filter(|p| !seen_messages.contains(&p.discord_message_id))
+            .map(|p| p.pk)
+            .collect::<Vec<Uuid>>();
+
+        if !stale_posts.is_empty() {
+            gallery_post::Entity::delete_many()
+                .filter(gallery_post::Column::Pk.is_in(stale_posts.clone()))
+                .exec(self.db_connection.as_ref())
+                .await?;
+        }
+        summary.removed_posts = stale_posts.len();
+        self.publish(GalleryEvent::PostsDeleted { gallery: gallery_model.pk, posts: stale_posts }).await;
+
+        info!("Resynced gallery {}: {:?}", gallery_model.pk, summary);
+        Ok(summary)
+    }
+
+    /// Pages through a channel's history before a message, or all of it, ingesting messages that have no posts yet.
+    /// Returns the ids of every message that was seen.
+    ///
+    /// A shutdown stops between pages. With `save_progress`, the page being worked on is saved as the gallery's
+    /// backfill cursor, so the backfill continues from there once the bot is back.
+    #[instrument(skip_all, fields(before = ?before))]
+    async fn ingest_history(&self, http: &Http, gallery_model: &gallery::Model, mut before: Option<MessageId>, save_progress: bool, summary: &mut ResyncSummary) -> Result<HashSet<i64>> {
+        let channel_id = ChannelId(gallery_model.discord_channel_id as u64);
+        let guild_id = gallery_model.discord_guild_id.map(|guild_id| GuildId(guild_id as u64));
+
+        let mut seen_messages = HashSet::new();
+        let _task = match self.shutdown.begin_task() {
+            Some(task) => task,
+            None => {
+                summary.interrupted = true;
+                return Ok(seen_messages);
+            }
+        };
+
+        loop {
+            if self.shutdown.is_shutting_down() {
+                if save_progress && before.is_some() {
+                    info!("Shutting down, the backfill of gallery {} will continue before message {:?}.", gallery_model.pk, before.map(|before| before.0));
+                    self.set_backfill_cursor(gallery_model, before).await?;
+                }
+                summary.interrupted = true;
+                return Ok(seen_messages);
+            }
+
+            let messages = channel_id.messages(http, |retriever| {
+                if let Some(before) = before {
+                    retriever.before(before);
+                }
+                retriever.limit(100)
+            }).await?;
+
+            // Messages come newest first, so the last one is where the next page starts
+            let page_start = before;
+            before = match messages.last() {
+                Some(message) => Some(message.id),
+                None => break
+            };
+            if let Some(page_start) = page_start.filter(|_| save_progress) {
+                self.set_backfill_cursor(gallery_model, Some(page_start)).await?;
+            }
+
+            for mut msg in messages {
+                seen_messages.insert(msg.id.0 as i64);
+                if parse_command(&msg.content, &self.command_prefix).is_some() || !self.find_message_posts(msg.id).await?.is_empty() {
+                    continue;
+                }
+
+                // Messages fetched over HTTP don't carry their guild, which the opt-out check needs
+                msg.guild_id = msg.guild_id.or(guild_id);
+                let has_media = !msg.attachments.is_empty() || !msg.embeds.is_empty();
+                // Old reposts aren't worth a reaction, but they're still grouped
+                self.handle_new_message(http, msg, false).await?;
+                if has_media {
+                    summary.ingested_messages += 1;
+                }
+            }
+        }
+
+        if save_progress {
+            self.set_backfill_cursor(gallery_model, None).await?;
+        }
+        Ok(seen_messages)
+    }
+
+    /// Groups new posts with earlier posts of the same images. Runs before the posts are published,
+    /// so they show up collapsed. Failing only loses the grouping, so errors are logged and not returned.
+    async fn check_duplicates(&self, http: &Http, channel_id: ChannelId, message_id: MessageId, posts: &[Uuid], react_to_reposts: bool) {
+        let detector = match &self.duplicates {
+            Some(detector) => detector,
+            None => return
+        };
+
+        let reposts = match detector.check_posts(posts).await {
+            Ok(reposts) => reposts,
+            Err(why) => {
+                error!("Error checking message {} for reposts: {:?}", message_id.0, why);
+                self.metrics.count_error("duplicates");
+                return;
+            }
+        };
+
+        if let (Some(repost), Some(reaction), true) = (reposts.first(), &self.repost_reaction, react_to_reposts) {
+            debug!("Post {} of message {} reposts post {}.", repost.post.pk, message_id.0, repost.original.pk);
+            if let Err(why) = channel_id.create_reaction(http, message_id, reaction.clone()).await {
+                warn!("Could not react to repost {}: {:?}", message_id.0, why);
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip_all, err)]
+    async fn set_backfill_cursor(&self, gallery_model: &gallery::Model, before: Option<MessageId>) -> Result<(), DbErr> {
+        gallery::Entity::update_many()
+            .col_expr(gallery::Column::BackfillBefore, Expr::value(before.map(|before| before.0 as i64)))
+            .filter(gallery::Column::Pk.eq(gallery_model.pk))
+            .exec(self.db_connection.as_ref())
+            .await
+            .map(|_| ())
+    }
+
+    #[instrument(level = "debug", skip_all, err)]
+    async fn mark_ingested(&self, gallery_model: &gallery::Model) -> Result<(), DbErr> {
+        gallery::Entity::update_many()
+            .col_expr(gallery::Column::DateLastIngested, Expr::value(Utc::now()))
+            .filter(gallery::Column::Pk.eq(gallery_model.pk))
+            .exec(self.db_connection.as_ref())
+            .await
+            .map(|_| ())
+    }
+
+    /// Counts a failure to ingest a message against the channel's gallery, for the admin dashboard.
+    async fn record_ingest_error(&self, channel_id: ChannelId, why: &anyhow::Error) {
+        let result = gallery::Entity::update_many()
+            .col_expr(gallery::Column::IngestErrorCount, Expr::col(gallery::Column::IngestErrorCount).add(1))
+            .col_expr(gallery::Column::LastIngestError, Expr::value(Some(why.to_string())))
+            .filter(gallery::Column::DiscordChannelId.eq(channel_id.0 as i64))
+            .exec(self.db_connection.as_ref())
+            .await;
+
+        if let Err(why) = result {
+            error!("Error recording ingestion error for channel {}: {:?}", channel_id.0, why);
+        }
+    }
+
+    /// Tells live gallery pages about a change. Events without posts are dropped.
+    /// Posts are already saved by now, so failing to publish only leaves live pages behind.
+    #[instrument(level = "debug", skip_all)]
+    async fn publish(&self, event: GalleryEvent) {
+        if event.posts().is_empty() {
+            return;
+        }
+        if let Err(why) = self.events.notify(&event).await {
+            error!("Error publishing {:?}: {:?}", event, why);
+        }
+    }
+
+    #[instrument(level = "debug", skip(self), fields(channel_id = channel_id.0), err)]
     async fn find_gallery_from_channel_id(&self, channel_id: ChannelId) -> Result<Option<gallery::Model>, DbErr> {
         gallery::Entity::find()
             .filter(gallery::Column::DiscordChannelId.eq(channel_id.0 as i64))
@@ -144,15 +951,197 @@ impl Handler {
             .await
     }
 
-    async fn create_gallery(&self, channel: Channel) -> Result<gallery::Model, DbErr> {
+    #[instrument(level = "debug", skip_all, err)]
+    async fn create_gallery(&self, channel: Channel, guild_id: Option<GuildId>) -> Result<gallery::Model, DbErr> {
+        let defaults = self.gallery_defaults.for_guild(guild_id.map(|g| g.0));
         let gallery_active_model = gallery::ActiveModel {
             name: ActiveValue::Set(channel.to_string()),
             discord_channel_id: ActiveValue::Set(channel.id().0 as i64),
+            discord_guild_id: ActiveValue::Set(guild_id.map(|g| g.0 as i64)),
+            visibility: ActiveValue::Set(defaults.visibility),
+            required_role_id: ActiveValue::Set(defaults.required_role_id.map(|role_id| role_id as i64)),
             ..Default::default()
         };
 
         gallery_active_model.insert(self.db_connection.as_ref()).await
     }
+
+    #[instrument(level = "debug", skip_all, err)]
+    async fn set_gallery_guild_id(&self, gallery: &gallery::Model, guild_id: GuildId) -> Result<gallery::Model, DbErr> {
+        let mut gallery_active_model: gallery::ActiveModel = gallery.clone().into();
+        gallery_active_model.discord_guild_id = ActiveValue::Set(Some(guild_id.0 as i64));
+
+        gallery_active_model.update(self.db_connection.as_ref()).await
+    }
+
+    /// Posts ingested before authors were recorded have none, so opting out doesn't hide them.
+    /// Seeing their messages again fills the author in here.
+    async fn fill_missing_author_id(&self, gallery_model: &gallery::Model, message_id: MessageId, author_id: UserId) -> Result<(), DbErr> {
+        let result = gallery_post::Entity::update_many()
+            .col_expr(gallery_post::Column::DiscordAuthorId, Expr::value(author_id.0 as i64))
+            .filter(gallery_post::Column::Gallery.eq(gallery_model.pk))
+            .filter(gallery_post::Column::DiscordMessageId.eq(message_id.0 as i64))
+            .filter(gallery_post::Column::DiscordAuthorId.is_null())
+            .exec(self.db_connection.as_ref())
+            .await?;
+
+        if result.rows_affected > 0 {
+            info!("Hid {} posts of message {} by opted out author {}.", result.rows_affected, message_id.0, author_id.0);
+        }
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self), fields(guild_id = guild_id.0, user_id = user_id.0), err)]
+    async fn is_opted_out(&self, guild_id: GuildId, user_id: UserId) -> Result<bool, DbErr> {
+        artist_optout::Entity::find_by_id((guild_id.0 as i64, user_id.0 as i64))
+            .one(self.db_connection.as_ref())
+            .await
+            .map(|optout| optout.is_some())
+    }
+
+    #[instrument(level = "debug", skip(self), fields(message_id = message_id.0), err)]
+    async fn find_message_posts(&self, message_id: MessageId) -> Result<Vec<gallery_post::Model>, DbErr> {
+        gallery_post::Entity::find()
+            .filter(gallery_post::Column::DiscordMessageId.eq(message_id.0 as i64))
+            .all(self.db_connection.as_ref())
+            .await
+    }
+
+}
+
+/// The posts a message's attachments and embeds were saved to.
+#[derive(Debug, Default)]
+struct UpsertedPosts {
+    created: Vec<Uuid>,
+    updated: Vec<Uuid>
+}
+
+/// The post columns that come from Discord, and can change when a message is edited or replayed.
+const SOURCE_COLUMNS: [gallery_post::Column; 10] = [
+    gallery_post::Column::DiscordAuthorId,
+    gallery_post::Column::AuthorName,
+    gallery_post::Column::Content,
+    gallery_post::Column::SourceUrl,
+    gallery_post::Column::MediaUrl,
+    gallery_post::Column::MediaWidth,
+    gallery_post::Column::MediaHeight,
+    gallery_post::Column::ThumbnailUrl,
+    gallery_post::Column::ThumbnailWidth,
+    gallery_post::Column::ThumbnailHeight
+];
+
+/// Saves the posts created from a single message, updating the ones that already exist in place
+/// so replayed or backfilled messages don't duplicate them. New posts are linked to the message's tags.
+async fn upsert_message_posts(
+    txn: &DatabaseTransaction,
+    gallery_id: Uuid,
+    discord_message_id: u64,
+    new_posts: Vec<gallery_post::ActiveModel>,
+    message_tags: &[String]
+) -> Result<UpsertedPosts, DbErr> {
+    if new_posts.is_empty() {
+        debug!("No new posts to insert.");
+        return Ok(UpsertedPosts::default())
+    }
+
+    let message_posts = gallery_post::Entity::find()
+        .filter(gallery_post::Column::Gallery.eq(gallery_id))
+        .filter(gallery_post::Column::DiscordMessageId.eq(discord_message_id as i64));
+
+    let existing_keys = message_posts.clone()
+        .all(txn)
+        .await?
+        .into_iter()
+        .map(|p| p.source_key)
+        .collect::<HashSet<String>>();
+    let upserted_keys = new_posts.iter()
+        .map(|p| p.source_key.as_ref().clone())
+        .collect::<HashSet<String>>();
+
+    let mut insert = gallery_post::Entity::insert_many(new_posts);
+    insert.query().on_conflict(
+        OnConflict::columns([gallery_post::Column::Gallery, gallery_post::Column::DiscordMessageId, gallery_post::Column::SourceKey])
+            .update_columns(SOURCE_COLUMNS)
+            .to_owned()
+    );
+    txn.execute(txn.get_database_backend().build(insert.query())).await?;
+
+    let mut upserted = UpsertedPosts::default();
+    for post in message_posts.all(txn).await? {
+        if !upserted_keys.contains(&post.source_key) {
+            continue;
+        }
+        if existing_keys.contains(&post.source_key) {
+            upserted.updated.push(post.pk);
+        } else {
+            upserted.created.push(post.pk);
+        }
+    }
+
+    debug!("Linking {} new posts to tags {:?}.", upserted.created.len(), message_tags);
+    tags::link_tags(txn, &upserted.created, message_tags).await?;
+
+    Ok(upserted)
+}
+
+/// Applies the values `new` sets to `old`, leaving the columns it doesn't set alone.
+/// Returns None when nothing would change.
+fn changed_post(old: &gallery_post::Model, new: &gallery_post::ActiveModel) -> Option<gallery_post::ActiveModel> {
+    let mut changed = old.clone().into_active_model();
+    let mut has_changes = false;
+
+    for column in SOURCE_COLUMNS {
+        if let ActiveValue::Set(value) = new.get(column) {
+            if changed.get(column).into_value().as_ref() != Some(&value) {
+                changed.set(column, value);
+                has_changes = true;
+            }
+        }
+    }
+
+    if has_changes { Some(changed) } else { None }
+}
+
+/// Registers `/gallery optout` and `/gallery optin`, replacing the bot's other slash commands.
+/// The `gallery` chat commands keep working alongside them.
+async fn register_slash_commands(http: &Http) -> serenity::Result<()> {
+    ApplicationCommand::set_global_application_commands(http, |commands| commands
+        .create_application_command(|command| command
+            .name("gallery")
+            .description("Galleries of the art posted in this server")
+            .dm_permission(false)
+            .create_option(|option| option
+                .name("optout")
+                .description("Keep your posts out of this server's galleries")
+                .kind(ApplicationCommandOptionType::SubCommand))
+            .create_option(|option| option
+                .name("optin")
+                .description("Show your posts in this server's galleries again")
+                .kind(ApplicationCommandOptionType::SubCommand))
+        )
+    ).await?;
+
+    Ok(())
+}
+
+/// Whether the author of `msg` can manage the channel it was sent in. Always true outside of servers.
+async fn author_can_manage_channel(ctx: &Context, msg: &Message) -> Result<bool> {
+    match msg.guild_id {
+        Some(guild_id) => Ok(member_permissions_in(&ctx.http, guild_id, msg.channel_id, msg.author.id).await?.manage_channels()),
+        None => Ok(true)
+    }
+}
+
+/// Computes the permissions a member of a guild has in one of its channels.
+pub async fn member_permissions_in(http: &Http, guild_id: GuildId, channel_id: ChannelId, user_id: UserId) -> Result<Permissions> {
+    let channel = match channel_id.to_channel(http).await? {
+        Channel::Guild(channel) => channel,
+        _ => anyhow::bail!("Channel {} is not a guild channel.", channel_id.0)
+    };
+    let guild = guild_id.to_partial_guild(http).await?;
+    let member = guild_id.member(http, user_id).await?;
+
+    Ok(guild.user_permissions_in(&channel, &member)?)
 }
 
 /// Protected way to send a message to the channel. Logs any errors.
@@ -162,37 +1151,90 @@ async fn send_message(ctx: &Context, channel_id: &ChannelId, message: impl std::
     }
 }
 
+/// What a resync changed.
+#[derive(Debug, Default)]
+pub struct ResyncSummary {
+    pub ingested_messages: usize,
+    pub removed_posts: usize,
+    /// Stopped early because of a shutdown.
+    pub interrupted: bool
+}
+
+/// The message a post was created from.
+struct PostSource {
+    discord_message_id: u64,
+    discord_author_id: Option<u64>,
+    author_name: Option<String>,
+    content: Option<String>,
+    /// When the message was posted. Left to the database's default when unknown.
+    date_created: Option<DateTime<Utc>>
+}
+
+impl PostSource {
+    /// Creates an ActiveModel for a post from this message, with the message fields already set.
+    fn to_post(&self, gallery: &gallery::Model) -> gallery_post::ActiveModel {
+        gallery_post::ActiveModel {
+            gallery: ActiveValue::Set(gallery.pk),
+            discord_message_id: ActiveValue::Set(self.discord_message_id as i64),
+            discord_author_id: ActiveValue::Set(self.discord_author_id.map(|id| id as i64)),
+            author_name: ActiveValue::Set(self.author_name.clone()),
+            content: ActiveValue::Set(self.content.clone()),
+            date_created: ActiveValue::Set(self.date_created.unwrap_or_else(Utc::now)),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an ActiveModel setting only the message fields, to update posts whose media didn't change.
+    fn to_message_update(&self) -> gallery_post::ActiveModel {
+        gallery_post::ActiveModel {
+            discord_author_id: ActiveValue::Set(self.discord_author_id.map(|id| id as i64)),
+            author_name: ActiveValue::Set(self.author_name.clone()),
+            content: ActiveValue::Set(self.content.clone()),
+            ..<gallery_post::ActiveModel as ActiveModelTrait>::default()
+        }
+    }
+}
+
+/// Source keys of posts made from attachments are this followed by the attachment id.
+const ATTACHMENT_KEY_PREFIX: &str = "attachment:";
+/// Source keys of posts made from embeds are this followed by the embed's index.
+const EMBED_KEY_PREFIX: &str = "embed:";
+
 // Converts an iterator of Attachment objects to an iterator of gallery_post::ActiveModel objects. 
 fn attachments_to_db<'r>(
     attachments: impl Iterator<Item = Attachment> + 'r,
     gallery: &'r gallery::Model,
-    discord_message_id: u64
+    source: &'r PostSource
 ) -> impl Iterator<Item = gallery_post::ActiveModel> + 'r {
+    // Every column embeds set is set here too, since insert_many needs all its models to set the same ones
     attachments.filter(attachment_is_image).map(move |a| gallery_post::ActiveModel {
-        gallery: ActiveValue::Set(gallery.pk),
-        discord_message_id: ActiveValue::Set(discord_message_id as i64),
+        source_key: ActiveValue::Set(format!("{}{}", ATTACHMENT_KEY_PREFIX, a.id.0)),
+        source_url: ActiveValue::Set(None),
         media_url: ActiveValue::Set(Some(a.url)),
         media_width: ActiveValue::Set(a.width.and_then(|i| i32::try_from(i).ok())),
         media_height: ActiveValue::Set(a.height.and_then(|i| i32::try_from(i).ok())),
-        ..Default::default()
+        thumbnail_url: ActiveValue::Set(None),
+        thumbnail_width: ActiveValue::Set(None),
+        thumbnail_height: ActiveValue::Set(None),
+        ..source.to_post(gallery)
     })
 }
 
 fn embeds_to_db<'r>(
     embeds: impl Iterator<Item = Embed> + 'r,
     gallery: &'r gallery::Model,
-    discord_message_id: u64
+    source: &'r PostSource
 ) -> impl Iterator<Item = gallery_post::ActiveModel> + 'r {
-    embeds.filter_map(move |e|
-        if e.image.is_none() && e.thumbnail.is_none() {
-            None
-        } else {
+    // Embeds are keyed by their position among the ones with media, the only ones that were ever stored
+    embeds
+        .filter(|e| e.image.is_some() || e.thumbnail.is_some())
+        .enumerate()
+        .map(move |(index, e)| {
             let (image_url, image_width, image_height) = transpose_embed_image(e.image);
             let (thumbnail_url, thumbnail_width, thumbnail_height) = tranpose_embed_thumbnail(e.thumbnail);
-            
-            Some(gallery_post::ActiveModel {
-                gallery: ActiveValue::Set(gallery.pk),
-                discord_message_id: ActiveValue::Set(discord_message_id as i64),
+
+            gallery_post::ActiveModel {
+                source_key: ActiveValue::Set(format!("{}{}", EMBED_KEY_PREFIX, index)),
                 source_url: ActiveValue::Set(e.url),
                 media_url: ActiveValue::Set(image_url),
                 media_width: ActiveValue::Set(image_width),
@@ -200,10 +1242,9 @@ fn embeds_to_db<'r>(
                 thumbnail_url: ActiveValue::Set(thumbnail_url),
                 thumbnail_width: ActiveValue::Set(thumbnail_width),
                 thumbnail_height: ActiveValue::Set(thumbnail_height),
-                ..Default::default()
-            })
-        }
-    )
+                ..source.to_post(gallery)
+            }
+        })
 }
 
 fn attachment_is_image(a: &Attachment) -> bool {
@@ -226,4 +1267,413 @@ fn transpose_embed_image(image: Option<EmbedImage>) -> (Option<String>, Option<i
         i.height.and_then(|h| i32::try_from(h).ok())
     ))
     .unwrap_or_default()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde_json::{json, Value};
+    use serenity::http::HttpBuilder;
+    use sea_orm::QueryOrder;
+    use tokio::sync::broadcast;
+    use warp::{Filter, http::StatusCode};
+
+    use super::*;
+    use crate::events::event_channel;
+    use crate::test_db::{self, day, insert_gallery, insert_post};
+
+    const CHANNEL_ID: u64 = 10;
+    const GUILD_ID: u64 = 20;
+    const MESSAGE_ID: u64 = 30;
+
+    async fn handler() -> Handler {
+        let (db, pool) = test_db::sqlite().await;
+        insert_gallery(&db, CHANNEL_ID as i64, Some(GUILD_ID as i64)).await;
+        let db = Arc::new(db);
+
+        Handler {
+            base_url: "https://galleria.example".to_owned(),
+            command_prefix: "~".to_owned(),
+            db_connection: db.clone(),
+            tag_parser: TagParser::new(None).unwrap(),
+            gallery_defaults: GalleryDefaults::default(),
+            features: Features::default(),
+            events: EventNotifier::new(db, event_channel()),
+            share_signer: None,
+            shutdown: Shutdown::new(),
+            metrics: Arc::new(Metrics::new(pool)),
+            gateway: Arc::new(GatewayStatus::default()),
+            duplicates: None,
+            repost_reaction: None
+        }
+    }
+
+    /// A Discord API whose channel history is `messages`, all on the first page. `on_page` runs whenever it's served.
+    fn mock_discord(messages: Vec<Value>, on_page: impl Fn() + Clone + Send + Sync + 'static) -> Http {
+        let api = warp::query::<HashMap<String, String>>().map(move |query: HashMap<String, String>| {
+            if query.contains_key("before") {
+                return warp::reply::json(&Vec::<Value>::new());
+            }
+            on_page();
+            warp::reply::json(&messages)
+        });
+        let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        HttpBuilder::new("token")
+            .proxy(format!("http://{}/", addr))
+            .unwrap()
+            .ratelimiter_disabled(true)
+            .build()
+    }
+
+    /// A Discord API that answers every request with `status` and `body`, and counts the requests.
+    fn mock_api(status: StatusCode, body: Value) -> (Http, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let api = warp::any().map(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            warp::reply::with_status(warp::reply::json(&body), status)
+        });
+        let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let http = HttpBuilder::new("token")
+            .proxy(format!("http://{}/", addr))
+            .unwrap()
+            .ratelimiter_disabled(true)
+            .build();
+        (http, requests)
+    }
+
+    fn user() -> Value {
+        json!({ "id": "100", "username": "artist", "discriminator": "0001", "avatar": null })
+    }
+
+    fn attachment() -> Value {
+        json!({
+            "id": "40",
+            "filename": "drawing.png",
+            "url": "https://cdn.discordapp.com/attachments/10/40/drawing.png",
+            "proxy_url": "https://media.discordapp.net/attachments/10/40/drawing.png",
+            "size": 1024,
+            "width": 800,
+            "height": 600,
+            "content_type": "image/png"
+        })
+    }
+
+    fn message(attachments: Vec<Value>, embeds: Vec<Value>) -> Value {
+        json!({
+            "id": MESSAGE_ID.to_string(),
+            "channel_id": CHANNEL_ID.to_string(),
+            "guild_id": GUILD_ID.to_string(),
+            "author": user(),
+            "content": "New drawing, also https://example.com/painting.png #sketch",
+            "timestamp": "2022-09-01T12:00:00+00:00",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": attachments,
+            "embeds": embeds,
+            "pinned": false,
+            "type": 0
+        })
+    }
+
+    fn link_embed() -> Value {
+        json!({
+            "type": "image",
+            "url": "https://example.com/painting.png",
+            "thumbnail": { "url": "https://example.com/painting.png", "width": 400, "height": 300 }
+        })
+    }
+
+    /// A message update carrying only `fields`, as Discord sends them.
+    fn message_update(fields: Value) -> MessageUpdateEvent {
+        let mut update = json!({
+            "id": MESSAGE_ID.to_string(),
+            "channel_id": CHANNEL_ID.to_string(),
+            "guild_id": GUILD_ID.to_string()
+        });
+        update.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(update).unwrap()
+    }
+
+    async fn stored_posts(handler: &Handler) -> Vec<gallery_post::Model> {
+        gallery_post::Entity::find()
+            .order_by_asc(gallery_post::Column::Pk)
+            .all(handler.db_connection.as_ref())
+            .await
+            .unwrap()
+    }
+
+    /// The handler, with its events published to the returned receiver. SQLite only publishes events to this process.
+    fn subscribed(handler: Handler) -> (Handler, broadcast::Receiver<GalleryEvent>) {
+        let events = event_channel();
+        let receiver = events.subscribe();
+        (Handler { events: EventNotifier::new(handler.db_connection.clone(), events), ..handler }, receiver)
+    }
+
+    /// The gallery and sorted posts of an event, to compare events regardless of post order.
+    fn sorted_event(event: GalleryEvent) -> (Uuid, Vec<Uuid>) {
+        let mut posts = event.posts().to_vec();
+        posts.sort();
+        (event.gallery(), posts)
+    }
+
+    #[tokio::test]
+    async fn deleting_a_message_removes_its_posts() {
+        let (handler, mut events) = subscribed(handler().await);
+        let (http, _) = mock_api(StatusCode::INTERNAL_SERVER_ERROR, json!({}));
+
+        let msg: Message = serde_json::from_value(message(vec![attachment()], Vec::new())).unwrap();
+        handler.handle_new_message(&http, msg, false).await.unwrap();
+        let posts = stored_posts(&handler).await;
+        assert_eq!(posts.len(), 1);
+        let gallery_pk = posts[0].gallery;
+        let post_pks = posts.iter().map(|p| p.pk).collect::<Vec<Uuid>>();
+
+        let created = events.try_recv().unwrap();
+        assert!(matches!(created, GalleryEvent::PostsCreated { .. }));
+        assert_eq!(sorted_event(created), (gallery_pk, post_pks.clone()));
+
+        // Messages outside of galleries and unknown messages are ignored
+        handler.handle_message_delete(ChannelId(CHANNEL_ID + 1), vec![MessageId(MESSAGE_ID)]).await.unwrap();
+        handler.handle_message_delete(ChannelId(CHANNEL_ID), vec![MessageId(MESSAGE_ID + 1)]).await.unwrap();
+        assert_eq!(stored_posts(&handler).await.len(), 1);
+        assert!(events.try_recv().is_err());
+
+        handler.handle_message_delete(ChannelId(CHANNEL_ID), vec![MessageId(MESSAGE_ID)]).await.unwrap();
+        assert!(stored_posts(&handler).await.is_empty());
+        let deleted = events.try_recv().unwrap();
+        assert!(matches!(deleted, GalleryEvent::PostsDeleted { .. }));
+        assert_eq!(sorted_event(deleted), (gallery_pk, post_pks));
+    }
+
+    #[tokio::test]
+    async fn opting_out_twice_is_harmless() {
+        let handler = handler().await;
+        let (guild_id, user_id) = (Some(GuildId(GUILD_ID)), UserId(100));
+
+        assert!(handler.opt_out(guild_id, user_id).await.unwrap().starts_with("You have opted out."));
+        assert!(handler.opt_out(guild_id, user_id).await.unwrap().starts_with("You have already opted out"));
+        assert!(handler.is_opted_out(GuildId(GUILD_ID), user_id).await.unwrap());
+
+        assert!(handler.opt_in(guild_id, user_id).await.unwrap().starts_with("You have opted back in."));
+        assert!(handler.opt_in(guild_id, user_id).await.unwrap().starts_with("You are not opted out"));
+        assert!(handler.opt_out(None, user_id).await.unwrap().contains("inside a server"));
+    }
+
+    #[tokio::test]
+    async fn replayed_messages_fill_in_missing_authors_of_opted_out_posts() {
+        let handler = handler().await;
+        let (http, _) = mock_api(StatusCode::INTERNAL_SERVER_ERROR, json!({}));
+
+        // As if ingested before authors were recorded
+        let msg: Message = serde_json::from_value(message(vec![attachment()], Vec::new())).unwrap();
+        handler.handle_new_message(&http, msg.clone(), false).await.unwrap();
+        gallery_post::Entity::update_many()
+            .col_expr(gallery_post::Column::DiscordAuthorId, Expr::value(Option::<i64>::None))
+            .exec(handler.db_connection.as_ref())
+            .await
+            .unwrap();
+
+        handler.opt_out(Some(GuildId(GUILD_ID)), UserId(100)).await.unwrap();
+        handler.handle_new_message(&http, msg, false).await.unwrap();
+
+        let posts = stored_posts(&handler).await;
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].discord_author_id, Some(100));
+    }
+
+    #[tokio::test]
+    async fn replayed_messages_update_their_posts() {
+        let handler = handler().await;
+        let (http, _) = mock_api(StatusCode::INTERNAL_SERVER_ERROR, json!({}));
+
+        let msg: Message = serde_json::from_value(message(vec![attachment()], Vec::new())).unwrap();
+        handler.handle_new_message(&http, msg.clone(), false).await.unwrap();
+        let posts = stored_posts(&handler).await;
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].source_key, "attachment:40");
+
+        let mut replayed = msg;
+        replayed.content = "New drawing, now finished".to_owned();
+        handler.handle_new_message(&http, replayed, false).await.unwrap();
+        let replayed_posts = stored_posts(&handler).await;
+        assert_eq!(replayed_posts.len(), 1);
+        assert_eq!(replayed_posts[0].pk, posts[0].pk);
+        assert_eq!(replayed_posts[0].content.as_deref(), Some("New drawing, now finished"));
+    }
+
+    #[tokio::test]
+    async fn updates_keep_the_pks_of_unchanged_posts() {
+        let (handler, mut events) = subscribed(handler().await);
+        let (http, _) = mock_api(StatusCode::INTERNAL_SERVER_ERROR, json!({}));
+
+        let msg: Message = serde_json::from_value(message(vec![attachment()], vec![link_embed()])).unwrap();
+        handler.handle_new_message(&http, msg, false).await.unwrap();
+        let created = stored_posts(&handler).await;
+        assert_eq!(created.len(), 2);
+        events.try_recv().unwrap();
+
+        // Editing the text retags every post but keeps them
+        handler.handle_message_update(&http, message_update(json!({ "content": "Finished drawing #ink" }))).await.unwrap();
+        let edited = stored_posts(&handler).await;
+        assert_eq!(edited.iter().map(|p| p.pk).collect::<Vec<Uuid>>(), created.iter().map(|p| p.pk).collect::<Vec<Uuid>>());
+        assert!(edited.iter().all(|p| p.content.as_deref() == Some("Finished drawing #ink")));
+        let tags = tags::find_post_tags(handler.db_connection.as_ref(), &[edited[0].pk]).await.unwrap();
+        assert_eq!(tags, vec!["ink".to_owned()]);
+        assert_eq!(sorted_event(events.try_recv().unwrap()).1.len(), 2);
+
+        // Removing the embed removes only its post
+        handler.handle_message_update(&http, message_update(json!({ "embeds": [] }))).await.unwrap();
+        let remaining = stored_posts(&handler).await;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].source_key, "attachment:40");
+        assert!(matches!(events.try_recv().unwrap(), GalleryEvent::PostsDeleted { .. }));
+
+        // Replaying the update changes nothing
+        handler.handle_message_update(&http, message_update(json!({ "embeds": [] }))).await.unwrap();
+        assert_eq!(stored_posts(&handler).await, remaining);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn resyncs_ingest_new_messages_and_remove_stale_posts() {
+        let handler = handler().await;
+        let gallery_model = handler.find_gallery_from_channel_id(ChannelId(CHANNEL_ID)).await.unwrap().unwrap();
+        let stale = insert_post(&handler.db_connection, &gallery_model, 99, 100, "Deleted drawing", day(1)).await;
+
+        let http = mock_discord(vec![message(vec![attachment()], Vec::new())], || ());
+        let summary = handler.resync_gallery(&http, &gallery_model).await.unwrap();
+        assert_eq!(summary.ingested_messages, 1);
+        assert_eq!(summary.removed_posts, 1);
+
+        let posts = stored_posts(&handler).await;
+        assert_eq!(posts.len(), 1);
+        assert_ne!(posts[0].pk, stale.pk);
+        assert_eq!(posts[0].discord_message_id, MESSAGE_ID as i64);
+
+        // Nothing left to do the second time around
+        let summary = handler.resync_gallery(&http, &gallery_model).await.unwrap();
+        assert_eq!(summary.ingested_messages, 0);
+        assert_eq!(summary.removed_posts, 0);
+    }
+
+    async fn backfill_cursor(handler: &Handler) -> Option<i64> {
+        handler.find_gallery_from_channel_id(ChannelId(CHANNEL_ID)).await.unwrap().unwrap().backfill_before
+    }
+
+    #[tokio::test]
+    async fn interrupted_backfills_continue_where_they_stopped() {
+        let handler = handler().await;
+        let gallery_model = handler.find_gallery_from_channel_id(ChannelId(CHANNEL_ID)).await.unwrap().unwrap();
+
+        let shutdown = handler.shutdown.clone();
+        let http = mock_discord(vec![message(vec![attachment()], Vec::new())], move || shutdown.trigger());
+        let summary = handler.backfill_gallery(&http, &gallery_model).await.unwrap();
+        assert!(summary.interrupted);
+        assert_eq!(summary.ingested_messages, 1);
+        assert_eq!(backfill_cursor(&handler).await, Some(MESSAGE_ID as i64));
+
+        let handler = Handler { shutdown: Shutdown::new(), ..handler };
+        let gallery_model = handler.find_gallery_from_channel_id(ChannelId(CHANNEL_ID)).await.unwrap().unwrap();
+        let http = mock_discord(Vec::new(), || panic!("the backfill started over"));
+        let summary = handler.backfill_gallery(&http, &gallery_model).await.unwrap();
+        assert!(!summary.interrupted);
+        assert_eq!(backfill_cursor(&handler).await, None);
+    }
+
+    #[tokio::test]
+    async fn interrupted_resyncs_leave_backfills_and_posts_alone() {
+        let handler = handler().await;
+        let gallery_model = handler.find_gallery_from_channel_id(ChannelId(CHANNEL_ID)).await.unwrap().unwrap();
+        let stale = insert_post(&handler.db_connection, &gallery_model, 99, 100, "Deleted drawing", day(1)).await;
+        // A backfill that was interrupted earlier
+        handler.set_backfill_cursor(&gallery_model, Some(MessageId(5))).await.unwrap();
+
+        let shutdown = handler.shutdown.clone();
+        let http = mock_discord(vec![message(vec![attachment()], Vec::new())], move || shutdown.trigger());
+        let summary = handler.resync_gallery(&http, &gallery_model).await.unwrap();
+        assert!(summary.interrupted);
+        assert_eq!(summary.removed_posts, 0);
+
+        assert_eq!(backfill_cursor(&handler).await, Some(5));
+        assert!(stored_posts(&handler).await.iter().any(|post| post.pk == stale.pk));
+    }
+
+    #[tokio::test]
+    async fn embed_only_updates_keep_attachment_posts() {
+        let handler = handler().await;
+        let (http, requests) = mock_api(StatusCode::INTERNAL_SERVER_ERROR, json!({}));
+
+        let msg: Message = serde_json::from_value(message(vec![attachment()], Vec::new())).unwrap();
+        handler.handle_new_message(&http, msg, false).await.unwrap();
+        let created = stored_posts(&handler).await;
+        assert_eq!(created.len(), 1);
+
+        // The update Discord sends once it has fetched the embed for the message's link
+        let embed_update = || message_update(json!({ "embeds": [link_embed()] }));
+        handler.handle_message_update(&http, embed_update()).await.unwrap();
+        let updated = stored_posts(&handler).await;
+        assert_eq!(updated.len(), 2);
+
+        // The attachment post is untouched and the embed's post takes its author and content from it
+        let attachment_post = updated.iter().find(|p| p.source_key == "attachment:40").unwrap();
+        let embed_post = updated.iter().find(|p| p.source_key == "embed:0").unwrap();
+        assert_eq!(attachment_post, &created[0]);
+        assert_eq!(embed_post.thumbnail_url.as_deref(), Some("https://example.com/painting.png"));
+        assert_eq!(embed_post.discord_author_id, Some(100));
+        assert_eq!(embed_post.content, created[0].content);
+        assert_eq!(embed_post.date_created, created[0].date_created);
+        assert_eq!(tags::find_post_tags(handler.db_connection.as_ref(), &[embed_post.pk]).await.unwrap(), vec!["sketch".to_owned()]);
+
+        // Everything came from the database, Discord wasn't asked
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+        // Replaying the update changes nothing
+        handler.handle_message_update(&http, embed_update()).await.unwrap();
+        assert_eq!(stored_posts(&handler).await, updated);
+    }
+
+    #[tokio::test]
+    async fn updates_of_unseen_messages_fetch_them() {
+        let handler = handler().await;
+        let (http, requests) = mock_api(StatusCode::OK, message(Vec::new(), vec![link_embed()]));
+
+        handler.handle_message_update(&http, message_update(json!({ "embeds": [link_embed()] }))).await.unwrap();
+        let posts = stored_posts(&handler).await;
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].discord_author_id, Some(100));
+        assert_eq!(posts[0].author_name.as_deref(), Some("artist"));
+        assert_eq!(posts[0].date_created, Utc.with_ymd_and_hms(2022, 9, 1, 12, 0, 0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_fetches_store_nothing() {
+        let handler = handler().await;
+        let (http, requests) = mock_api(StatusCode::INTERNAL_SERVER_ERROR, json!({ "message": "500: Internal Server Error", "code": 0 }));
+
+        assert!(handler.handle_message_update(&http, message_update(json!({ "embeds": [link_embed()] }))).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(stored_posts(&handler).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn opted_out_authors_are_checked_after_fetching() {
+        let handler = handler().await;
+        handler.opt_out(Some(GuildId(GUILD_ID)), UserId(100)).await.unwrap();
+        let (http, _) = mock_api(StatusCode::OK, message(Vec::new(), vec![link_embed()]));
+
+        handler.handle_message_update(&http, message_update(json!({ "embeds": [link_embed()] }))).await.unwrap();
+        assert!(stored_posts(&handler).await.is_empty());
+    }
+}
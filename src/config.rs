@@ -0,0 +1,835 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Result;
+use sea_orm::{ActiveEnum, DatabaseConnection, SqlxPostgresConnector, SqlxSqliteConnector};
+use serde::Deserialize;
+use serenity::model::channel::ReactionType;
+use serenity::prelude::GatewayIntents;
+use sqlx::{PgPool, SqlitePool};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sql_entities::sea_orm_active_enums::Visibility;
+
+use crate::auth::DiscordAuthConfig;
+use crate::duplicates::DEFAULT_MAX_DISTANCE;
+use crate::shutdown::DEFAULT_SHUTDOWN_TIMEOUT;
+use crate::tags::TagParser;
+
+/// The config file that's read when no other is given, if it exists.
+pub const DEFAULT_CONFIG_FILE: &str = "galleria.toml";
+
+pub const DEFAULT_COMMAND_PREFIX: &str = "~";
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+
+/// Enough to see new messages in guilds and DMs, including their content.
+const DEFAULT_INTENTS: [&str; 3] = ["guild_messages", "direct_messages", "message_content"];
+
+/// The config as read from the file, environment and command line, before it's validated.
+/// Every field is optional here, so all missing and invalid ones can be reported at once.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RawConfig {
+    discord_token: Option<String>,
+    base_url: Option<String>,
+    listen_addr: Option<String>,
+    command_prefix: Option<String>,
+    tag_pattern: Option<String>,
+    intents: Option<Vec<String>>,
+    /// In seconds.
+    shutdown_timeout: Option<u64>,
+    database: RawDatabase,
+    share_links: RawShareLinks,
+    oauth: RawOAuth,
+    admin: RawAdmin,
+    features: RawFeatures,
+    metrics: RawMetrics,
+    duplicates: RawDuplicates,
+    defaults: RawGalleryDefaults,
+    guilds: BTreeMap<String, RawGalleryDefaults>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+    /// Problems found while reading the sources, reported along with the ones found by validation.
+    #[serde(skip)]
+    errors: Vec<ConfigError>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawDatabase {
+    url: Option<String>,
+    max_connections: Option<u32>,
+    min_connections: Option<u32>,
+    auto_migrate: Option<bool>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawShareLinks {
+    secret: Option<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawOAuth {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    authorize_url: Option<String>,
+    token_url: Option<String>,
+    api_url: Option<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawAdmin {
+    user_ids: Option<Vec<u64>>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawFeatures {
+    feeds: Option<bool>,
+    downloads: Option<bool>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawMetrics {
+    listen_addr: Option<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawDuplicates {
+    enabled: Option<bool>,
+    max_distance: Option<u32>,
+    repost_reaction: Option<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+struct RawGalleryDefaults {
+    visibility: Option<String>,
+    required_role_id: Option<u64>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>
+}
+
+/// Settings given on the command line, which take precedence over everything else.
+#[derive(Debug, Default)]
+pub struct ConfigOverrides {
+    pub database_url: Option<String>,
+    pub base_url: Option<String>,
+    pub listen_addr: Option<String>
+}
+
+/// A problem with a single setting.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// Every problem found in the config.
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The configuration has {} problem{}:", self.0.len(), if self.0.len() == 1 { "" } else { "s" })?;
+        for error in &self.0 {
+            write!(f, "\n  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// The validated config.
+pub struct Config {
+    pub discord_token: String,
+    pub base_url: String,
+    pub listen_addr: SocketAddr,
+    pub command_prefix: String,
+    pub tag_pattern: Option<String>,
+    pub intents: GatewayIntents,
+    /// How long in-flight requests and ingestion get to finish when shutting down.
+    pub shutdown_timeout: Duration,
+    pub database: DatabaseConfig,
+    /// Share links can only be made when a secret is set, but private galleries stay private without one.
+    pub share_secret: Option<String>,
+    /// Signing in with Discord is enabled when the application's OAuth2 credentials are set.
+    pub oauth: Option<DiscordAuthConfig>,
+    /// The admin area is enabled when any admins are set.
+    pub admin_user_ids: Vec<u64>,
+    pub features: Features,
+    /// Metrics are served on their own address, since they include the ids of unlisted galleries.
+    pub metrics_listen_addr: Option<SocketAddr>,
+    pub duplicates: DuplicatesConfig,
+    pub gallery_defaults: GalleryDefaults
+}
+
+#[derive(Debug, Clone)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    /// Whether pending migrations are applied on startup, instead of refusing to start.
+    pub auto_migrate: bool
+}
+
+impl DatabaseConfig {
+    /// Whether this is a Postgres database. Only Postgres can pass events between processes.
+    pub fn is_postgres(&self) -> bool {
+        self.url.starts_with("postgres://") || self.url.starts_with("postgresql://")
+    }
+
+    /// Connects to the database, creating it first when it's an SQLite file that doesn't exist yet.
+    /// The pool is returned too, so its stats can be reported.
+    pub async fn connect(&self) -> Result<(DatabaseConnection, DatabasePool)> {
+        if self.is_postgres() {
+            let pool = PgPoolOptions::new()
+                .max_connections(self.max_connections)
+                .min_connections(self.min_connections)
+                .connect(&self.url)
+                .await?;
+
+            Ok((SqlxPostgresConnector::from_sqlx_postgres_pool(pool.clone()), DatabasePool::Postgres(pool)))
+        } else {
+            let options = SqliteConnectOptions::from_str(&self.url)?.create_if_missing(true);
+            let pool = SqlitePoolOptions::new()
+                .max_connections(self.max_connections)
+                .min_connections(self.min_connections)
+                .connect_with(options)
+                .await?;
+
+            Ok((SqlxSqliteConnector::from_sqlx_sqlite_pool(pool.clone()), DatabasePool::Sqlite(pool)))
+        }
+    }
+}
+
+/// The connection pool behind a [`DatabaseConnection`].
+#[derive(Clone)]
+pub enum DatabasePool {
+    Postgres(PgPool),
+    Sqlite(SqlitePool)
+}
+
+impl DatabasePool {
+    pub fn size(&self) -> u32 {
+        match self {
+            DatabasePool::Postgres(pool) => pool.size(),
+            DatabasePool::Sqlite(pool) => pool.size()
+        }
+    }
+
+    pub fn num_idle(&self) -> usize {
+        match self {
+            DatabasePool::Postgres(pool) => pool.num_idle(),
+            DatabasePool::Sqlite(pool) => pool.num_idle()
+        }
+    }
+}
+
+/// Parts of the web frontend that can be turned off.
+#[derive(Debug, Clone, Copy)]
+pub struct Features {
+    pub feeds: bool,
+    pub downloads: bool
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features { feeds: true, downloads: true }
+    }
+}
+
+/// How reposts of the same image are found.
+#[derive(Debug, Clone)]
+pub struct DuplicatesConfig {
+    /// Whether images are downloaded and hashed on ingestion. Off unless turned on, since it fetches every image.
+    pub enabled: bool,
+    /// How many bits the perceptual hashes of two images may differ by for them to count as the same image.
+    pub max_distance: u32,
+    /// Added to messages reposting an image, when set.
+    pub repost_reaction: Option<ReactionType>
+}
+
+/// What new galleries start out as. Guilds can have their own defaults, which fall back to the global ones.
+#[derive(Debug, Clone, Default)]
+pub struct GalleryDefaults {
+    default: GuildDefaults,
+    guilds: HashMap<u64, GuildDefaults>
+}
+
+impl GalleryDefaults {
+    pub fn for_guild(&self, guild_id: Option<u64>) -> GuildDefaults {
+        guild_id.and_then(|guild_id| self.guilds.get(&guild_id))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GuildDefaults {
+    pub visibility: Visibility,
+    pub required_role_id: Option<u64>
+}
+
+impl Default for GuildDefaults {
+    fn default() -> Self {
+        GuildDefaults { visibility: Visibility::Public, required_role_id: None }
+    }
+}
+
+/// Reads the config file, then applies environment variables and command line flags over it.
+/// A file that was asked for must exist, the default file is only read if it does.
+pub fn load_config(file: Option<&Path>, overrides: ConfigOverrides) -> RawConfig {
+    let mut config = match read_config_file(file) {
+        Ok(config) => config,
+        Err(message) => {
+            let mut config = RawConfig::default();
+            config.error("file", message);
+            config
+        }
+    };
+
+    config.apply_env();
+
+    config.database.url = overrides.database_url.or(config.database.url);
+    config.base_url = overrides.base_url.or(config.base_url);
+    config.listen_addr = overrides.listen_addr.or(config.listen_addr);
+
+    config
+}
+
+fn read_config_file(file: Option<&Path>) -> Result<RawConfig, String> {
+    let (path, required) = match file {
+        Some(path) => (path, true),
+        None => (Path::new(DEFAULT_CONFIG_FILE), false)
+    };
+
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(why) if !required && why.kind() == std::io::ErrorKind::NotFound => return Ok(RawConfig::default()),
+        Err(why) => return Err(format!("Could not read {}: {}", path.display(), why))
+    };
+
+    toml::from_str(&contents).map_err(|why| format!("Could not parse {}: {}", path.display(), why))
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+impl RawConfig {
+    fn apply_env(&mut self) {
+        fn set(target: &mut Option<String>, name: &str) {
+            if let Some(value) = env_var(name) {
+                *target = Some(value);
+            }
+        }
+
+        set(&mut self.discord_token, "DISCORD_TOKEN");
+        set(&mut self.base_url, "BASE_URL");
+        set(&mut self.listen_addr, "LISTEN_ADDR");
+        set(&mut self.command_prefix, "COMMAND_PREFIX");
+        set(&mut self.tag_pattern, "TAG_PATTERN");
+        set(&mut self.database.url, "DATABASE_URL");
+        set(&mut self.share_links.secret, "SHARE_SECRET");
+        set(&mut self.oauth.client_id, "DISCORD_CLIENT_ID");
+        set(&mut self.oauth.client_secret, "DISCORD_CLIENT_SECRET");
+        set(&mut self.oauth.authorize_url, "OAUTH_AUTHORIZE_URL");
+        set(&mut self.oauth.token_url, "OAUTH_TOKEN_URL");
+        set(&mut self.oauth.api_url, "DISCORD_API_URL");
+        set(&mut self.metrics.listen_addr, "METRICS_LISTEN_ADDR");
+        set(&mut self.duplicates.repost_reaction, "REPOST_REACTION");
+
+        if let Some(intents) = env_var("DISCORD_INTENTS") {
+            self.intents = Some(split_list(&intents).map(str::to_owned).collect());
+        }
+        if let Some(shutdown_timeout) = self.parse_env("SHUTDOWN_TIMEOUT", "shutdown_timeout") {
+            self.shutdown_timeout = Some(shutdown_timeout);
+        }
+        if let Some(max_connections) = self.parse_env("DATABASE_MAX_CONNECTIONS", "database.max_connections") {
+            self.database.max_connections = Some(max_connections);
+        }
+        if let Some(min_connections) = self.parse_env("DATABASE_MIN_CONNECTIONS", "database.min_connections") {
+            self.database.min_connections = Some(min_connections);
+        }
+        if let Some(auto_migrate) = self.parse_env("DATABASE_AUTO_MIGRATE", "database.auto_migrate") {
+            self.database.auto_migrate = Some(auto_migrate);
+        }
+        if let Some(feeds) = self.parse_env("FEATURE_FEEDS", "features.feeds") {
+            self.features.feeds = Some(feeds);
+        }
+        if let Some(downloads) = self.parse_env("FEATURE_DOWNLOADS", "features.downloads") {
+            self.features.downloads = Some(downloads);
+        }
+        if let Some(enabled) = self.parse_env("DUPLICATES_ENABLED", "duplicates.enabled") {
+            self.duplicates.enabled = Some(enabled);
+        }
+        if let Some(max_distance) = self.parse_env("DUPLICATES_MAX_DISTANCE", "duplicates.max_distance") {
+            self.duplicates.max_distance = Some(max_distance);
+        }
+        if let Some(ids) = env_var("ADMIN_USER_IDS") {
+            match split_list(&ids).map(str::parse).collect::<Result<Vec<u64>, _>>() {
+                Ok(ids) => self.admin.user_ids = Some(ids),
+                Err(why) => self.error("admin.user_ids", format!("ADMIN_USER_IDS is not a comma separated list of user ids: {}", why))
+            }
+        }
+    }
+
+    fn parse_env<T: FromStr>(&mut self, name: &str, key: &str) -> Option<T>
+    where T::Err: fmt::Display {
+        let value = env_var(name)?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(why) => {
+                self.error(key, format!("{} is invalid: {}", name, why));
+                None
+            }
+        }
+    }
+
+    fn error(&mut self, key: &str, message: impl Into<String>) {
+        self.errors.push(ConfigError { key: key.to_owned(), message: message.into() });
+    }
+
+    /// Validates just the database settings, for commands that don't need anything else.
+    pub fn database(mut self) -> Result<DatabaseConfig, ConfigErrors> {
+        let database = self.validate_database();
+        let errors = self.errors.into_iter()
+            .filter(|error| error.key == "file" || error.key.starts_with("database"))
+            .collect::<Vec<ConfigError>>();
+
+        match database {
+            Some(database) if errors.is_empty() => Ok(database),
+            _ => Err(ConfigErrors(errors))
+        }
+    }
+
+    fn validate_database(&mut self) -> Option<DatabaseConfig> {
+        let unknown = std::mem::take(&mut self.database.unknown);
+        self.report_unknown("database", unknown);
+
+        let url = self.required("database.url", "DATABASE_URL", self.database.url.clone());
+        if let Some(url) = &url {
+            if !(url.starts_with("postgres://") || url.starts_with("postgresql://") || url.starts_with("sqlite:")) {
+                self.error("database.url", "Only Postgres and SQLite databases are supported, the url should start with postgres:// or sqlite:.");
+            }
+        }
+
+        let max_connections = self.database.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        let min_connections = self.database.min_connections.unwrap_or(0);
+        if max_connections == 0 {
+            self.error("database.max_connections", "Must be at least 1.");
+        }
+        if min_connections > max_connections {
+            self.error("database.min_connections", format!("Can't be more than database.max_connections, which is {}.", max_connections));
+        }
+
+        Some(DatabaseConfig {
+            url: url?,
+            max_connections,
+            min_connections,
+            auto_migrate: self.database.auto_migrate.unwrap_or(false)
+        })
+    }
+
+    fn required(&mut self, key: &str, env_name: &str, value: Option<String>) -> Option<String> {
+        if value.is_none() {
+            self.error(key, format!("Is required, set it in the config file or with {}.", env_name));
+        }
+        value
+    }
+
+    fn report_unknown(&mut self, section: &str, unknown: BTreeMap<String, toml::Value>) {
+        for key in unknown.into_keys() {
+            let key = if section.is_empty() { key } else { format!("{}.{}", section, key) };
+            self.error(&key, "Unknown setting.");
+        }
+    }
+
+    /// Checks every setting, collecting all problems instead of stopping at the first.
+    pub fn validate(mut self) -> Result<Config, ConfigErrors> {
+        let unknown = std::mem::take(&mut self.unknown);
+        self.report_unknown("", unknown);
+        for (section, unknown) in [
+            ("share_links", std::mem::take(&mut self.share_links.unknown)),
+            ("oauth", std::mem::take(&mut self.oauth.unknown)),
+            ("admin", std::mem::take(&mut self.admin.unknown)),
+            ("features", std::mem::take(&mut self.features.unknown)),
+            ("metrics", std::mem::take(&mut self.metrics.unknown)),
+            ("duplicates", std::mem::take(&mut self.duplicates.unknown))
+        ] {
+            self.report_unknown(section, unknown);
+        }
+
+        let database = self.validate_database();
+        let discord_token = self.required("discord_token", "DISCORD_TOKEN", self.discord_token.clone());
+
+        let base_url = self.required("base_url", "BASE_URL", self.base_url.clone())
+            .map(|base_url| base_url.trim_end_matches('/').to_owned());
+        if let Some(base_url) = &base_url {
+            if !(base_url.starts_with("http://") || base_url.starts_with("https://")) {
+                self.error("base_url", "Must be an http:// or https:// url.");
+            }
+        }
+
+        let listen_addr = self.required("listen_addr", "LISTEN_ADDR", self.listen_addr.clone())
+            .and_then(|listen_addr| self.parse_addr("listen_addr", &listen_addr));
+        let metrics_listen_addr = self.metrics.listen_addr.clone()
+            .and_then(|metrics_listen_addr| self.parse_addr("metrics.listen_addr", &metrics_listen_addr));
+        if metrics_listen_addr.is_some() && metrics_listen_addr == listen_addr {
+            self.error("metrics.listen_addr", "Must be a different address than listen_addr.");
+        }
+
+        let command_prefix = self.command_prefix.clone().unwrap_or_else(|| DEFAULT_COMMAND_PREFIX.to_owned());
+        if command_prefix.is_empty() || command_prefix.contains(char::is_whitespace) {
+            self.error("command_prefix", "Must not be empty or contain whitespace.");
+        }
+
+        if let Err(why) = TagParser::new(self.tag_pattern.as_deref()) {
+            self.error("tag_pattern", format!("Not a valid tag pattern: {}", why));
+        }
+
+        let intents = self.validate_intents();
+
+        let oauth = match (self.oauth.client_id.take(), self.oauth.client_secret.take()) {
+            (Some(client_id), Some(client_secret)) => Some(DiscordAuthConfig {
+                client_id,
+                client_secret,
+                authorize_url: self.oauth.authorize_url.take().unwrap_or_else(|| DiscordAuthConfig::DEFAULT_AUTHORIZE_URL.to_owned()),
+                token_url: self.oauth.token_url.take().unwrap_or_else(|| DiscordAuthConfig::DEFAULT_TOKEN_URL.to_owned()),
+                api_url: self.oauth.api_url.take().unwrap_or_else(|| DiscordAuthConfig::DEFAULT_API_URL.to_owned())
+            }),
+            (Some(_), None) => {
+                self.error("oauth.client_secret", "Is required when oauth.client_id is set.");
+                None
+            },
+            (None, Some(_)) => {
+                self.error("oauth.client_id", "Is required when oauth.client_secret is set.");
+                None
+            },
+            (None, None) => None
+        };
+
+        let features = Features {
+            feeds: self.features.feeds.unwrap_or(true),
+            downloads: self.features.downloads.unwrap_or(true)
+        };
+
+        let duplicates = self.validate_duplicates();
+        let gallery_defaults = self.validate_gallery_defaults(oauth.is_some());
+
+        let errors = std::mem::take(&mut self.errors);
+        match (discord_token, base_url, listen_addr, database) {
+            (Some(discord_token), Some(base_url), Some(listen_addr), Some(database)) if errors.is_empty() => Ok(Config {
+                discord_token,
+                base_url,
+                listen_addr,
+                command_prefix,
+                tag_pattern: self.tag_pattern,
+                intents,
+                shutdown_timeout: self.shutdown_timeout.map(Duration::from_secs).unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+                database,
+                share_secret: self.share_links.secret,
+                oauth,
+                admin_user_ids: self.admin.user_ids.unwrap_or_default(),
+                features,
+                metrics_listen_addr,
+                duplicates,
+                gallery_defaults
+            }),
+            _ => Err(ConfigErrors(errors))
+        }
+    }
+
+    fn parse_addr(&mut self, key: &str, addr: &str) -> Option<SocketAddr> {
+        match SocketAddr::from_str(addr) {
+            Ok(addr) => Some(addr),
+            Err(why) => {
+                self.error(key, format!("{:?} is not an address and port: {}", addr, why));
+                None
+            }
+        }
+    }
+
+    fn validate_duplicates(&mut self) -> DuplicatesConfig {
+        let max_distance = self.duplicates.max_distance.unwrap_or(DEFAULT_MAX_DISTANCE);
+        if max_distance >= 64 {
+            self.error("duplicates.max_distance", "Must be less than 64, the number of bits in a hash.");
+        }
+
+        let repost_reaction = self.duplicates.repost_reaction.clone()
+            .and_then(|reaction| match ReactionType::try_from(reaction.as_str()) {
+                Ok(reaction) => Some(reaction),
+                Err(why) => {
+                    self.error("duplicates.repost_reaction", format!("{:?} is not an emoji or custom emoji like <:name:id>: {}", reaction, why));
+                    None
+                }
+            });
+
+        DuplicatesConfig {
+            enabled: self.duplicates.enabled.unwrap_or(false),
+            max_distance,
+            repost_reaction
+        }
+    }
+
+    fn validate_intents(&mut self) -> GatewayIntents {
+        let names = self.intents.clone()
+            .unwrap_or_else(|| DEFAULT_INTENTS.iter().map(|name| (*name).to_owned()).collect());
+
+        let mut intents = GatewayIntents::empty();
+        for name in names {
+            match parse_intent(&name) {
+                Some(intent) => intents |= intent,
+                None => self.error("intents", format!("Unknown gateway intent {:?}.", name))
+            }
+        }
+
+        // Galleries are made from messages, which can't be ingested without these
+        for (intent, name) in [(GatewayIntents::GUILD_MESSAGES, "guild_messages"), (GatewayIntents::MESSAGE_CONTENT, "message_content")] {
+            if !intents.contains(intent) {
+                self.error("intents", format!("Must include {}.", name));
+            }
+        }
+
+        intents
+    }
+
+    fn validate_gallery_defaults(&mut self, sign_in_enabled: bool) -> GalleryDefaults {
+        let raw_default = std::mem::take(&mut self.defaults);
+        let default = self.validate_guild_defaults("defaults", raw_default, GuildDefaults::default(), sign_in_enabled);
+
+        let mut guilds = HashMap::new();
+        for (guild_id, raw_defaults) in std::mem::take(&mut self.guilds) {
+            let key = format!("guilds.{}", guild_id);
+            let defaults = self.validate_guild_defaults(&key, raw_defaults, default, sign_in_enabled);
+            match guild_id.parse::<u64>() {
+                Ok(guild_id) => {
+                    guilds.insert(guild_id, defaults);
+                },
+                Err(_) => self.error(&key, "Guilds are configured by their id.")
+            }
+        }
+
+        GalleryDefaults { default, guilds }
+    }
+
+    fn validate_guild_defaults(&mut self, key: &str, raw: RawGalleryDefaults, fallback: GuildDefaults, sign_in_enabled: bool) -> GuildDefaults {
+        self.report_unknown(key, raw.unknown);
+
+        let visibility_key = format!("{}.visibility", key);
+        let visibility = match raw.visibility {
+            Some(visibility) => match Visibility::try_from_value(&visibility) {
+                Ok(Visibility::Members) if !sign_in_enabled => {
+                    self.error(&visibility_key, "Member-only galleries need Discord sign in, configure the oauth section.");
+                    Visibility::Members
+                },
+                Ok(visibility) => visibility,
+                Err(_) => {
+                    self.error(&visibility_key, format!("Unknown visibility {:?}, expected public, unlisted, members or private.", visibility));
+                    fallback.visibility
+                }
+            },
+            None => fallback.visibility
+        };
+
+        GuildDefaults {
+            visibility,
+            required_role_id: raw.required_role_id.or(fallback.required_role_id)
+        }
+    }
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+fn parse_intent(name: &str) -> Option<GatewayIntents> {
+    Some(match name {
+        "guilds" => GatewayIntents::GUILDS,
+        "guild_members" => GatewayIntents::GUILD_MEMBERS,
+        "guild_messages" => GatewayIntents::GUILD_MESSAGES,
+        "guild_message_reactions" => GatewayIntents::GUILD_MESSAGE_REACTIONS,
+        "guild_presences" => GatewayIntents::GUILD_PRESENCES,
+        "direct_messages" => GatewayIntents::DIRECT_MESSAGES,
+        "direct_message_reactions" => GatewayIntents::DIRECT_MESSAGE_REACTIONS,
+        "message_content" => GatewayIntents::MESSAGE_CONTENT,
+        _ => return None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use sea_orm::prelude::Uuid;
+
+    use super::*;
+
+    const VALID: &str = r#"
+        discord_token = "token"
+        base_url = "https://galleria.example/"
+        listen_addr = "127.0.0.1:8080"
+
+        [database]
+        url = "postgres://localhost/galleria"
+    "#;
+
+    /// Tests that set environment variables hold this, so they don't see each other's.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn parse(toml: &str) -> RawConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn error_keys(toml: &str) -> Vec<String> {
+        match parse(toml).validate() {
+            Ok(_) => panic!("expected the config to be invalid"),
+            Err(ConfigErrors(errors)) => errors.into_iter().map(|error| error.key).collect()
+        }
+    }
+
+    /// Writes a config file and loads it, with `env` set while it's loaded.
+    fn load_with_env(toml: &str, env: &[(&str, &str)], overrides: ConfigOverrides) -> Config {
+        let _guard = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let file = env::temp_dir().join(format!("galleria-config-{}.toml", Uuid::new_v4()));
+        std::fs::write(&file, toml).unwrap();
+
+        for (name, value) in env {
+            env::set_var(name, value);
+        }
+        let config = load_config(Some(&file), overrides);
+        for (name, _) in env {
+            env::remove_var(name);
+        }
+
+        std::fs::remove_file(&file).unwrap();
+        config.validate().unwrap()
+    }
+
+    #[test]
+    fn valid_configs_are_filled_in_with_defaults() {
+        let config = parse(VALID).validate().unwrap();
+        assert_eq!(config.base_url, "https://galleria.example");
+        assert_eq!(config.listen_addr, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert_eq!(config.command_prefix, DEFAULT_COMMAND_PREFIX);
+        assert_eq!(config.database.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert!(config.features.feeds && config.features.downloads);
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let keys = error_keys(r#"
+            base_url = "galleria.example"
+            command_prefix = "two words"
+            colour = "blue"
+
+            [database]
+            url = "mysql://localhost/galleria"
+            min_connections = 20
+
+            [defaults]
+            visibility = "secret"
+        "#);
+        assert_eq!(keys, [
+            "colour",
+            "database.url",
+            "database.min_connections",
+            "discord_token",
+            "base_url",
+            "listen_addr",
+            "command_prefix",
+            "defaults.visibility"
+        ]);
+    }
+
+    #[test]
+    fn malformed_listen_addresses_are_errors() {
+        let toml = VALID.replace("127.0.0.1:8080", "localhost");
+        assert_eq!(error_keys(&toml), ["listen_addr"]);
+    }
+
+    #[test]
+    fn environment_variables_override_the_file() {
+        let config = load_with_env(VALID, &[("LISTEN_ADDR", "0.0.0.0:9000"), ("COMMAND_PREFIX", "!")], ConfigOverrides::default());
+        assert_eq!(config.listen_addr, SocketAddr::from(([0, 0, 0, 0], 9000)));
+        assert_eq!(config.command_prefix, "!");
+        assert_eq!(config.base_url, "https://galleria.example");
+    }
+
+    #[test]
+    fn command_line_flags_override_the_environment() {
+        let overrides = ConfigOverrides {
+            base_url: Some("https://cli.example".to_owned()),
+            ..ConfigOverrides::default()
+        };
+        let config = load_with_env(VALID, &[("BASE_URL", "https://env.example")], overrides);
+        assert_eq!(config.base_url, "https://cli.example");
+    }
+
+    #[test]
+    fn guilds_fall_back_to_the_global_defaults() {
+        let config = parse(&format!(r#"
+            {}
+
+            [oauth]
+            client_id = "id"
+            client_secret = "secret"
+
+            [defaults]
+            visibility = "unlisted"
+
+            [guilds.123]
+            visibility = "members"
+            required_role_id = 456
+
+            [guilds.789]
+            required_role_id = 12
+        "#, VALID)).validate().unwrap();
+
+        let defaults = config.gallery_defaults.for_guild(Some(123));
+        assert_eq!(defaults.visibility, Visibility::Members);
+        assert_eq!(defaults.required_role_id, Some(456));
+
+        let defaults = config.gallery_defaults.for_guild(Some(789));
+        assert_eq!(defaults.visibility, Visibility::Unlisted);
+        assert_eq!(defaults.required_role_id, Some(12));
+
+        for guild_id in [Some(1), None] {
+            let defaults = config.gallery_defaults.for_guild(guild_id);
+            assert_eq!(defaults.visibility, Visibility::Unlisted);
+            assert_eq!(defaults.required_role_id, None);
+        }
+    }
+}
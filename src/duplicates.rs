@@ -0,0 +1,329 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use image::{DynamicImage, imageops::FilterType};
+use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait, FromQueryResult, IntoActiveModel, QueryFilter, QueryOrder, QuerySelect, prelude::Uuid, sea_query::Expr};
+use sha2::{Digest, Sha256};
+use sql_entities::{gallery, gallery_post};
+use tracing::{debug, instrument, warn};
+
+/// How many bits the perceptual hashes of two images may differ by for them to count as the same image.
+/// Rescaling and recompressing stays well below this, different pieces are usually 20 or more apart.
+pub const DEFAULT_MAX_DISTANCE: u32 = 6;
+
+/// Larger images aren't downloaded for hashing.
+const MAX_IMAGE_BYTES: u64 = 32 * 1024 * 1024;
+
+/// How many of a guild's latest posts a new image is compared to by perceptual hash.
+/// Reposts of older art are still caught when they're exact copies.
+const MAX_PERCEPTUAL_CANDIDATES: u64 = 5000;
+
+/// The hashes of an image, stored with the posts it's in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageHashes {
+    /// SHA-256 of the file, in hex. Only matches exact re-uploads.
+    pub content: String,
+    /// Difference hash of the pixels. Stays close for resized and recompressed copies.
+    pub perceptual: i64
+}
+
+/// Hashes an image file. Fails for files that aren't images in one of the supported formats.
+pub fn hash_image(bytes: &[u8]) -> Result<ImageHashes> {
+    let content = format!("{:x}", Sha256::digest(bytes));
+    let perceptual = difference_hash(&image::load_from_memory(bytes)?);
+
+    Ok(ImageHashes { content, perceptual: perceptual as i64 })
+}
+
+/// Shrinks the image to 9×8 grey pixels and sets a bit for every pixel that's brighter than the one to its right.
+fn difference_hash(image: &DynamicImage) -> u64 {
+    let pixels = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if pixels.get_pixel(x, y)[0] > pixels.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// How many bits two perceptual hashes differ by.
+pub fn hash_distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// A post of an image that was posted before.
+#[derive(Debug)]
+pub struct Repost {
+    pub post: gallery_post::Model,
+    /// The earliest post of the image.
+    pub original: gallery_post::Model
+}
+
+#[derive(Debug, FromQueryResult)]
+struct HashedPost {
+    pk: Uuid,
+    perceptual_hash: i64,
+    duplicate_group: Option<Uuid>,
+    date_created: DateTime<Utc>
+}
+
+/// Finds posts of the same image across the galleries of a guild, and links them into duplicate groups.
+///
+/// A group's id is the pk of the earlier of the first two posts found to match, which is its earliest post
+/// unless a backfill turns up older ones. Posts keep their group when that post is deleted.
+#[derive(Clone)]
+pub struct DuplicateDetector {
+    db: Arc<DatabaseConnection>,
+    client: reqwest::Client,
+    max_distance: u32
+}
+
+impl DuplicateDetector {
+    pub fn new(db: Arc<DatabaseConnection>, max_distance: u32) -> Self {
+        DuplicateDetector { db, client: reqwest::Client::new(), max_distance }
+    }
+
+    /// Hashes the images of `posts` and groups them with earlier posts of the same image.
+    /// Returns the posts that turned out to be reposts. Posts that can't be hashed are logged and skipped.
+    #[instrument(skip_all, fields(posts = posts.len()))]
+    pub async fn check_posts(&self, posts: &[Uuid]) -> Result<Vec<Repost>> {
+        if posts.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let posts = gallery_post::Entity::find()
+            .filter(gallery_post::Column::Pk.is_in(posts.iter().copied()))
+            .all(self.db.as_ref())
+            .await?;
+
+        let mut reposts = Vec::new();
+        for post in posts {
+            let pk = post.pk;
+            match self.check_post(post).await {
+                Ok(Some(repost)) => reposts.push(repost),
+                Ok(None) => {},
+                Err(why) => warn!("Could not check post {} for duplicates: {:?}", pk, why)
+            }
+        }
+        Ok(reposts)
+    }
+
+    async fn check_post(&self, post: gallery_post::Model) -> Result<Option<Repost>> {
+        let media_url = match post.media_url.as_ref().or(post.thumbnail_url.as_ref()) {
+            Some(media_url) => media_url.clone(),
+            None => return Ok(None)
+        };
+
+        let bytes = self.download(&media_url).await?;
+        // Decoding is CPU bound, keep it off the async workers
+        let hashes = tokio::task::spawn_blocking(move || hash_image(&bytes)).await??;
+
+        let matching = self.find_matching_post(&post, &hashes).await?;
+        let group = matching.as_ref().map(|matching| matching.duplicate_group.unwrap_or_else(|| {
+            // The first duplicate of a post starts a group, named after whichever of the two came first
+            if (matching.date_created, matching.pk) <= (post.date_created, post.pk) { matching.pk } else { post.pk }
+        }));
+
+        if let (Some(matching), Some(group)) = (&matching, group) {
+            if matching.duplicate_group.is_none() {
+                gallery_post::Entity::update_many()
+                    .col_expr(gallery_post::Column::DuplicateGroup, Expr::value(group))
+                    .filter(gallery_post::Column::Pk.eq(matching.pk))
+                    .exec(self.db.as_ref())
+                    .await?;
+            }
+        }
+
+        let mut active_post = post.clone().into_active_model();
+        active_post.content_hash = ActiveValue::Set(Some(hashes.content));
+        active_post.perceptual_hash = ActiveValue::Set(Some(hashes.perceptual));
+        active_post.duplicate_group = ActiveValue::Set(group);
+        let post = active_post.update(self.db.as_ref()).await?;
+
+        // Backfills go from new to old, so the post matched might be a later repost of this one
+        let matching = match matching {
+            Some(matching) if (matching.date_created, matching.pk) < (post.date_created, post.pk) => matching,
+            _ => return Ok(None)
+        };
+        let original = match gallery_post::Entity::find_by_id(matching.duplicate_group.unwrap_or(matching.pk)).one(self.db.as_ref()).await? {
+            Some(original) => original,
+            // The group's first post was deleted, the post matched is the earliest one left that's known
+            None => match gallery_post::Entity::find_by_id(matching.pk).one(self.db.as_ref()).await? {
+                Some(original) => original,
+                None => return Ok(None)
+            }
+        };
+
+        debug!("Post {} is a repost of post {}.", post.pk, original.pk);
+        Ok(Some(Repost { post, original }))
+    }
+
+    async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        if response.content_length().is_some_and(|length| length > MAX_IMAGE_BYTES) {
+            anyhow::bail!("The image is larger than {} bytes.", MAX_IMAGE_BYTES);
+        }
+
+        let bytes = response.bytes().await?;
+        if bytes.len() as u64 > MAX_IMAGE_BYTES {
+            anyhow::bail!("The image is larger than {} bytes.", MAX_IMAGE_BYTES);
+        }
+        Ok(bytes.to_vec())
+    }
+
+    /// Finds the closest post of the same image in the galleries of the post's guild, oldest first on ties.
+    /// Without a guild only the post's own gallery is searched. Other posts of the same message don't count.
+    ///
+    /// Exact re-uploads are found through the indexed content hash. Only the latest
+    /// `MAX_PERCEPTUAL_CANDIDATES` posts are compared by perceptual hash, since that can't use an index.
+    async fn find_matching_post(&self, post: &gallery_post::Model, hashes: &ImageHashes) -> Result<Option<HashedPost>> {
+        let gallery_model = gallery::Entity::find_by_id(post.gallery).one(self.db.as_ref()).await?;
+        let galleries: Vec<Uuid> = match gallery_model.and_then(|gallery_model| gallery_model.discord_guild_id) {
+            Some(guild_id) => gallery::Entity::find()
+                .filter(gallery::Column::DiscordGuildId.eq(guild_id))
+                .all(self.db.as_ref())
+                .await?
+                .into_iter()
+                .map(|gallery_model| gallery_model.pk)
+                .collect(),
+            None => vec![post.gallery]
+        };
+
+        let candidates = || gallery_post::Entity::find()
+            .select_only()
+            .column(gallery_post::Column::Pk)
+            .column(gallery_post::Column::PerceptualHash)
+            .column(gallery_post::Column::DuplicateGroup)
+            .column(gallery_post::Column::DateCreated)
+            .filter(gallery_post::Column::Gallery.is_in(galleries.iter().copied()))
+            .filter(gallery_post::Column::Pk.ne(post.pk))
+            .filter(gallery_post::Column::DiscordMessageId.ne(post.discord_message_id))
+            .filter(gallery_post::Column::PerceptualHash.is_not_null());
+
+        let exact = candidates()
+            .filter(gallery_post::Column::ContentHash.eq(hashes.content.as_str()))
+            .order_by_asc(gallery_post::Column::DateCreated)
+            .order_by_asc(gallery_post::Column::Pk)
+            .into_model::<HashedPost>()
+            .one(self.db.as_ref())
+            .await?;
+        if exact.is_some() {
+            return Ok(exact);
+        }
+
+        let recent = candidates()
+            .order_by_desc(gallery_post::Column::DateCreated)
+            .order_by_desc(gallery_post::Column::Pk)
+            .limit(MAX_PERCEPTUAL_CANDIDATES)
+            .into_model::<HashedPost>()
+            .all(self.db.as_ref())
+            .await?;
+
+        Ok(closest_match(recent, hashes.perceptual, self.max_distance))
+    }
+}
+
+/// The candidate within `max_distance` of `perceptual_hash` that's closest to it, the earliest one on ties.
+fn closest_match(candidates: Vec<HashedPost>, perceptual_hash: i64, max_distance: u32) -> Option<HashedPost> {
+    candidates.into_iter()
+        .map(|candidate| (hash_distance(candidate.perceptual_hash, perceptual_hash), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, candidate)| (*distance, candidate.date_created, candidate.pk))
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use chrono::TimeZone;
+    use image::{ImageOutputFormat, RgbImage};
+
+    use super::*;
+
+    /// A picture with enough structure for the hashes to tell it apart from others.
+    fn picture(width: u32, height: u32, seed: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let (x, y) = (x * 256 / width, y * 256 / height);
+            let value = ((x * seed + y * (seed + 3)) ^ (x * y / 64)) % 256;
+            image::Rgb([value as u8, (255 - value) as u8, ((x + y) / 2) as u8])
+        }))
+    }
+
+    fn encode(image: &DynamicImage, format: ImageOutputFormat) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    fn hashed_post(pk: u128, perceptual_hash: i64, day: u32) -> HashedPost {
+        HashedPost {
+            pk: Uuid::from_u128(pk),
+            perceptual_hash,
+            duplicate_group: None,
+            date_created: Utc.with_ymd_and_hms(2022, 9, day, 12, 0, 0).unwrap()
+        }
+    }
+
+    #[test]
+    fn hash_distance_counts_differing_bits() {
+        assert_eq!(hash_distance(0, 0), 0);
+        assert_eq!(hash_distance(0b1011, 0b0010), 2);
+        assert_eq!(hash_distance(0, -1), 64);
+        assert_eq!(hash_distance(i64::MIN, 0), 1);
+    }
+
+    #[test]
+    fn resized_copies_stay_close() {
+        let original = picture(640, 480, 7);
+        let resized = original.resize_exact(320, 240, FilterType::Lanczos3);
+
+        let distance = hash_distance(difference_hash(&original) as i64, difference_hash(&resized) as i64);
+        assert!(distance <= DEFAULT_MAX_DISTANCE, "distance {} is above {}", distance, DEFAULT_MAX_DISTANCE);
+    }
+
+    #[test]
+    fn different_pictures_are_far_apart() {
+        let distance = hash_distance(difference_hash(&picture(640, 480, 7)) as i64, difference_hash(&picture(640, 480, 29)) as i64);
+        assert!(distance > DEFAULT_MAX_DISTANCE, "distance {} is not above {}", distance, DEFAULT_MAX_DISTANCE);
+    }
+
+    #[test]
+    fn recompressed_copies_share_the_perceptual_hash_only() {
+        let original = picture(640, 480, 7);
+        let png = hash_image(&encode(&original, ImageOutputFormat::Png)).unwrap();
+        let jpeg = hash_image(&encode(&original, ImageOutputFormat::Jpeg(80))).unwrap();
+
+        assert_ne!(png.content, jpeg.content);
+        assert!(hash_distance(png.perceptual, jpeg.perceptual) <= DEFAULT_MAX_DISTANCE);
+        assert_eq!(hash_image(&encode(&original, ImageOutputFormat::Png)).unwrap(), png);
+    }
+
+    #[test]
+    fn files_that_arent_images_fail_to_hash() {
+        assert!(hash_image(b"not an image").is_err());
+    }
+
+    #[test]
+    fn closest_match_prefers_distance_then_age() {
+        let candidates = vec![
+            hashed_post(1, 0b1111, 3),
+            hashed_post(2, 0b0011, 2),
+            hashed_post(3, 0b0001, 4),
+            hashed_post(4, 0b0001, 1)
+        ];
+        assert_eq!(closest_match(candidates, 0b0000, 6).map(|post| post.pk), Some(Uuid::from_u128(4)));
+    }
+
+    #[test]
+    fn closest_match_ignores_distant_posts() {
+        let candidates = vec![hashed_post(1, -1, 1)];
+        assert!(closest_match(candidates, 0, DEFAULT_MAX_DISTANCE).is_none());
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, Statement, prelude::Uuid};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, warn, instrument};
+
+/// How many events a slow subscriber can fall behind before it starts missing them.
+pub const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// The Postgres channel events are passed between galleria processes on.
+pub const NOTIFY_CHANNEL: &str = "galleria_events";
+
+/// Notification payloads are limited to 8000 bytes, so events about many posts are split up.
+const MAX_POSTS_PER_NOTIFICATION: usize = 100;
+
+/// A change to the posts of a gallery, published by the bot as it ingests messages.
+/// Only post pks are carried, subscribers load the posts themselves.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+// The variant names are the event types on the wire, which NOTIFY payloads and SSE clients rely on
+#[allow(clippy::enum_variant_names)]
+pub enum GalleryEvent {
+    PostsCreated { gallery: Uuid, posts: Vec<Uuid> },
+    PostsUpdated { gallery: Uuid, posts: Vec<Uuid> },
+    PostsDeleted { gallery: Uuid, posts: Vec<Uuid> },
+}
+
+impl GalleryEvent {
+    pub fn gallery(&self) -> Uuid {
+        match self {
+            GalleryEvent::PostsCreated { gallery, .. } => *gallery,
+            GalleryEvent::PostsUpdated { gallery, .. } => *gallery,
+            GalleryEvent::PostsDeleted { gallery, .. } => *gallery,
+        }
+    }
+
+    /// The same event, for only some of its posts.
+    fn with_posts(&self, posts: Vec<Uuid>) -> GalleryEvent {
+        let gallery = self.gallery();
+        match self {
+            GalleryEvent::PostsCreated { .. } => GalleryEvent::PostsCreated { gallery, posts },
+            GalleryEvent::PostsUpdated { .. } => GalleryEvent::PostsUpdated { gallery, posts },
+            GalleryEvent::PostsDeleted { .. } => GalleryEvent::PostsDeleted { gallery, posts },
+        }
+    }
+
+    pub fn posts(&self) -> &[Uuid] {
+        match self {
+            GalleryEvent::PostsCreated { posts, .. } => posts,
+            GalleryEvent::PostsUpdated { posts, .. } => posts,
+            GalleryEvent::PostsDeleted { posts, .. } => posts,
+        }
+    }
+}
+
+pub type EventSender = broadcast::Sender<GalleryEvent>;
+
+pub fn event_channel() -> EventSender {
+    let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+    sender
+}
+
+/// Publishes events to every galleria process through Postgres, so the bot and web server can run separately.
+/// Processes serving live updates receive them with [`relay_notifications`], including their own.
+/// Other databases can't notify, events only reach this process' subscribers there.
+#[derive(Clone)]
+pub struct EventNotifier {
+    db: Arc<DatabaseConnection>,
+    local: EventSender
+}
+
+impl EventNotifier {
+    pub fn new(db: Arc<DatabaseConnection>, local: EventSender) -> Self {
+        EventNotifier { db, local }
+    }
+
+    #[instrument(level = "debug", skip_all, fields(gallery_id = %event.gallery()))]
+    pub async fn notify(&self, event: &GalleryEvent) -> Result<()> {
+        if self.db.get_database_backend() != DbBackend::Postgres {
+            // Sending only fails when nobody is subscribed, which is fine
+            if self.local.send(event.clone()).is_err() {
+                debug!("No subscribers for gallery events.");
+            }
+            return Ok(());
+        }
+
+        for posts in event.posts().chunks(MAX_POSTS_PER_NOTIFICATION) {
+            let payload = serde_json::to_string(&event.with_posts(posts.to_vec()))?;
+            self.db.execute(Statement::from_sql_and_values(
+                DbBackend::Postgres,
+                "SELECT pg_notify($1, $2)",
+                vec![NOTIFY_CHANNEL.into(), payload.into()]
+            )).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Passes events published by any galleria process on to this process' subscribers.
+/// Only returns when listening can't be started, lost connections are reestablished.
+pub async fn relay_notifications(db_url: &str, events: EventSender) -> Result<()> {
+    let mut listener = PgListener::connect(db_url).await?;
+    listener.listen(NOTIFY_CHANNEL).await?;
+    info!("Listening for gallery events.");
+
+    loop {
+        let notification = match listener.try_recv().await {
+            Ok(Some(notification)) => notification,
+            Ok(None) => {
+                // The listener reconnects on the next receive, events sent in between are lost
+                warn!("Lost the connection for gallery events, live updates may have missed some.");
+                continue;
+            },
+            Err(why) => {
+                error!("Error receiving gallery events: {:?}", why);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        match serde_json::from_str::<GalleryEvent>(notification.payload()) {
+            // Sending only fails when nobody is subscribed, which is fine
+            Ok(event) => if events.send(event).is_err() {
+                debug!("No subscribers for gallery events.");
+            },
+            Err(why) => warn!("Ignoring malformed gallery event {:?}: {:?}", notification.payload(), why)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    #[tokio::test]
+    async fn events_about_many_posts_are_split_up() {
+        let (db, _, _guard) = match test_db::postgres().await {
+            Some(postgres) => postgres,
+            None => return
+        };
+        let mut listener = test_db::listen().await;
+
+        let gallery = Uuid::new_v4();
+        let posts = (0..MAX_POSTS_PER_NOTIFICATION + 1).map(|_| Uuid::new_v4()).collect::<Vec<Uuid>>();
+        EventNotifier::new(Arc::new(db), event_channel())
+            .notify(&GalleryEvent::PostsDeleted { gallery, posts: posts.clone() })
+            .await
+            .unwrap();
+
+        let first = test_db::next_event(&mut listener).await;
+        let second = test_db::next_event(&mut listener).await;
+        assert_eq!(first, GalleryEvent::PostsDeleted { gallery, posts: posts[..MAX_POSTS_PER_NOTIFICATION].to_vec() });
+        assert_eq!(second, GalleryEvent::PostsDeleted { gallery, posts: posts[MAX_POSTS_PER_NOTIFICATION..].to_vec() });
+    }
+}
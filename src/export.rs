@@ -0,0 +1,600 @@
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use migration::attachment_id_from_url;
+use sea_orm::{ActiveEnum, ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, TransactionTrait, prelude::Uuid};
+use serde::{Deserialize, Serialize};
+use sql_entities::{artist_optout, gallery, gallery_post, sea_orm_active_enums::Visibility};
+use tracing::{info, warn};
+
+use crate::archive::archive_file_name;
+use crate::tags;
+
+/// Identifies galleria gallery archives.
+pub const ARCHIVE_FORMAT: &str = "galleria-gallery";
+
+/// The current archive version. Bumped whenever a field is removed or changes meaning,
+/// new optional fields don't need a new version.
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// A gallery and its posts, in a form that doesn't depend on the database schema.
+///
+/// Posts keep referring to Discord's CDN and the original sources. Their media can be mirrored next to the archive
+/// when exporting, importing always uses the urls.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GalleryArchive {
+    pub format: String,
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub gallery: ArchivedGallery,
+    pub posts: Vec<ArchivedPost>,
+    /// Artists who opted out in the gallery's guild, so their art stays hidden on the importing instance.
+    #[serde(default)]
+    pub opted_out_authors: Vec<i64>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedGallery {
+    pub pk: Uuid,
+    pub name: String,
+    pub discord_channel_id: i64,
+    pub discord_guild_id: Option<i64>,
+    /// One of `public`, `unlisted`, `members` or `private`.
+    pub visibility: String,
+    pub required_role_id: Option<i64>,
+    pub date_created: DateTime<Utc>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedPost {
+    pub pk: Uuid,
+    pub discord_message_id: i64,
+    pub discord_author_id: Option<i64>,
+    pub author_name: Option<String>,
+    pub content: Option<String>,
+    /// Which attachment or embed of the message the post was made from. Worked out on import when missing.
+    #[serde(default)]
+    pub source_key: String,
+    pub source_url: Option<String>,
+    pub media_url: Option<String>,
+    pub media_width: Option<i32>,
+    pub media_height: Option<i32>,
+    pub thumbnail_url: Option<String>,
+    pub thumbnail_width: Option<i32>,
+    pub thumbnail_height: Option<i32>,
+    pub date_created: DateTime<Utc>,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The image's hashes, so importing doesn't download every image again to find reposts.
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    #[serde(default)]
+    pub perceptual_hash: Option<i64>,
+    /// Usually the pk of the group's earliest post, which might be in another gallery.
+    #[serde(default)]
+    pub duplicate_group: Option<Uuid>,
+    /// Where the media was mirrored to, relative to the media directory of the export.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_file: Option<String>
+}
+
+/// What to do when the archive's channel already has a gallery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Refuse to import.
+    Fail,
+    /// Leave the existing gallery alone and import nothing.
+    Skip,
+    /// Delete the existing gallery and its posts, then import.
+    Replace
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(ConflictPolicy::Fail),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "replace" => Ok(ConflictPolicy::Replace),
+            _ => anyhow::bail!("Unknown conflict policy {:?}, expected fail, skip or replace.", s)
+        }
+    }
+}
+
+/// The result of an import.
+#[derive(Debug)]
+pub enum ImportOutcome {
+    Imported { gallery: Uuid, posts: usize, remapped_ids: usize },
+    Skipped { existing_gallery: Uuid }
+}
+
+/// Finds a gallery by its uuid or by the id of its Discord channel.
+pub async fn find_gallery_by_reference(db: &DatabaseConnection, reference: &str) -> Result<gallery::Model> {
+    let gallery_model = match (Uuid::parse_str(reference), reference.parse::<i64>()) {
+        (Ok(gallery_id), _) => gallery::Entity::find_by_id(gallery_id).one(db).await?,
+        (_, Ok(channel_id)) => gallery::Entity::find()
+            .filter(gallery::Column::DiscordChannelId.eq(channel_id))
+            .one(db)
+            .await?,
+        _ => anyhow::bail!("{:?} is neither a gallery uuid nor a channel id.", reference)
+    };
+
+    gallery_model.ok_or_else(|| anyhow::anyhow!("There is no gallery {}.", reference))
+}
+
+/// Exports a gallery, downloading its posts' media into `media_dir` when given.
+/// Media that can't be downloaded is logged and left out, the archive still has its urls.
+pub async fn export_gallery(db: &DatabaseConnection, gallery_model: gallery::Model, media_dir: Option<&Path>) -> Result<GalleryArchive> {
+    let posts = gallery_post::Entity::find()
+        .filter(gallery_post::Column::Gallery.eq(gallery_model.pk))
+        .order_by_asc(gallery_post::Column::DateCreated)
+        .order_by_asc(gallery_post::Column::Pk)
+        .all(db)
+        .await?;
+
+    let post_pks = posts.iter().map(|post| post.pk).collect::<Vec<Uuid>>();
+    let mut tags_by_post = tags::find_tags_by_post(db, &post_pks).await?;
+
+    let opted_out_authors = match gallery_model.discord_guild_id {
+        Some(guild_id) => artist_optout::Entity::find()
+            .filter(artist_optout::Column::DiscordGuildId.eq(guild_id))
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|optout| optout.discord_user_id)
+            .collect(),
+        None => Vec::new()
+    };
+
+    if let Some(media_dir) = media_dir {
+        for dir in ["media", "thumbnails"] {
+            tokio::fs::create_dir_all(media_dir.join(dir)).await?;
+        }
+    }
+    let client = reqwest::Client::new();
+
+    // Posts are numbered oldest first, like in downloaded archives
+    let mut archived_posts = Vec::with_capacity(posts.len());
+    for (index, post) in posts.into_iter().enumerate() {
+        let (media_file, thumbnail_file) = match media_dir {
+            Some(media_dir) => (
+                mirror_file(&client, media_dir, "media", index + 1, &post, post.media_url.as_deref()).await,
+                mirror_file(&client, media_dir, "thumbnails", index + 1, &post, post.thumbnail_url.as_deref()).await
+            ),
+            None => (None, None)
+        };
+
+        archived_posts.push(ArchivedPost {
+            tags: tags_by_post.remove(&post.pk).unwrap_or_default(),
+            pk: post.pk,
+            discord_message_id: post.discord_message_id,
+            discord_author_id: post.discord_author_id,
+            author_name: post.author_name,
+            content: post.content,
+            source_key: post.source_key,
+            source_url: post.source_url,
+            media_url: post.media_url,
+            media_width: post.media_width,
+            media_height: post.media_height,
+            thumbnail_url: post.thumbnail_url,
+            thumbnail_width: post.thumbnail_width,
+            thumbnail_height: post.thumbnail_height,
+            date_created: post.date_created,
+            hidden: post.hidden,
+            content_hash: post.content_hash,
+            perceptual_hash: post.perceptual_hash,
+            duplicate_group: post.duplicate_group,
+            media_file,
+            thumbnail_file
+        });
+    }
+    let posts = archived_posts;
+
+    info!("Exported gallery {} with {} posts.", gallery_model.pk, posts.len());
+
+    Ok(GalleryArchive {
+        format: ARCHIVE_FORMAT.to_owned(),
+        version: ARCHIVE_VERSION,
+        exported_at: Utc::now(),
+        gallery: ArchivedGallery {
+            pk: gallery_model.pk,
+            name: gallery_model.name,
+            discord_channel_id: gallery_model.discord_channel_id,
+            discord_guild_id: gallery_model.discord_guild_id,
+            visibility: gallery_model.visibility.to_value(),
+            required_role_id: gallery_model.required_role_id,
+            date_created: gallery_model.date_created
+        },
+        posts,
+        opted_out_authors
+    })
+}
+
+/// Downloads `url` into `subdir` of the media directory, returning the path it was written to relative to the directory.
+async fn mirror_file(client: &reqwest::Client, media_dir: &Path, subdir: &str, number: usize, post: &gallery_post::Model, url: Option<&str>) -> Option<String> {
+    let url = url?;
+    let response = client.get(url).send().await.and_then(|response| response.error_for_status());
+    let bytes = match response {
+        Ok(response) => response.bytes().await,
+        Err(why) => Err(why)
+    };
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(why) => {
+            warn!("Could not download {} for the export: {:?}", url, why);
+            return None;
+        }
+    };
+
+    let path = format!("{}/{}", subdir, archive_file_name(number, post, url));
+    match tokio::fs::write(media_dir.join(&path), &bytes).await {
+        Ok(()) => Some(path),
+        Err(why) => {
+            warn!("Could not write {}: {:?}", path, why);
+            None
+        }
+    }
+}
+
+/// Reads an archive, checking it's one this version of galleria understands.
+pub fn parse_archive(json: &str) -> Result<GalleryArchive> {
+    let archive: GalleryArchive = serde_json::from_str(json)?;
+
+    if archive.format != ARCHIVE_FORMAT {
+        anyhow::bail!("Not a gallery archive, the format is {:?}.", archive.format);
+    }
+    if archive.version > ARCHIVE_VERSION {
+        anyhow::bail!("The archive is version {}, this version of galleria reads up to version {}.", archive.version, ARCHIVE_VERSION);
+    }
+
+    Ok(archive)
+}
+
+/// Imports an archive in a single transaction.
+///
+/// Galleries and posts keep their ids, so links to them keep working after moving instances.
+/// Ids that are already taken by other galleries or posts are replaced with new ones.
+pub async fn import_gallery(db: &DatabaseConnection, archive: GalleryArchive, on_conflict: ConflictPolicy) -> Result<ImportOutcome> {
+    let existing = gallery::Entity::find()
+        .filter(gallery::Column::DiscordChannelId.eq(archive.gallery.discord_channel_id))
+        .one(db)
+        .await?;
+
+    if let Some(existing) = &existing {
+        match on_conflict {
+            ConflictPolicy::Fail => anyhow::bail!(
+                "Channel {} already has gallery {}. Import with the skip or replace conflict policy.",
+                archive.gallery.discord_channel_id, existing.pk
+            ),
+            ConflictPolicy::Skip => return Ok(ImportOutcome::Skipped { existing_gallery: existing.pk }),
+            ConflictPolicy::Replace => info!("Replacing gallery {} with the imported one.", existing.pk)
+        }
+    }
+
+    let visibility = Visibility::try_from_value(&archive.gallery.visibility)
+        .map_err(|_| anyhow::anyhow!("Unknown gallery visibility {:?}.", archive.gallery.visibility))?;
+
+    let txn = db.begin().await?;
+
+    // Posts and their tags go along with the gallery
+    if let Some(existing) = existing {
+        gallery::Entity::delete_by_id(existing.pk).exec(&txn).await?;
+    }
+
+    let mut remapped_ids = 0;
+    let gallery_id = match gallery::Entity::find_by_id(archive.gallery.pk).one(&txn).await? {
+        Some(_) => {
+            remapped_ids += 1;
+            Uuid::new_v4()
+        },
+        None => archive.gallery.pk
+    };
+
+    gallery::ActiveModel {
+        pk: ActiveValue::Set(gallery_id),
+        name: ActiveValue::Set(archive.gallery.name),
+        discord_channel_id: ActiveValue::Set(archive.gallery.discord_channel_id),
+        discord_guild_id: ActiveValue::Set(archive.gallery.discord_guild_id),
+        visibility: ActiveValue::Set(visibility),
+        required_role_id: ActiveValue::Set(archive.gallery.required_role_id),
+        date_created: ActiveValue::Set(archive.gallery.date_created),
+        ..Default::default()
+    }.insert(&txn).await?;
+
+    let posts = with_source_keys(archive.posts);
+    let taken_post_ids = find_taken_post_ids(&txn, &posts).await?;
+    let post_count = posts.len();
+
+    // Groups named after a post whose id had to be changed follow it
+    let mut post_ids = HashMap::new();
+    for post in &posts {
+        let post_id = if taken_post_ids.contains(&post.pk) {
+            remapped_ids += 1;
+            Uuid::new_v4()
+        } else {
+            post.pk
+        };
+        post_ids.insert(post.pk, post_id);
+    }
+
+    for post in posts {
+        let post_id = post_ids[&post.pk];
+        let duplicate_group = post.duplicate_group.map(|group| post_ids.get(&group).copied().unwrap_or(group));
+
+        gallery_post::ActiveModel {
+            pk: ActiveValue::Set(post_id),
+            gallery: ActiveValue::Set(gallery_id),
+            discord_message_id: ActiveValue::Set(post.discord_message_id),
+            discord_author_id: ActiveValue::Set(post.discord_author_id),
+            author_name: ActiveValue::Set(post.author_name),
+            content: ActiveValue::Set(post.content),
+            source_key: ActiveValue::Set(post.source_key),
+            source_url: ActiveValue::Set(post.source_url),
+            media_url: ActiveValue::Set(post.media_url),
+            media_width: ActiveValue::Set(post.media_width),
+            media_height: ActiveValue::Set(post.media_height),
+            thumbnail_url: ActiveValue::Set(post.thumbnail_url),
+            thumbnail_width: ActiveValue::Set(post.thumbnail_width),
+            thumbnail_height: ActiveValue::Set(post.thumbnail_height),
+            date_created: ActiveValue::Set(post.date_created),
+            hidden: ActiveValue::Set(post.hidden),
+            content_hash: ActiveValue::Set(post.content_hash),
+            perceptual_hash: ActiveValue::Set(post.perceptual_hash),
+            duplicate_group: ActiveValue::Set(duplicate_group)
+        }.insert(&txn).await?;
+
+        tags::link_tags(&txn, &[post_id], &post.tags).await?;
+    }
+
+    if let Some(guild_id) = archive.gallery.discord_guild_id {
+        for user_id in archive.opted_out_authors {
+            let optout = artist_optout::Entity::find_by_id((guild_id, user_id)).one(&txn).await?;
+            if optout.is_none() {
+                artist_optout::ActiveModel {
+                    discord_guild_id: ActiveValue::Set(guild_id),
+                    discord_user_id: ActiveValue::Set(user_id),
+                    ..Default::default()
+                }.insert(&txn).await?;
+            }
+        }
+    }
+
+    txn.commit().await?;
+    info!("Imported gallery {} with {} posts, {} ids remapped.", gallery_id, post_count, remapped_ids);
+
+    Ok(ImportOutcome::Imported { gallery: gallery_id, posts: post_count, remapped_ids })
+}
+
+/// Works out the source keys of posts from archives exported before posts had them,
+/// the same way the migration that added them did, and drops posts that turn out to be duplicates.
+fn with_source_keys(posts: Vec<ArchivedPost>) -> Vec<ArchivedPost> {
+    let mut embed_counts = HashMap::new();
+    let mut seen = HashSet::new();
+
+    posts.into_iter()
+        .filter_map(|mut post| {
+            if post.source_key.is_empty() {
+                let attachment_id = match &post.source_url {
+                    None => post.media_url.as_deref().and_then(attachment_id_from_url),
+                    Some(_) => None
+                };
+                post.source_key = match attachment_id {
+                    Some(attachment_id) => format!("attachment:{}", attachment_id),
+                    None => {
+                        let index = embed_counts.entry(post.discord_message_id).or_insert(0);
+                        *index += 1;
+                        format!("embed:{}", *index - 1)
+                    }
+                };
+            }
+
+            if seen.insert((post.discord_message_id, post.source_key.clone())) {
+                Some(post)
+            } else {
+                info!("Skipping post {}, it duplicates another post of message {}.", post.pk, post.discord_message_id);
+                None
+            }
+        })
+        .collect()
+}
+
+async fn find_taken_post_ids(db: &impl ConnectionTrait, posts: &[ArchivedPost]) -> Result<HashSet<Uuid>> {
+    let mut taken = HashSet::new();
+
+    // Keep the parameter count of each query well below Postgres' limit
+    for chunk in posts.chunks(1000) {
+        let existing = gallery_post::Entity::find()
+            .filter(gallery_post::Column::Pk.is_in(chunk.iter().map(|post| post.pk)))
+            .all(db)
+            .await?;
+        taken.extend(existing.into_iter().map(|post| post.pk));
+    }
+
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{IntoActiveModel, PaginatorTrait};
+    use warp::Filter;
+
+    use super::*;
+    use crate::test_db::{self, day, insert_gallery, insert_post};
+
+    /// A gallery with two posts, the second one a repost of the first, a tag and an opted out artist.
+    async fn exported_gallery(db: &DatabaseConnection) -> (gallery::Model, GalleryArchive) {
+        let gallery_model = insert_gallery(db, 1, Some(5)).await;
+        let first = insert_post(db, &gallery_model, 10, 100, "first #fox", day(1)).await;
+        let repost = insert_post(db, &gallery_model, 11, 101, "again", day(2)).await;
+        tags::link_tags(db, &[first.pk], &["fox".to_owned()]).await.unwrap();
+
+        let mut repost = repost.into_active_model();
+        repost.duplicate_group = ActiveValue::Set(Some(first.pk));
+        repost.update(db).await.unwrap();
+
+        artist_optout::ActiveModel {
+            discord_guild_id: ActiveValue::Set(5),
+            discord_user_id: ActiveValue::Set(102),
+            ..Default::default()
+        }.insert(db).await.unwrap();
+
+        let archive = export_gallery(db, gallery_model.clone(), None).await.unwrap();
+        (gallery_model, archive)
+    }
+
+    /// Round trips an archive through JSON, as the export and import commands do.
+    fn reparse(archive: &GalleryArchive) -> GalleryArchive {
+        parse_archive(&serde_json::to_string(archive).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn imports_keep_ids_tags_and_optouts() {
+        let (db, _) = test_db::sqlite().await;
+        let (gallery_model, archive) = exported_gallery(&db).await;
+        let exported_pks = archive.posts.iter().map(|post| post.pk).collect::<Vec<Uuid>>();
+        gallery::Entity::delete_by_id(gallery_model.pk).exec(&db).await.unwrap();
+        artist_optout::Entity::delete_many().exec(&db).await.unwrap();
+
+        let outcome = import_gallery(&db, reparse(&archive), ConflictPolicy::Fail).await.unwrap();
+        assert!(matches!(outcome, ImportOutcome::Imported { gallery, posts: 2, remapped_ids: 0 } if gallery == gallery_model.pk));
+
+        let posts = gallery_post::Entity::find()
+            .order_by_asc(gallery_post::Column::DateCreated)
+            .all(&db)
+            .await
+            .unwrap();
+        assert_eq!(posts.iter().map(|post| post.pk).collect::<Vec<Uuid>>(), exported_pks);
+        assert_eq!(posts[1].duplicate_group, Some(posts[0].pk));
+        assert_eq!(tags::find_post_tags(&db, &[posts[0].pk]).await.unwrap(), vec!["fox".to_owned()]);
+        assert!(artist_optout::Entity::find_by_id((5, 102)).one(&db).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn conflicting_channels_follow_the_policy() {
+        let (db, _) = test_db::sqlite().await;
+        let (gallery_model, archive) = exported_gallery(&db).await;
+
+        let error = import_gallery(&db, reparse(&archive), ConflictPolicy::Fail).await.unwrap_err();
+        assert!(error.to_string().contains("already has gallery"));
+
+        let outcome = import_gallery(&db, reparse(&archive), ConflictPolicy::Skip).await.unwrap();
+        assert!(matches!(outcome, ImportOutcome::Skipped { existing_gallery } if existing_gallery == gallery_model.pk));
+
+        // The existing gallery is gone before the import, so nothing needs a new id
+        let outcome = import_gallery(&db, reparse(&archive), ConflictPolicy::Replace).await.unwrap();
+        assert!(matches!(outcome, ImportOutcome::Imported { posts: 2, remapped_ids: 0, .. }));
+        assert_eq!(gallery::Entity::find().count(&db).await.unwrap(), 1);
+        assert_eq!(gallery_post::Entity::find().count(&db).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn taken_ids_are_remapped_with_their_groups() {
+        let (db, _) = test_db::sqlite().await;
+        let (gallery_model, mut archive) = exported_gallery(&db).await;
+
+        // Importing a copy under another channel finds every id taken
+        archive.gallery.discord_channel_id = 2;
+        let outcome = import_gallery(&db, reparse(&archive), ConflictPolicy::Fail).await.unwrap();
+        let imported_gallery = match outcome {
+            ImportOutcome::Imported { gallery, posts: 2, remapped_ids: 3 } => gallery,
+            outcome => panic!("unexpected outcome {:?}", outcome)
+        };
+        assert_ne!(imported_gallery, gallery_model.pk);
+
+        let posts = gallery_post::Entity::find()
+            .filter(gallery_post::Column::Gallery.eq(imported_gallery))
+            .order_by_asc(gallery_post::Column::DateCreated)
+            .all(&db)
+            .await
+            .unwrap();
+        assert_eq!(posts.len(), 2);
+        assert!(posts.iter().all(|post| archive.posts.iter().all(|archived| archived.pk != post.pk)));
+        // The repost points at the copy of its original, not at the original gallery
+        assert_eq!(posts[1].duplicate_group, Some(posts[0].pk));
+    }
+
+    #[tokio::test]
+    async fn archives_without_source_keys_get_them_derived() {
+        let (db, _) = test_db::sqlite().await;
+        let (gallery_model, mut archive) = exported_gallery(&db).await;
+        gallery::Entity::delete_by_id(gallery_model.pk).exec(&db).await.unwrap();
+
+        // As exported before source keys existed, with a duplicate left behind by a replayed message
+        let mut duplicate: ArchivedPost = serde_json::from_value(serde_json::to_value(&archive.posts[0]).unwrap()).unwrap();
+        duplicate.pk = Uuid::new_v4();
+        archive.posts.push(duplicate);
+        for post in &mut archive.posts {
+            post.source_key = String::new();
+        }
+
+        let outcome = import_gallery(&db, reparse(&archive), ConflictPolicy::Fail).await.unwrap();
+        assert!(matches!(outcome, ImportOutcome::Imported { posts: 2, .. }));
+
+        let posts = gallery_post::Entity::find()
+            .order_by_asc(gallery_post::Column::DateCreated)
+            .all(&db)
+            .await
+            .unwrap();
+        let source_keys = posts.iter().map(|post| post.source_key.as_str()).collect::<Vec<&str>>();
+        assert_eq!(source_keys, ["attachment:100", "attachment:110"]);
+    }
+
+    #[test]
+    fn only_known_formats_and_versions_parse() {
+        let archive = |format: &str, version: u32| serde_json::json!({
+            "format": format,
+            "version": version,
+            "exported_at": day(1),
+            "gallery": {
+                "pk": Uuid::nil(),
+                "name": "art",
+                "discord_channel_id": 1,
+                "discord_guild_id": null,
+                "visibility": "public",
+                "required_role_id": null,
+                "date_created": day(1)
+            },
+            "posts": []
+        }).to_string();
+
+        assert!(parse_archive(&archive(ARCHIVE_FORMAT, ARCHIVE_VERSION)).is_ok());
+        assert!(parse_archive(&archive("something-else", ARCHIVE_VERSION)).unwrap_err().to_string().contains("Not a gallery archive"));
+        assert!(parse_archive(&archive(ARCHIVE_FORMAT, ARCHIVE_VERSION + 1)).unwrap_err().to_string().contains("reads up to version"));
+        assert!(parse_archive("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn media_is_mirrored_when_asked() {
+        let (db, _) = test_db::sqlite().await;
+        let files = warp::path("art.png").map(|| "not really a png");
+        let (addr, server) = warp::serve(files).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let gallery_model = insert_gallery(&db, 1, None).await;
+        let post = insert_post(&db, &gallery_model, 10, 100, "", day(1)).await;
+        let mut post = post.into_active_model();
+        post.media_url = ActiveValue::Set(Some(format!("http://{}/art.png", addr)));
+        post.thumbnail_url = ActiveValue::Set(Some(format!("http://{}/missing.png", addr)));
+        let post = post.update(&db).await.unwrap();
+
+        let media_dir = std::env::temp_dir().join(format!("galleria-export-{}", Uuid::new_v4()));
+        let archive = export_gallery(&db, gallery_model, Some(&media_dir)).await.unwrap();
+
+        let media_file = archive.posts[0].media_file.clone().unwrap();
+        assert_eq!(media_file, format!("media/0001_artist-100_{}.png", &post.pk.to_string()[..8]));
+        assert_eq!(std::fs::read_to_string(media_dir.join(&media_file)).unwrap(), "not really a png");
+        // The thumbnail 404s, the archive keeps its url
+        assert_eq!(archive.posts[0].thumbnail_file, None);
+        assert!(archive.posts[0].thumbnail_url.is_some());
+
+        std::fs::remove_dir_all(media_dir).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, hash_map::DefaultHasher};
+use std::hash::{Hash, Hasher};
+
+use atom_syndication as atom;
+use chrono::{DateTime, FixedOffset, Utc};
+use rss::extension::dublincore::DublinCoreExtensionBuilder;
+use sql_entities::{gallery, gallery_post};
+
+/// The number of posts included in a feed.
+pub const FEED_LENGTH: u64 = 50;
+
+/// A gallery and its latest posts, newest first, ready to be rendered into a feed.
+/// Posts link to `<gallery_url>/post/<pk>` and the feeds themselves live at `<gallery_url>/feed.atom` and `feed.rss`.
+pub struct GalleryFeed<'a> {
+    pub gallery_url: String,
+    pub gallery: &'a gallery::Model,
+    pub posts: &'a [gallery_post::Model]
+}
+
+impl<'a> GalleryFeed<'a> {
+    pub fn gallery_url(&self) -> String {
+        self.gallery_url.clone()
+    }
+
+    /// The time the feed last changed. Galleries without posts use their creation date.
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.posts.iter()
+            .map(|p| p.date_created)
+            .max()
+            .unwrap_or(self.gallery.date_created)
+    }
+
+    /// A weak ETag that changes whenever a post is added, removed or edited.
+    /// Only what ends up in the feed is hashed, edits that change nothing in it keep the ETag.
+    pub fn etag(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.gallery.pk.hash(&mut hasher);
+        self.gallery.name.hash(&mut hasher);
+        for post in self.posts {
+            post.pk.hash(&mut hasher);
+            post.date_created.hash(&mut hasher);
+            post.author_name.hash(&mut hasher);
+            post.content.hash(&mut hasher);
+            post.media_url.hash(&mut hasher);
+        }
+
+        format!("W/\"{:x}\"", hasher.finish())
+    }
+
+    pub fn to_atom(&self) -> String {
+        let gallery_url = self.gallery_url();
+
+        let entries = self.posts.iter()
+            .map(|post| {
+                let published: DateTime<FixedOffset> = post.date_created.into();
+                let mut links = vec![atom::LinkBuilder::default()
+                    .href(post_link(&gallery_url, post))
+                    .rel("alternate")
+                    .build()];
+                if let Some(media_url) = &post.media_url {
+                    links.push(atom::LinkBuilder::default()
+                        .href(media_url.clone())
+                        .rel("enclosure")
+                        .mime_type(Some(guess_image_mime_type(media_url).to_owned()))
+                        .build());
+                }
+
+                atom::EntryBuilder::default()
+                    .id(format!("urn:uuid:{}", post.pk))
+                    .title(post_title(post))
+                    .updated(published)
+                    .published(Some(published))
+                    .authors(vec![atom::PersonBuilder::default()
+                        .name(post.author_name.clone().unwrap_or_else(|| "Unknown artist".to_owned()))
+                        .build()])
+                    .links(links)
+                    .summary(post.content.clone().map(atom::Text::plain))
+                    .build()
+            })
+            .collect::<Vec<atom::Entry>>();
+
+        atom::FeedBuilder::default()
+            .id(format!("urn:uuid:{}", self.gallery.pk))
+            .title(self.gallery.name.clone())
+            .updated(DateTime::<FixedOffset>::from(self.last_modified()))
+            .links(vec![
+                atom::LinkBuilder::default()
+                    .href(gallery_url.clone())
+                    .rel("alternate")
+                    .build(),
+                atom::LinkBuilder::default()
+                    .href(format!("{}/feed.atom", gallery_url))
+                    .rel("self")
+                    .mime_type(Some("application/atom+xml".to_owned()))
+                    .build()
+            ])
+            .generator(Some(atom::Generator {
+                value: "Galleria".to_owned(),
+                ..Default::default()
+            }))
+            .entries(entries)
+            .build()
+            .to_string()
+    }
+
+    pub fn to_rss(&self) -> String {
+        let gallery_url = self.gallery_url();
+
+        let items = self.posts.iter()
+            .map(|post| {
+                let enclosure = post.media_url.as_ref().map(|media_url| rss::EnclosureBuilder::default()
+                    .url(media_url.clone())
+                    // The size of the media isn't known, 0 is the accepted placeholder
+                    .length("0".to_owned())
+                    .mime_type(guess_image_mime_type(media_url).to_owned())
+                    .build());
+
+                let creator = DublinCoreExtensionBuilder::default()
+                    .creators(post.author_name.iter().cloned().collect::<Vec<String>>())
+                    .build();
+
+                rss::ItemBuilder::default()
+                    .title(Some(post_title(post)))
+                    .link(Some(post_link(&gallery_url, post)))
+                    .guid(Some(rss::GuidBuilder::default()
+                        .value(format!("urn:uuid:{}", post.pk))
+                        .permalink(false)
+                        .build()))
+                    .pub_date(Some(post.date_created.to_rfc2822()))
+                    .description(post.content.clone())
+                    .enclosure(enclosure)
+                    .dublin_core_ext(Some(creator))
+                    .build()
+            })
+            .collect::<Vec<rss::Item>>();
+
+        let namespaces = BTreeMap::from([
+            ("dc".to_owned(), rss::extension::dublincore::NAMESPACE.to_owned())
+        ]);
+
+        rss::ChannelBuilder::default()
+            .title(self.gallery.name.clone())
+            .link(gallery_url.clone())
+            .description(format!("The latest art posted to {}.", self.gallery.name))
+            .last_build_date(Some(self.last_modified().to_rfc2822()))
+            .generator(Some("Galleria".to_owned()))
+            .namespaces(namespaces)
+            .items(items)
+            .build()
+            .to_string()
+    }
+}
+
+fn post_title(post: &gallery_post::Model) -> String {
+    match &post.author_name {
+        Some(author_name) => format!("Art by {}", author_name),
+        None => "New art".to_owned()
+    }
+}
+
+fn post_link(gallery_url: &str, post: &gallery_post::Model) -> String {
+    format!("{}/post/{}", gallery_url, post.pk)
+}
+
+/// Guesses the mime type of an image from the extension of its url.
+pub fn guess_image_mime_type(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or_default().to_lowercase();
+    let extension = path.rsplit('.').next().unwrap_or_default();
+
+    match extension {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        _ => "application/octet-stream"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use sea_orm::prelude::Uuid;
+    use sql_entities::sea_orm_active_enums::Visibility;
+
+    use super::*;
+
+    fn gallery() -> gallery::Model {
+        gallery::Model {
+            pk: Uuid::from_u128(1),
+            name: "art".to_owned(),
+            discord_channel_id: 10,
+            discord_guild_id: Some(20),
+            date_created: Utc.with_ymd_and_hms(2022, 9, 1, 12, 0, 0).unwrap(),
+            visibility: Visibility::Public,
+            required_role_id: None,
+            date_last_ingested: None,
+            ingest_error_count: 0,
+            last_ingest_error: None,
+            backfill_before: None
+        }
+    }
+
+    fn post() -> gallery_post::Model {
+        gallery_post::Model {
+            pk: Uuid::from_u128(2),
+            gallery: Uuid::from_u128(1),
+            discord_message_id: 30,
+            discord_author_id: Some(40),
+            author_name: Some("artist".to_owned()),
+            content: Some("a drawing".to_owned()),
+            source_url: None,
+            media_url: Some("https://cdn.discordapp.com/attachments/10/50/drawing.png".to_owned()),
+            media_width: None,
+            media_height: None,
+            thumbnail_url: None,
+            thumbnail_width: None,
+            thumbnail_height: None,
+            date_created: Utc.with_ymd_and_hms(2022, 9, 2, 12, 0, 0).unwrap(),
+            hidden: false,
+            source_key: "attachment:50".to_owned(),
+            content_hash: None,
+            perceptual_hash: None,
+            duplicate_group: None
+        }
+    }
+
+    fn etag(gallery: &gallery::Model, posts: &[gallery_post::Model]) -> String {
+        GalleryFeed { gallery_url: "https://example.com/gallery/1".to_owned(), gallery, posts }.etag()
+    }
+
+    #[test]
+    fn etag_is_stable() {
+        let gallery = gallery();
+        assert_eq!(etag(&gallery, &[post()]), etag(&gallery, &[post()]));
+    }
+
+    #[test]
+    fn etag_changes_with_edits() {
+        let gallery = gallery();
+        let original = etag(&gallery, &[post()]);
+
+        let mut edited = post();
+        edited.content = Some("a painting".to_owned());
+        assert_ne!(etag(&gallery, &[edited]), original);
+
+        let mut edited = post();
+        edited.media_url = Some("https://cdn.discordapp.com/attachments/10/50/painting.png".to_owned());
+        assert_ne!(etag(&gallery, &[edited]), original);
+
+        let mut edited = post();
+        edited.author_name = Some("someone else".to_owned());
+        assert_ne!(etag(&gallery, &[edited]), original);
+
+        assert_ne!(etag(&gallery, &[]), original);
+    }
+
+    #[test]
+    fn etag_ignores_what_the_feed_leaves_out() {
+        let gallery = gallery();
+        let mut measured = post();
+        measured.media_width = Some(800);
+        assert_eq!(etag(&gallery, &[measured]), etag(&gallery, &[post()]));
+    }
+}
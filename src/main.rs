@@ -1,31 +1,145 @@
+mod archive;
+mod auth;
 mod bot;
+mod config;
+mod duplicates;
+mod events;
+mod export;
+mod feed;
+mod metrics;
+mod schema;
+mod share;
+mod shutdown;
+mod static_site;
+mod tags;
+mod telemetry;
+#[cfg(test)]
+mod test_db;
 mod web;
 
+use crate::auth::DiscordAuth;
 use crate::bot::Handler;
-use crate::web::galleria_service;
+use crate::config::{Config, ConfigOverrides, RawConfig};
+use crate::duplicates::DuplicateDetector;
+use crate::events::{event_channel, relay_notifications, EventNotifier, EventSender};
+use crate::export::{ConflictPolicy, ImportOutcome};
+use crate::metrics::{GatewayStatus, Metrics};
+use crate::share::ShareSigner;
+use crate::shutdown::{listen_for_signals, Shutdown};
+use crate::tags::TagParser;
+use crate::telemetry::LogFormat;
+use crate::web::{galleria_service, health, metrics_endpoint, AdminContext, ViewerAuth};
 
-use std::env;
-use std::net::SocketAddr;
-use std::str::FromStr;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use anyhow::Result;
-use futures::FutureExt;
-use futures::future::try_join;
-use sea_orm::Database;
+use clap::{Args, Parser, Subcommand};
+use futures::future::{try_join, try_join3};
+use migration::{Migrator, MigratorTrait};
+use sea_orm::{DatabaseConnection, EntityTrait};
 use serenity::Client;
-use serenity::prelude::GatewayIntents;
+use serenity::http::Http;
+use sql_entities::gallery;
+use tracing::{info, warn};
+use warp::Filter;
 
-struct Environment {
-    token: String,
-    db_url: String,
-    base_url: String,
-    web_listen_addr: SocketAddr
+#[derive(Parser)]
+#[clap(name = "galleria", version, about = "Art galleries made from Discord channels.")]
+struct Cli {
+    #[clap(flatten)]
+    config: ConfigArgs,
+    #[clap(flatten)]
+    telemetry: TelemetryArgs,
+    /// What to run, the bot and web server together if omitted.
+    #[clap(subcommand)]
+    command: Option<CliCommand>
 }
 
-fn load() -> Result<Environment> {
-    // Load the dotenv file, but ignore not found errors. 
+/// Where the config comes from. Settings from the file are overridden by environment variables,
+/// which are overridden by these flags.
+#[derive(Args)]
+struct ConfigArgs {
+    /// The config file to read. galleria.toml is read if it exists when omitted.
+    #[clap(long = "config", global = true, env = "GALLERIA_CONFIG")]
+    file: Option<PathBuf>,
+    /// Overrides database.url.
+    #[clap(long, global = true)]
+    database_url: Option<String>,
+    /// Overrides base_url. For render-static, the url the site will be hosted at.
+    #[clap(long, global = true)]
+    base_url: Option<String>,
+    /// Overrides listen_addr.
+    #[clap(long, global = true)]
+    listen_addr: Option<String>
+}
+
+/// Where logs and traces go. These aren't config settings, since logging starts before the config is read.
+#[derive(Args)]
+struct TelemetryArgs {
+    /// How log lines are written: text or json. RUST_LOG sets what's logged.
+    #[clap(long, global = true, env = "LOG_FORMAT", default_value = "text")]
+    log_format: LogFormat,
+    /// Export traces to the OpenTelemetry collector at this OTLP/gRPC endpoint, e.g. http://localhost:4317.
+    #[clap(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Run the Discord bot and the web server.
+    Serve,
+    /// Run only the Discord bot. Live updates reach web servers in other processes through the database.
+    Bot,
+    /// Run only the web server. Any number of them can run next to a single bot.
+    Web,
+    /// Apply pending database migrations.
+    Migrate,
+    /// Ingest the messages in a channel's history that aren't in its gallery yet.
+    Backfill {
+        /// The id of a channel with a gallery.
+        channel: u64
+    },
+    /// Bring galleries up to date with their channels, removing posts whose messages are gone.
+    /// This also records the authors of posts ingested before authors were, so opt-outs hide them.
+    Resync {
+        /// A gallery uuid or channel id. Every gallery is resynced if omitted.
+        gallery: Option<String>
+    },
+    /// Export a gallery as a JSON archive.
+    Export {
+        /// A gallery uuid or channel id.
+        gallery: String,
+        /// Where to write the archive, stdout if omitted or `-`.
+        file: Option<PathBuf>,
+        /// Also download the posts' media into this directory. The archive records which file belongs to which post.
+        #[clap(long)]
+        with_media: Option<PathBuf>
+    },
+    /// Import a gallery from a JSON archive.
+    Import {
+        file: PathBuf,
+        /// What to do when the channel already has a gallery: fail, skip or replace.
+        #[clap(long, default_value = "fail")]
+        on_conflict: ConflictPolicy
+    },
+    /// Render a gallery into a directory that can be served by any static host.
+    RenderStatic {
+        /// A gallery uuid or channel id.
+        gallery: String,
+        /// Feeds are only written when the url the site will be hosted at is given with --base-url.
+        outdir: PathBuf
+    },
+    /// Check the configuration without starting anything.
+    CheckConfig
+}
+
+fn load_dotenv() -> Result<()> {
+    // Load the dotenv file, but ignore not found errors.
     dotenv::dotenv()
-        .map(|ok| Some(ok))
+        .map(Some)
         .or_else(|err| match err {
             dotenv::Error::Io(io_error) =>
                 if io_error.kind() == std::io::ErrorKind::NotFound {
@@ -36,38 +150,396 @@ fn load() -> Result<Environment> {
             _ => Err(err)
         })?;
 
-    Ok(Environment {
-        token: env::var("DISCORD_TOKEN")?,
-        db_url:  env::var("DATABASE_URL")?,
-        base_url: env::var("BASE_URL")?,
-        web_listen_addr: SocketAddr::from_str(&env::var("LISTEN_ADDR")?)?
-    })
+    Ok(())
+}
+
+impl ConfigArgs {
+    fn load(&self) -> Result<RawConfig> {
+        Ok(config::load_config(self.file.as_deref(), ConfigOverrides {
+            database_url: self.database_url.clone(),
+            base_url: self.base_url.clone(),
+            listen_addr: self.listen_addr.clone()
+        }))
+    }
+}
+
+/// Commands that only touch the database don't need the rest of the configuration.
+async fn connect_database(config_args: &ConfigArgs) -> Result<DatabaseConnection> {
+    let database = config_args.load()?.database()?;
+    let (db_connection, _) = database.connect().await?;
+
+    schema::ensure_schema(&db_connection, database.auto_migrate).await?;
+    Ok(db_connection)
+}
+
+/// Everything the bot and web server share.
+struct Services {
+    config: Config,
+    db_connection: Arc<DatabaseConnection>,
+    events: EventSender,
+    share_signer: Option<Arc<ShareSigner>>,
+    handler: Arc<Handler>,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown
+}
+
+async fn setup(config_args: &ConfigArgs) -> Result<Services> {
+    let config = config_args.load()?.validate()?;
+
+    let (db_connection, pool) = config.database.connect().await?;
+    if !config.database.is_postgres() {
+        info!("Using SQLite, live updates only reach web servers in this process.");
+    }
+
+    let schema = schema::ensure_schema(&db_connection, config.database.auto_migrate).await?;
+    info!("The database schema is at {}.", schema.version.as_deref().unwrap_or("no migrations"));
+    let db_connection = Arc::new(db_connection);
+    let events = event_channel();
+    let metrics = Arc::new(Metrics::new(pool));
+
+    let shutdown = Shutdown::new();
+    tokio::spawn(listen_for_signals(shutdown.clone()));
+
+    // Without a secret no share links can be made, but private galleries stay private
+    let share_signer = config.share_secret.as_deref().map(|secret| Arc::new(ShareSigner::new(secret)));
+
+    // The pattern was already checked by validation
+    let tag_parser = TagParser::new(config.tag_pattern.as_deref())?;
+
+    let handler = Arc::new(Handler {
+        db_connection: db_connection.clone(),
+        base_url: config.base_url.clone(),
+        command_prefix: config.command_prefix.clone(),
+        tag_parser,
+        gallery_defaults: config.gallery_defaults.clone(),
+        features: config.features,
+        events: EventNotifier::new(db_connection.clone(), events.clone()),
+        share_signer: share_signer.clone(),
+        shutdown: shutdown.clone(),
+        metrics: metrics.clone(),
+        gateway: Arc::new(GatewayStatus::default()),
+        duplicates: config.duplicates.enabled
+            .then(|| DuplicateDetector::new(db_connection.clone(), config.duplicates.max_distance)),
+        repost_reaction: config.duplicates.repost_reaction.clone()
+    });
+
+    Ok(Services { config, db_connection, events, share_signer, handler, metrics, shutdown })
+}
+
+async fn start_bot(services: &Services) -> Result<Client> {
+    Ok(Client::builder(&services.config.discord_token, services.config.intents)
+        .event_handler_arc(services.handler.clone())
+        .await?)
+}
+
+/// Runs the bot until shutdown, then lets event handlers that already started finish before disconnecting.
+async fn run_discord_client(mut discord_client: Client, shutdown: Shutdown, timeout: Duration) -> Result<()> {
+    let shard_manager = discord_client.shard_manager.clone();
+    let mut client = Box::pin(discord_client.start());
+
+    tokio::select! {
+        result = &mut client => return Ok(result?),
+        _ = shutdown.wait() => {}
+    }
+
+    shutdown.drain(timeout).await;
+    info!("Disconnecting from Discord.");
+    shard_manager.lock().await.shutdown_all().await;
+
+    // Returns once the shards are down
+    client.await?;
+    Ok(())
+}
+
+/// Serves the metrics and health checks on their own address, if one is configured.
+/// Everything is taken from the services up front, so they can be handed to the web server.
+fn run_metrics(services: &Services, gateway: Option<Arc<GatewayStatus>>) -> impl Future<Output = Result<()>> {
+    let addr = services.config.metrics_listen_addr;
+    let service = metrics_endpoint(services.metrics.clone())
+        .or(health(services.db_connection.clone(), gateway, services.shutdown.clone()));
+    let shutdown = services.shutdown.clone();
+
+    async move {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return Ok(())
+        };
+
+        let (_, metrics_server) = warp::serve(service)
+            .try_bind_with_graceful_shutdown(addr, async move { shutdown.wait().await })?;
+
+        info!("Serving metrics on {}.", addr);
+        metrics_server.await;
+        Ok(())
+    }
+}
+
+/// Serves the web frontend until shutdown. The gateway is only given when this process runs the bot,
+/// so readiness doesn't depend on a bot running elsewhere.
+async fn run_web(services: Services, http: Arc<Http>, gateway: Option<Arc<GatewayStatus>>) -> Result<()> {
+    let Services { config, db_connection, events, share_signer, handler, metrics, shutdown } = services;
+
+    // Membership checks use the bot's own connection to look up channel permissions
+    let discord_auth = config.oauth.map(|oauth| Arc::new(DiscordAuth::new(
+        oauth,
+        &config.base_url,
+        http.clone(),
+        db_connection.clone()
+    )));
+
+    // The admin area resyncs galleries through the bot
+    let admin_context = if config.admin_user_ids.is_empty() {
+        None
+    } else {
+        Some(Arc::new(AdminContext {
+            user_ids: config.admin_user_ids,
+            handler,
+            http,
+            resyncing: Mutex::new(HashSet::new())
+        }))
+    };
+
+    let viewer_auth = ViewerAuth { share_signer, discord_auth };
+    let service = galleria_service(db_connection.clone(), config.base_url, config.features, events.clone(), viewer_auth, admin_context, shutdown.clone())
+        .or(health(db_connection, gateway, shutdown.clone()))
+        .with(warp::log::custom(move |info| metrics.observe_request(info)))
+        .with(warp::trace::request());
+    let stop_accepting = {
+        let shutdown = shutdown.clone();
+        async move { shutdown.wait().await }
+    };
+    let (_, web_server) = warp::serve(service).bind_with_graceful_shutdown(config.listen_addr, stop_accepting);
+
+    // In-flight requests get until the timeout, long downloads are cut off after that
+    let timeout = config.shutdown_timeout;
+    let web_server = async {
+        tokio::select! {
+            _ = web_server => info!("All connections are closed."),
+            _ = async { shutdown.wait().await; tokio::time::sleep(timeout).await } =>
+                warn!("Connections were still open after {:?}, closing them.", timeout)
+        }
+    };
+
+    // On Postgres events come through the database, whichever process published them.
+    // Relaying only returns when it can't start, which is fatal.
+    if config.database.is_postgres() {
+        tokio::select! {
+            result = relay_notifications(&config.database.url, events) => result?,
+            _ = web_server => {}
+        }
+    } else {
+        web_server.await;
+    }
+
+    Ok(())
+}
+
+async fn serve(config_args: &ConfigArgs) -> Result<()> {
+    let services = setup(config_args).await?;
+    let discord_client = start_bot(&services).await?;
+    let http = discord_client.cache_and_http.http.clone();
+    let (shutdown, timeout) = (services.shutdown.clone(), services.config.shutdown_timeout);
+    let gateway = services.handler.gateway.clone();
+
+    try_join3(
+        run_discord_client(discord_client, shutdown, timeout),
+        run_metrics(&services, Some(gateway.clone())),
+        run_web(services, http, Some(gateway))
+    ).await?;
+    Ok(())
+}
+
+async fn run_bot(config_args: &ConfigArgs) -> Result<()> {
+    let services = setup(config_args).await?;
+    let discord_client = start_bot(&services).await?;
+    let gateway = services.handler.gateway.clone();
+
+    try_join(
+        run_discord_client(discord_client, services.shutdown.clone(), services.config.shutdown_timeout),
+        run_metrics(&services, Some(gateway))
+    ).await?;
+    Ok(())
+}
+
+/// The bot runs in other processes, this one only uses Discord's HTTP API for sign ins and admin resyncs.
+async fn run_web_only(config_args: &ConfigArgs) -> Result<()> {
+    let services = setup(config_args).await?;
+    let http = Arc::new(Http::new(&services.config.discord_token));
+    let (shutdown, timeout) = (services.shutdown.clone(), services.config.shutdown_timeout);
+
+    try_join(run_metrics(&services, None), run_web(services, http, None)).await?;
+
+    // Resyncs started from the admin area
+    shutdown.drain(timeout).await;
+    Ok(())
+}
+
+async fn migrate(config_args: &ConfigArgs) -> Result<()> {
+    let (db_connection, _) = config_args.load()?.database()?.connect().await?;
+
+    let state = schema::schema_state(&db_connection).await?;
+    if !state.unknown.is_empty() {
+        anyhow::bail!("The database was migrated by a newer version of galleria, it has migrations this version doesn't know: {}.", state.unknown.join(", "));
+    }
+
+    Migrator::up(&db_connection, None).await?;
+    println!("Applied {} migrations, the database is up to date.", state.pending.len());
+    Ok(())
+}
+
+async fn backfill(config_args: &ConfigArgs, channel: u64) -> Result<()> {
+    let services = setup(config_args).await?;
+    let http = Http::new(&services.config.discord_token);
+    let gallery_model = export::find_gallery_by_reference(&services.db_connection, &channel.to_string()).await?;
+
+    let summary = services.handler.backfill_gallery(&http, &gallery_model).await?;
+    println!("Added art from {} messages to gallery {}.", summary.ingested_messages, gallery_model.pk);
+    if summary.interrupted {
+        println!("The backfill was interrupted, run it again or start the bot to continue it.");
+    }
+    Ok(())
+}
+
+async fn resync(config_args: &ConfigArgs, gallery: Option<String>) -> Result<()> {
+    let services = setup(config_args).await?;
+    let http = Http::new(&services.config.discord_token);
+
+    let galleries = match gallery {
+        Some(gallery) => vec![export::find_gallery_by_reference(&services.db_connection, &gallery).await?],
+        None => gallery::Entity::find().all(services.db_connection.as_ref()).await?
+    };
+
+    // Keep going through the other galleries when one fails, e.g. because the bot lost access to its channel
+    let mut failed = 0;
+    for gallery_model in galleries {
+        if services.shutdown.is_shutting_down() {
+            break;
+        }
+
+        match services.handler.resync_gallery(&http, &gallery_model).await {
+            Ok(summary) if summary.interrupted => println!(
+                "Resync of gallery {} was interrupted after adding art from {} messages.",
+                gallery_model.pk, summary.ingested_messages
+            ),
+            Ok(summary) => println!(
+                "Resynced gallery {}: added art from {} messages, removed {} posts.",
+                gallery_model.pk, summary.ingested_messages, summary.removed_posts
+            ),
+            Err(why) => {
+                eprintln!("Could not resync gallery {}: {:?}", gallery_model.pk, why);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} galleries could not be resynced.", failed);
+    }
+    Ok(())
+}
+
+async fn export_command(config_args: &ConfigArgs, gallery: String, file: Option<PathBuf>, with_media: Option<PathBuf>) -> Result<()> {
+    let db_connection = connect_database(config_args).await?;
+    let gallery_model = export::find_gallery_by_reference(&db_connection, &gallery).await?;
+    let archive = export::export_gallery(&db_connection, gallery_model, with_media.as_deref()).await?;
+    let json = serde_json::to_string_pretty(&archive)?;
+
+    match file {
+        Some(path) if path.as_os_str() != "-" => std::fs::write(path, json)?,
+        _ => println!("{}", json)
+    }
+    Ok(())
+}
+
+async fn import_command(config_args: &ConfigArgs, file: PathBuf, on_conflict: ConflictPolicy) -> Result<()> {
+    let db_connection = connect_database(config_args).await?;
+    let archive = export::parse_archive(&std::fs::read_to_string(file)?)?;
+
+    match export::import_gallery(&db_connection, archive, on_conflict).await? {
+        ImportOutcome::Imported { gallery, posts, remapped_ids } =>
+            println!("Imported gallery {} with {} posts, {} ids had to be changed.", gallery, posts, remapped_ids),
+        ImportOutcome::Skipped { existing_gallery } =>
+            println!("Skipped, the channel already has gallery {}.", existing_gallery)
+    }
+    Ok(())
+}
+
+async fn render_static(config_args: &ConfigArgs, gallery: String, outdir: PathBuf) -> Result<()> {
+    let db_connection = connect_database(config_args).await?;
+    let gallery_model = export::find_gallery_by_reference(&db_connection, &gallery).await?;
+    let summary = static_site::render_static_site(&db_connection, &gallery_model, &outdir, config_args.base_url.as_deref()).await?;
+
+    println!("Rendered {} posts with {} media files into {}.", summary.posts, summary.media, outdir.display());
+    if summary.failed_media > 0 {
+        println!("{} media files could not be downloaded, their pages link to Discord instead.", summary.failed_media);
+    }
+    Ok(())
+}
+
+/// Reports every problem with the config at once, or what the valid config enables.
+fn check_config(config_args: &ConfigArgs) -> Result<()> {
+    let config = config_args.load()?.validate()?;
+    let enabled = |enabled: bool| if enabled { "enabled" } else { "disabled" };
+
+    println!("The configuration is valid.");
+    println!("Database: {}", if config.database.is_postgres() { "Postgres" } else { "SQLite" });
+    println!("Automatic migrations: {}", enabled(config.database.auto_migrate));
+    println!("Command prefix: {}", config.command_prefix);
+    println!("Share links: {}", enabled(config.share_secret.is_some()));
+    println!("Discord sign in: {}", enabled(config.oauth.is_some()));
+    println!("Admin area: {}", enabled(!config.admin_user_ids.is_empty()));
+    println!("Feeds: {}", enabled(config.features.feeds));
+    println!("Downloads: {}", enabled(config.features.downloads));
+    match config.metrics_listen_addr {
+        Some(addr) => println!("Metrics: {}", addr),
+        None => println!("Metrics: disabled")
+    }
+    println!("Duplicate detection: {}", enabled(config.duplicates.enabled));
+    if let Some(reaction) = &config.duplicates.repost_reaction {
+        println!("Repost reaction: {}", reaction);
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    let environment = load().unwrap();
+    // Loaded before parsing, so the dotenv file can set flags' environment variables too
+    if let Err(why) = load_dotenv() {
+        eprintln!("Could not load the .env file: {:?}", why);
+        std::process::exit(1);
+    }
 
-    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(CliCommand::Serve);
 
-    // Setup DB
-    let db_connection_base = Database::connect(environment.db_url).await
-        .expect("Could not estable a connection to the database.");
-    let db_connection = Arc::new(db_connection_base);
+    // Maintenance commands print their results to stdout, so logs go elsewhere
+    let logs_to_stderr = !matches!(command, CliCommand::Serve | CliCommand::Bot | CliCommand::Web);
+    let telemetry = match telemetry::init_tracing(cli.telemetry.log_format, cli.telemetry.otlp_endpoint.as_deref(), logs_to_stderr) {
+        Ok(telemetry) => telemetry,
+        Err(why) => {
+            eprintln!("Could not set up tracing: {:?}", why);
+            std::process::exit(1);
+        }
+    };
 
-    let intents = GatewayIntents::GUILD_MESSAGES
-        | GatewayIntents::DIRECT_MESSAGES
-        | GatewayIntents::MESSAGE_CONTENT;
+    let result = match command {
+        CliCommand::Serve => serve(&cli.config).await,
+        CliCommand::Bot => run_bot(&cli.config).await,
+        CliCommand::Web => run_web_only(&cli.config).await,
+        CliCommand::Migrate => migrate(&cli.config).await,
+        CliCommand::Backfill { channel } => backfill(&cli.config, channel).await,
+        CliCommand::Resync { gallery } => resync(&cli.config, gallery).await,
+        CliCommand::Export { gallery, file, with_media } => export_command(&cli.config, gallery, file, with_media).await,
+        CliCommand::Import { file, on_conflict } => import_command(&cli.config, file, on_conflict).await,
+        CliCommand::RenderStatic { gallery, outdir } => render_static(&cli.config, gallery, outdir).await,
+        CliCommand::CheckConfig => check_config(&cli.config)
+    };
 
-    let mut discord_client = Client::builder(&environment.token, intents)
-        .event_handler(Handler { db_connection: db_connection.clone(), base_url: environment.base_url })
-        .await
-        .expect("Error created client");
-    
-    let web_server = warp::serve(galleria_service(db_connection.clone())).bind(environment.web_listen_addr)
-        .map(|_| Ok(()));
+    // Exiting skips destructors, so the remaining spans are exported first
+    drop(telemetry);
 
-    if let Err(why) = try_join(discord_client.start(), web_server).await {
-        println!("Client error: {:?}", why);
+    if let Err(why) = result {
+        eprintln!("{:?}", why);
+        std::process::exit(1);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use prometheus::{Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use sea_orm::prelude::Uuid;
+use serenity::gateway::ConnectionStage;
+use tracing::error;
+use warp::http::StatusCode;
+
+use crate::config::DatabasePool;
+
+/// Prometheus metrics of the bot, the web server and the database pool.
+///
+/// Ingestion is counted per gallery, which reveals the ids of unlisted galleries,
+/// so these are only served on the separate metrics listener.
+pub struct Metrics {
+    registry: Registry,
+    ingested_posts: IntCounterVec,
+    event_seconds: HistogramVec,
+    errors: IntCounterVec,
+    http_requests: IntCounterVec,
+    http_request_seconds: HistogramVec,
+    db_connections: IntGauge,
+    db_idle_connections: IntGauge,
+    pool: DatabasePool
+}
+
+impl Metrics {
+    pub fn new(pool: DatabasePool) -> Self {
+        let registry = Registry::new_custom(Some("galleria".to_owned()), None)
+            .expect("The metrics prefix is valid.");
+
+        let ingested_posts = IntCounterVec::new(
+            Opts::new("ingested_posts_total", "Posts created from Discord messages."),
+            &["gallery"]
+        ).unwrap();
+        let event_seconds = HistogramVec::new(
+            HistogramOpts::new("event_handling_seconds", "How long handling gateway events took."),
+            &["event"]
+        ).unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "Errors while handling events, commands and backfills."),
+            &["source"]
+        ).unwrap();
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests served."),
+            &["method", "route", "status"]
+        ).unwrap();
+        let http_request_seconds = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "How long HTTP requests took to serve."),
+            &["method", "route"]
+        ).unwrap();
+        let db_connections = IntGauge::new("db_connections", "Open database connections.").unwrap();
+        let db_idle_connections = IntGauge::new("db_idle_connections", "Idle database connections.").unwrap();
+
+        // Registering only fails for duplicate names
+        registry.register(Box::new(ingested_posts.clone())).unwrap();
+        registry.register(Box::new(event_seconds.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_request_seconds.clone())).unwrap();
+        registry.register(Box::new(db_connections.clone())).unwrap();
+        registry.register(Box::new(db_idle_connections.clone())).unwrap();
+
+        Metrics {
+            registry,
+            ingested_posts,
+            event_seconds,
+            errors,
+            http_requests,
+            http_request_seconds,
+            db_connections,
+            db_idle_connections,
+            pool
+        }
+    }
+
+    pub fn count_ingested(&self, gallery_id: Uuid, posts: usize) {
+        self.ingested_posts.with_label_values(&[&gallery_id.to_string()]).inc_by(posts as u64);
+    }
+
+    /// Observes how long handling an event takes, once the returned timer is dropped.
+    pub fn time_event(&self, event: &str) -> HistogramTimer {
+        self.event_seconds.with_label_values(&[event]).start_timer()
+    }
+
+    pub fn count_error(&self, source: &str) {
+        self.errors.with_label_values(&[source]).inc();
+    }
+
+    pub fn observe_request(&self, info: warp::log::Info) {
+        let method = info.method().as_str();
+        let route = route_label(info.path(), info.status());
+
+        self.http_requests.with_label_values(&[method, &route, info.status().as_str()]).inc();
+        self.http_request_seconds.with_label_values(&[method, &route]).observe(info.elapsed().as_secs_f64());
+    }
+
+    /// The metrics in Prometheus' text format.
+    pub fn render(&self) -> String {
+        self.db_connections.set(self.pool.size() as i64);
+        self.db_idle_connections.set(self.pool.num_idle() as i64);
+
+        let mut buffer = Vec::new();
+        if let Err(why) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Error encoding metrics: {:?}", why);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Groups requests by route, so ids don't each get their own series.
+/// Paths that weren't found aren't routes, they're grouped together.
+fn route_label(path: &str, status: StatusCode) -> String {
+    if status == StatusCode::NOT_FOUND {
+        return "unmatched".to_owned();
+    }
+
+    path.split('/')
+        .map(|segment| if Uuid::parse_str(segment).is_ok() || segment.parse::<u64>().is_ok() { "{id}" } else { segment })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+/// Whether the bot's shards are connected to the gateway.
+#[derive(Default)]
+pub struct GatewayStatus {
+    shards: Mutex<HashMap<u64, ConnectionStage>>
+}
+
+impl GatewayStatus {
+    pub fn update(&self, shard_id: u64, stage: ConnectionStage) {
+        self.shards.lock().unwrap().insert(shard_id, stage);
+    }
+
+    /// Connected once every shard has connected, and no longer once any of them drops.
+    pub fn is_connected(&self) -> bool {
+        let shards = self.shards.lock().unwrap();
+        !shards.is_empty() && shards.values().all(|stage| *stage == ConnectionStage::Connected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_grouped_in_route_labels() {
+        let gallery = "/gallery/3f2b8c1e-9d4a-4b6e-8f7a-2c5d1e0b9a34/post/1234";
+        assert_eq!(route_label(gallery, StatusCode::OK), "/gallery/{id}/post/{id}");
+        assert_eq!(route_label("/api/v1/search", StatusCode::OK), "/api/v1/search");
+        assert_eq!(route_label("/wp-admin/1234", StatusCode::NOT_FOUND), "unmatched");
+    }
+
+    #[test]
+    fn the_gateway_is_connected_once_every_shard_is() {
+        let gateway = GatewayStatus::default();
+        assert!(!gateway.is_connected());
+
+        gateway.update(0, ConnectionStage::Connected);
+        gateway.update(1, ConnectionStage::Handshake);
+        assert!(!gateway.is_connected());
+
+        gateway.update(1, ConnectionStage::Connected);
+        assert!(gateway.is_connected());
+
+        gateway.update(0, ConnectionStage::Resuming);
+        assert!(!gateway.is_connected());
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use migration::{Migrator, MigratorTrait};
+use sea_orm::{DatabaseConnection, DbErr, EntityTrait, QueryOrder};
+use sql_entities::seaql_migrations;
+use tracing::info;
+
+/// How the database's schema compares to the migrations this version of galleria has.
+#[derive(Debug)]
+pub struct SchemaState {
+    /// The newest migration applied to the database.
+    pub version: Option<String>,
+    /// Migrations that haven't been applied yet.
+    pub pending: Vec<String>,
+    /// Applied migrations this version doesn't have, because a newer version applied them.
+    pub unknown: Vec<String>
+}
+
+pub async fn schema_state(db: &DatabaseConnection) -> Result<SchemaState> {
+    let known = Migrator::migrations().iter()
+        .map(|migration| migration.name().to_owned())
+        .collect::<Vec<String>>();
+    let applied = Migrator::get_migration_models(db).await?.into_iter()
+        .map(|migration| migration.version)
+        .collect::<Vec<String>>();
+
+    Ok(SchemaState {
+        version: applied.iter().max().cloned(),
+        pending: known.iter().filter(|name| !applied.contains(name)).cloned().collect(),
+        unknown: applied.iter().filter(|name| !known.contains(name)).cloned().collect()
+    })
+}
+
+/// Makes sure the schema is the one this version expects, applying pending migrations if `auto_migrate` is set.
+/// A schema that's ahead is never touched, the newer version that migrated it should be run instead.
+pub async fn ensure_schema(db: &DatabaseConnection, auto_migrate: bool) -> Result<SchemaState> {
+    let state = schema_state(db).await?;
+
+    if !state.unknown.is_empty() {
+        anyhow::bail!(
+            "The database was migrated by a newer version of galleria, it has migrations this version doesn't know: {}. Upgrade galleria instead.",
+            state.unknown.join(", ")
+        );
+    }
+
+    if state.pending.is_empty() {
+        return Ok(state);
+    }
+
+    if !auto_migrate {
+        anyhow::bail!(
+            "The database is missing {} migrations: {}. Run `galleria migrate` or set database.auto_migrate.",
+            state.pending.len(), state.pending.join(", ")
+        );
+    }
+
+    info!("Applying {} pending migrations.", state.pending.len());
+    Migrator::up(db, None).await?;
+    schema_state(db).await
+}
+
+/// The newest migration applied to the database, as reported by the health checks.
+pub async fn schema_version(db: &DatabaseConnection) -> Result<Option<String>, DbErr> {
+    let newest = seaql_migrations::Entity::find()
+        .order_by_desc(seaql_migrations::Column::Version)
+        .one(db)
+        .await?;
+
+    Ok(newest.map(|migration| migration.version))
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{ConnectionTrait, Statement};
+
+    use super::*;
+    use crate::test_db;
+
+    async fn check_migrations(db: &DatabaseConnection) {
+        let state = schema_state(db).await.unwrap();
+        assert!(state.pending.is_empty(), "pending migrations: {:?}", state.pending);
+        assert_eq!(state.version, Migrator::migrations().last().map(|migration| migration.name().to_owned()));
+        assert_eq!(schema_version(db).await.unwrap(), state.version);
+
+        Migrator::down(db, None).await.unwrap();
+        let state = schema_state(db).await.unwrap();
+        assert_eq!(state.version, None);
+        assert_eq!(state.pending.len(), Migrator::migrations().len());
+
+        // Rolling back has to leave nothing behind that would trip up migrating again
+        ensure_schema(db, true).await.unwrap();
+        assert!(schema_state(db).await.unwrap().pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sqlite_migrates_up_and_down() {
+        let (db, _) = test_db::sqlite().await;
+        check_migrations(&db).await;
+    }
+
+    #[tokio::test]
+    async fn postgres_migrates_up_and_down() {
+        let (db, _, _guard) = match test_db::postgres().await {
+            Some(postgres) => postgres,
+            None => return
+        };
+        check_migrations(&db).await;
+    }
+
+    #[tokio::test]
+    async fn pending_migrations_need_auto_migrate() {
+        let (db, _) = test_db::sqlite().await;
+        Migrator::down(&db, Some(1)).await.unwrap();
+
+        assert!(ensure_schema(&db, false).await.is_err());
+        assert_eq!(ensure_schema(&db, true).await.unwrap().pending, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn newer_schemas_are_refused() {
+        let (db, _) = test_db::sqlite().await;
+        db.execute(Statement::from_string(
+            db.get_database_backend(),
+            "INSERT INTO seaql_migrations (version, applied_at) VALUES ('m29990101_000001_from_the_future', 0)".to_owned()
+        )).await.unwrap();
+
+        assert!(ensure_schema(&db, true).await.is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use sea_orm::prelude::Uuid;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs and verifies share tokens, which grant access to a single private gallery until they expire.
+///
+/// A token is `<gallery uuid>.<expiry as unix seconds>.<signature>`, where the signature is an
+/// HMAC-SHA256 of the first two parts, encoded as unpadded url-safe base64.
+pub struct ShareSigner {
+    key: Vec<u8>
+}
+
+impl ShareSigner {
+    pub fn new(secret: &str) -> Self {
+        ShareSigner { key: secret.as_bytes().to_vec() }
+    }
+
+    pub fn sign(&self, gallery_id: Uuid, expires: DateTime<Utc>) -> String {
+        let payload = format!("{}.{}", gallery_id, expires.timestamp());
+        let signature = base64::encode_config(self.mac(&payload).finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Returns the gallery a token grants access to and when it expires, if the token is valid and unexpired.
+    pub fn verify(&self, token: &str, now: DateTime<Utc>) -> Option<(Uuid, DateTime<Utc>)> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+
+        let (gallery_id, expires) = payload.split_once('.')?;
+        let gallery_id = Uuid::parse_str(gallery_id).ok()?;
+        let expires = Utc.timestamp_opt(expires.parse().ok()?, 0).single()?;
+
+        if expires > now {
+            Some((gallery_id, expires))
+        } else {
+            None
+        }
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length.");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GALLERY: Uuid = Uuid::from_u128(1);
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 9, 1, 12, 0, 0).unwrap()
+    }
+
+    fn in_hours(hours: i64) -> DateTime<Utc> {
+        now() + chrono::Duration::hours(hours)
+    }
+
+    /// Signs any payload, to build tokens `sign` never would.
+    fn signed(signer: &ShareSigner, payload: &str) -> String {
+        let signature = base64::encode_config(signer.mac(payload).finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        format!("{}.{}", payload, signature)
+    }
+
+    #[test]
+    fn signed_tokens_verify() {
+        let signer = ShareSigner::new("secret");
+        let token = signer.sign(GALLERY, in_hours(24));
+
+        assert_eq!(signer.verify(&token, now()), Some((GALLERY, in_hours(24))));
+    }
+
+    #[test]
+    fn expired_tokens_do_not_verify() {
+        let signer = ShareSigner::new("secret");
+        let token = signer.sign(GALLERY, in_hours(24));
+
+        assert_eq!(signer.verify(&token, in_hours(24)), None);
+        assert_eq!(signer.verify(&token, in_hours(48)), None);
+    }
+
+    #[test]
+    fn tampered_tokens_do_not_verify() {
+        let signer = ShareSigner::new("secret");
+        let token = signer.sign(GALLERY, in_hours(24));
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+
+        // A later expiry with the old signature
+        let extended = token.replace(&in_hours(24).timestamp().to_string(), &in_hours(48).timestamp().to_string());
+        assert_eq!(signer.verify(&extended, now()), None);
+
+        let mut flipped = signature.as_bytes().to_vec();
+        flipped[0] = if flipped[0] == b'A' { b'B' } else { b'A' };
+        let flipped = format!("{}.{}", payload, String::from_utf8(flipped).unwrap());
+        assert_eq!(signer.verify(&flipped, now()), None);
+
+        assert_eq!(ShareSigner::new("other secret").verify(&token, now()), None);
+    }
+
+    #[test]
+    fn tokens_only_grant_their_own_gallery() {
+        let signer = ShareSigner::new("secret");
+        let other_gallery = Uuid::from_u128(2);
+        let token = signer.sign(GALLERY, in_hours(24));
+
+        let swapped = token.replace(&GALLERY.to_string(), &other_gallery.to_string());
+        assert_eq!(signer.verify(&swapped, now()), None);
+
+        let (gallery_id, _) = signer.verify(&token, now()).unwrap();
+        assert_ne!(gallery_id, other_gallery);
+    }
+
+    #[test]
+    fn malformed_tokens_do_not_verify() {
+        let signer = ShareSigner::new("secret");
+        let expires = in_hours(24).timestamp();
+
+        assert_eq!(signer.verify("", now()), None);
+        assert_eq!(signer.verify("no-dots", now()), None);
+        assert_eq!(signer.verify(&format!("{}.{}", GALLERY, expires), now()), None);
+        assert_eq!(signer.verify(&format!("{}.{}.not base64!", GALLERY, expires), now()), None);
+
+        // Correctly signed, but not what `sign` produces
+        assert_eq!(signer.verify(&signed(&signer, &GALLERY.to_string()), now()), None);
+        assert_eq!(signer.verify(&signed(&signer, &format!("{}.tomorrow", GALLERY)), now()), None);
+        assert_eq!(signer.verify(&signed(&signer, &format!("not-a-uuid.{}", expires)), now()), None);
+        assert_eq!(signer.verify(&signed(&signer, &format!("{}.{}", GALLERY, i64::MAX)), now()), None);
+        assert_eq!(signer.verify(&signed(&signer, &format!("{}.{}", GALLERY, expires)), now()), Some((GALLERY, in_hours(24))));
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{watch, Notify};
+use tracing::{info, warn};
+
+/// How long in-flight work gets to finish when no timeout is configured.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Coordinates shutting down: once triggered, no new work is started and in-flight work is waited for.
+#[derive(Clone)]
+pub struct Shutdown {
+    trigger: Arc<watch::Sender<bool>>,
+    signal: watch::Receiver<bool>,
+    tasks: Arc<TaskCounter>
+}
+
+struct TaskCounter {
+    count: AtomicUsize,
+    idle: Notify
+}
+
+/// Keeps shutdown waiting until it's dropped.
+pub struct TaskGuard {
+    tasks: Arc<TaskCounter>
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if self.tasks.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.tasks.idle.notify_waiters();
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (trigger, signal) = watch::channel(false);
+        Shutdown {
+            trigger: Arc::new(trigger),
+            signal,
+            tasks: Arc::new(TaskCounter { count: AtomicUsize::new(0), idle: Notify::new() })
+        }
+    }
+
+    pub fn trigger(&self) {
+        // Only fails without receivers, and this holds one
+        let _ = self.trigger.send(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Resolves once shutdown is triggered.
+    pub async fn wait(&self) {
+        let mut signal = self.signal.clone();
+        while !*signal.borrow() {
+            if signal.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Registers work that shutdown should wait for. Returns `None` once shutting down, so the work isn't started.
+    pub fn begin_task(&self) -> Option<TaskGuard> {
+        self.tasks.count.fetch_add(1, Ordering::AcqRel);
+        let guard = TaskGuard { tasks: self.tasks.clone() };
+
+        // Checked after counting, so work is either refused or waited for
+        if self.is_shutting_down() {
+            None
+        } else {
+            Some(guard)
+        }
+    }
+
+    /// Waits for in-flight work to finish, for at most `timeout`. Returns whether it all finished.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let idle = async {
+            loop {
+                // Registered before checking, so a task finishing in between isn't missed
+                let notified = self.tasks.idle.notified();
+                if self.tasks.count.load(Ordering::Acquire) == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+
+        match tokio::time::timeout(timeout, idle).await {
+            Ok(()) => true,
+            Err(_) => {
+                warn!("{} tasks were still running after {:?}.", self.tasks.count.load(Ordering::Acquire), timeout);
+                false
+            }
+        }
+    }
+}
+
+/// Triggers shutdown on SIGTERM or Ctrl-C.
+pub async fn listen_for_signals(shutdown: Shutdown) {
+    let ctrl_c = tokio::signal::ctrl_c();
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            },
+            Err(why) => {
+                warn!("Could not listen for SIGTERM: {:?}", why);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl-C, shutting down."),
+        _ = terminate => info!("Received SIGTERM, shutting down."),
+        _ = shutdown.wait() => {}
+    }
+
+    shutdown.trigger();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn draining_waits_for_tasks_started_before_shutdown() {
+        let shutdown = Shutdown::new();
+        let task = shutdown.begin_task().unwrap();
+
+        shutdown.trigger();
+        assert!(shutdown.begin_task().is_none());
+        assert!(!shutdown.drain(Duration::from_millis(10)).await);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(task);
+        });
+        assert!(shutdown.drain(Duration::from_secs(5)).await);
+    }
+}
@@ -0,0 +1,370 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use maud::{html, Markup};
+use sea_orm::{DatabaseConnection, prelude::Uuid};
+use sql_entities::{gallery, gallery_post};
+use tracing::{debug, info, warn};
+
+use crate::archive::archive_file_name;
+use crate::feed::{GalleryFeed, FEED_LENGTH};
+use crate::tags;
+use crate::web::{gallery_posts, render_page_meta, render_post_page, PageImage, PageLinks, PageMeta, PostPage};
+
+/// The longest side of generated thumbnails, in pixels.
+const THUMBNAIL_SIZE: u32 = 480;
+
+/// Assets from `static` the pages use.
+const STATIC_ASSETS: [&str; 2] = ["galleria.css", "post.mjs"];
+
+/// What a static export wrote.
+#[derive(Debug, Default)]
+pub struct StaticSiteSummary {
+    pub posts: usize,
+    pub media: usize,
+    /// Media that couldn't be downloaded, its pages link to the original urls instead.
+    pub failed_media: usize,
+    pub feeds: bool
+}
+
+/// A post's media, copied into the site.
+#[derive(Default)]
+struct LocalMedia {
+    media: Option<String>,
+    thumbnail: Option<(String, u32, u32)>
+}
+
+/// Writes a gallery as a static site that works without galleria running:
+///
+/// - `index.html`, a grid of the gallery's posts,
+/// - `post/<pk>/index.html` for each post, so the site keeps the live site's `<gallery>/post/<pk>` links,
+/// - `media/` and `thumbnails/` with copies of the media,
+/// - `feed.atom` and `feed.rss`, when the url the site will be hosted at is known, as feeds need absolute links,
+/// - `static/` with the stylesheet and scripts.
+pub async fn render_static_site(db: &DatabaseConnection, gallery_model: &gallery::Model, outdir: &Path, base_url: Option<&str>) -> Result<StaticSiteSummary> {
+    let base_url = base_url.map(|base_url| base_url.trim_end_matches('/'));
+    let posts = gallery_posts(gallery_model, Vec::new()).all(db).await?;
+    let post_pks = posts.iter().map(|post| post.pk).collect::<Vec<Uuid>>();
+    let tags_by_post = tags::find_tags_by_post(db, &post_pks).await?;
+
+    for dir in ["media", "thumbnails", "post", "static"] {
+        tokio::fs::create_dir_all(outdir.join(dir)).await?;
+    }
+    for asset in STATIC_ASSETS {
+        tokio::fs::copy(Path::new("static").join(asset), outdir.join("static").join(asset)).await?;
+    }
+
+    let mut summary = StaticSiteSummary { posts: posts.len(), ..Default::default() };
+    let client = reqwest::Client::new();
+
+    // Posts are numbered oldest first, like in downloaded archives
+    let mut local_media = Vec::with_capacity(posts.len());
+    for (index, post) in posts.iter().enumerate() {
+        let media = copy_post_media(&client, outdir, posts.len() - index, post).await;
+        match &media.media {
+            Some(_) => summary.media += 1,
+            None if post.media_url.is_some() || post.thumbnail_url.is_some() => summary.failed_media += 1,
+            None => {}
+        }
+        local_media.push(media);
+    }
+
+    for (index, post) in posts.iter().enumerate() {
+        let root = "../../";
+        let page_post = localize_post(post, &local_media[index], root);
+        let newer_post = index.checked_sub(1).map(|newer| localize_post(&posts[newer], &local_media[newer], root));
+        let older_post = posts.get(index + 1).map(|older| localize_post(older, &local_media[index + 1], root));
+        let post_tags = tags_by_post.get(&post.pk).cloned().unwrap_or_default();
+
+        // Link previews don't resolve og:image against the page, so it's absolute when the site's url is known
+        let image = match base_url {
+            Some(base_url) => PageImage::from_post(&localize_post(post, &local_media[index], &format!("{}/", base_url))),
+            None => PageImage::from_post(&page_post)
+        };
+        let meta = PageMeta {
+            title: match &post.author_name {
+                Some(author_name) => format!("Art by {} in {}", author_name, gallery_model.name),
+                None => format!("Art in {}", gallery_model.name)
+            },
+            description: post.content.clone().unwrap_or_else(|| gallery_model.name.clone()),
+            url: base_url.map(|base_url| format!("{}/post/{}/", base_url, post.pk)).unwrap_or_else(|| "./".to_owned()),
+            image
+        };
+        let page = PostPage {
+            gallery: gallery_model,
+            post: &page_post,
+            tags: &post_tags,
+            newer_post: newer_post.as_ref(),
+            older_post: older_post.as_ref(),
+            meta: &meta
+        };
+        let links = PageLinks {
+            gallery: root.to_owned(),
+            assets: format!("{}static", root),
+            post_prefix: "../".to_owned(),
+            post_suffix: "/".to_owned()
+        };
+
+        let page_dir = outdir.join("post").join(post.pk.to_string());
+        tokio::fs::create_dir_all(&page_dir).await?;
+        tokio::fs::write(page_dir.join("index.html"), render_post_page(&page, &links, None).into_string()).await?;
+    }
+
+    let index_posts = posts.iter()
+        .zip(&local_media)
+        .map(|(post, media)| localize_post(post, media, ""))
+        .collect::<Vec<gallery_post::Model>>();
+
+    if let Some(base_url) = base_url {
+        let feed_posts = posts.iter()
+            .zip(&local_media)
+            .take(FEED_LENGTH as usize)
+            .map(|(post, media)| localize_post(post, media, &format!("{}/", base_url)))
+            .collect::<Vec<gallery_post::Model>>();
+        let feed = GalleryFeed { gallery_url: base_url.to_owned(), gallery: gallery_model, posts: &feed_posts };
+
+        tokio::fs::write(outdir.join("feed.atom"), feed.to_atom()).await?;
+        tokio::fs::write(outdir.join("feed.rss"), feed.to_rss()).await?;
+        summary.feeds = true;
+    } else {
+        info!("No base url given, the static site of gallery {} has no feeds.", gallery_model.pk);
+    }
+
+    let index = render_gallery_page(gallery_model, &index_posts, base_url, summary.feeds);
+    tokio::fs::write(outdir.join("index.html"), index.into_string()).await?;
+
+    info!("Rendered gallery {} into {}: {:?}", gallery_model.pk, outdir.display(), summary);
+    Ok(summary)
+}
+
+/// Downloads a post's media into `media/` and makes a thumbnail of it in `thumbnails/`.
+/// Failures are logged and leave the post pointing at its original media.
+async fn copy_post_media(client: &reqwest::Client, outdir: &Path, number: usize, post: &gallery_post::Model) -> LocalMedia {
+    let media_url = match post.media_url.as_ref().or(post.thumbnail_url.as_ref()) {
+        Some(media_url) => media_url,
+        None => return LocalMedia::default()
+    };
+
+    let response = client.get(media_url).send().await.and_then(|response| response.error_for_status());
+    let bytes = match response {
+        Ok(response) => response.bytes().await,
+        Err(why) => Err(why)
+    };
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(why) => {
+            warn!("Could not download {} for the static site: {:?}", media_url, why);
+            return LocalMedia::default();
+        }
+    };
+
+    let file_name = archive_file_name(number, post, media_url);
+    let media_path = format!("media/{}", file_name);
+    if let Err(why) = tokio::fs::write(outdir.join(&media_path), &bytes).await {
+        warn!("Could not write {}: {:?}", media_path, why);
+        return LocalMedia::default();
+    }
+
+    let stem = file_name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(&file_name);
+    let thumbnail_path = format!("thumbnails/{}.jpg", stem);
+    let thumbnail_file: PathBuf = outdir.join(&thumbnail_path);
+
+    // Decoding and resizing is CPU bound, keep it off the async workers
+    let thumbnail = tokio::task::spawn_blocking(move || -> Result<(u32, u32)> {
+        let thumbnail = image::load_from_memory(&bytes)?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+        thumbnail.to_rgb8().save_with_format(&thumbnail_file, image::ImageFormat::Jpeg)?;
+        Ok((thumbnail.width(), thumbnail.height()))
+    }).await;
+
+    let thumbnail = match thumbnail {
+        Ok(Ok((width, height))) => Some((thumbnail_path, width, height)),
+        Ok(Err(why)) => {
+            debug!("Could not make a thumbnail of {}, using the full media: {:?}", media_path, why);
+            None
+        },
+        Err(why) => {
+            warn!("Thumbnail task for {} failed: {:?}", media_path, why);
+            None
+        }
+    };
+
+    LocalMedia { media: Some(media_path), thumbnail }
+}
+
+/// Points a post at its copied media, relative to `root`.
+fn localize_post(post: &gallery_post::Model, media: &LocalMedia, root: &str) -> gallery_post::Model {
+    let mut post = post.clone();
+
+    if let Some(media_path) = &media.media {
+        if post.media_url.is_some() {
+            post.media_url = Some(format!("{}{}", root, media_path));
+        }
+        // Embeds with only a thumbnail had it downloaded as their media
+        post.thumbnail_url = Some(format!("{}{}", root, media_path));
+    }
+    if let Some((thumbnail_path, width, height)) = &media.thumbnail {
+        post.thumbnail_url = Some(format!("{}{}", root, thumbnail_path));
+        post.thumbnail_width = Some(*width as i32);
+        post.thumbnail_height = Some(*height as i32);
+    }
+
+    post
+}
+
+fn render_gallery_page(gallery_model: &gallery::Model, posts: &[gallery_post::Model], base_url: Option<&str>, has_feeds: bool) -> Markup {
+    let links = PageLinks {
+        gallery: "./".to_owned(),
+        assets: "static".to_owned(),
+        post_prefix: "post/".to_owned(),
+        post_suffix: "/".to_owned()
+    };
+
+    let meta = PageMeta {
+        title: gallery_model.name.clone(),
+        description: match posts.len() {
+            1 => "1 post".to_owned(),
+            n => format!("{} posts", n)
+        },
+        url: base_url.map(|base_url| format!("{}/", base_url)).unwrap_or_else(|| "./".to_owned()),
+        image: posts.iter().find_map(PageImage::from_post).map(|image| PageImage {
+            url: match base_url {
+                Some(base_url) => format!("{}/{}", base_url, image.url),
+                None => image.url
+            },
+            ..image
+        })
+    };
+
+    html! {
+        (maud::DOCTYPE)
+        html {
+            head {
+                meta name="viewport" content="initial-scale=1";
+                (render_page_meta(&meta, None))
+                link rel="stylesheet" href=(links.asset("galleria.css"));
+                @if has_feeds {
+                    link rel="alternate" type="application/atom+xml" href="feed.atom";
+                    link rel="alternate" type="application/rss+xml" href="feed.rss";
+                }
+            }
+            body {
+                header {
+                    h1 { (gallery_model.name) }
+                }
+                main .gallery {
+                    @for post in posts {
+                        @let image_url = post.thumbnail_url.as_ref().or(post.media_url.as_ref());
+                        @if let Some(image_url) = image_url {
+                            a .gallery-item href=(links.post(post.pk)) {
+                                img src=(image_url) loading="lazy"
+                                    alt=(post.author_name.as_deref().map(|author| format!("Art by {}", author)).unwrap_or_default())
+                                    width=[post.thumbnail_width.filter(|w| *w > 0)]
+                                    height=[post.thumbnail_height.filter(|h| *h > 0)];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use sea_orm::{ActiveModelTrait, ActiveValue, IntoActiveModel};
+    use warp::Filter;
+
+    use super::*;
+    use crate::test_db::{self, day, insert_gallery, insert_post};
+
+    fn png() -> Vec<u8> {
+        let mut png = Vec::new();
+        image::RgbImage::new(960, 480).write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        png
+    }
+
+    /// Points a post's media at `url`.
+    async fn set_media_url(db: &DatabaseConnection, post: gallery_post::Model, url: String) -> gallery_post::Model {
+        let mut post = post.into_active_model();
+        post.media_url = ActiveValue::Set(Some(url));
+        post.update(db).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn static_sites_copy_media_and_link_it_relatively() {
+        let (db, _) = test_db::sqlite().await;
+        let files = warp::path("art.png").map(png);
+        let (addr, server) = warp::serve(files).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let gallery_model = insert_gallery(&db, 1, None).await;
+        let copied = insert_post(&db, &gallery_model, 10, 100, "", day(1)).await;
+        let copied = set_media_url(&db, copied, format!("http://{}/art.png", addr)).await;
+        let missing = insert_post(&db, &gallery_model, 11, 100, "", day(2)).await;
+        let missing = set_media_url(&db, missing, format!("http://{}/missing.png", addr)).await;
+
+        let outdir = std::env::temp_dir().join(format!("galleria-static-{}", Uuid::new_v4()));
+        let summary = render_static_site(&db, &gallery_model, &outdir, None).await.unwrap();
+        assert_eq!((summary.posts, summary.media, summary.failed_media, summary.feeds), (2, 1, 1, false));
+
+        let media_path = format!("media/0001_artist-100_{}.png", &copied.pk.to_string()[..8]);
+        assert_eq!(std::fs::read(outdir.join(&media_path)).unwrap(), png());
+        let thumbnail = image::open(outdir.join(format!("thumbnails/0001_artist-100_{}.jpg", &copied.pk.to_string()[..8]))).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+
+        let index = std::fs::read_to_string(outdir.join("index.html")).unwrap();
+        assert!(index.contains(&format!("href=\"post/{}/\"", copied.pk)));
+        assert!(!index.contains("feed.atom"));
+
+        let page = std::fs::read_to_string(outdir.join("post").join(copied.pk.to_string()).join("index.html")).unwrap();
+        assert!(page.contains(&format!("src=\"../../{}\"", media_path)));
+        // Media that couldn't be copied is still linked where it was
+        let page = std::fs::read_to_string(outdir.join("post").join(missing.pk.to_string()).join("index.html")).unwrap();
+        assert!(page.contains(&format!("http://{}/missing.png", addr)));
+
+        std::fs::remove_dir_all(outdir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn static_sites_have_feeds_with_a_base_url() {
+        let (db, _) = test_db::sqlite().await;
+        let gallery_model = insert_gallery(&db, 1, None).await;
+        let post = insert_post(&db, &gallery_model, 10, 100, "", day(1)).await;
+
+        let outdir = std::env::temp_dir().join(format!("galleria-static-{}", Uuid::new_v4()));
+        let summary = render_static_site(&db, &gallery_model, &outdir, Some("https://art.example/gallery/")).await.unwrap();
+        assert!(summary.feeds);
+
+        let atom = std::fs::read_to_string(outdir.join("feed.atom")).unwrap();
+        assert!(atom.contains(&format!("https://art.example/gallery/post/{}", post.pk)));
+        let index = std::fs::read_to_string(outdir.join("index.html")).unwrap();
+        assert!(index.contains("feed.atom"));
+
+        std::fs::remove_dir_all(outdir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn post_pages_have_absolute_og_images_with_a_base_url() {
+        let (db, _) = test_db::sqlite().await;
+        let files = warp::path("art.png").map(png);
+        let (addr, server) = warp::serve(files).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let gallery_model = insert_gallery(&db, 1, None).await;
+        let post = insert_post(&db, &gallery_model, 10, 100, "", day(1)).await;
+        let post = set_media_url(&db, post, format!("http://{}/art.png", addr)).await;
+
+        let outdir = std::env::temp_dir().join(format!("galleria-static-{}", Uuid::new_v4()));
+        render_static_site(&db, &gallery_model, &outdir, Some("https://art.example/gallery/")).await.unwrap();
+
+        let media_path = format!("media/0001_artist-100_{}.png", &post.pk.to_string()[..8]);
+        let page = std::fs::read_to_string(outdir.join("post").join(post.pk.to_string()).join("index.html")).unwrap();
+        assert!(page.contains(&format!("property=\"og:image\" content=\"https://art.example/gallery/{}\"", media_path)));
+        // The page itself still links the media relatively
+        assert!(page.contains(&format!("src=\"../../{}\"", media_path)));
+
+        std::fs::remove_dir_all(outdir).unwrap();
+    }
+}
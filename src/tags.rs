@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use regex::Regex;
+use sea_orm::{ConnectionTrait, EntityTrait, QueryFilter, QueryOrder, QueryTrait, ColumnTrait, ActiveValue, DbErr, prelude::Uuid, sea_query::OnConflict};
+use sql_entities::{gallery_post_tag, tag};
+
+/// Matches `#tag` style hashtags. Channel mentions (`<#1234>`) and HTML entities (`&#39;`) are skipped
+/// by requiring the `#` to start the text or follow something that isn't a word character, `<` or `&`.
+const HASHTAG_PATTERN: &str = r"(?:^|[^\w<&])#([\p{L}\p{N}_][\p{L}\p{N}_\-]*)";
+
+/// Extracts tags from message content.
+#[derive(Clone)]
+pub struct TagParser {
+    hashtag: Regex,
+    custom: Option<Regex>
+}
+
+impl TagParser {
+    /// Creates a parser for hashtags and, optionally, an additional tag syntax.
+    /// The custom pattern must have a capture group, the first one is used as the tag.
+    pub fn new(custom_pattern: Option<&str>) -> Result<Self> {
+        let custom = match custom_pattern {
+            Some(pattern) => {
+                let regex = Regex::new(pattern)?;
+                if regex.captures_len() < 2 {
+                    anyhow::bail!("Tag pattern {:?} needs a capture group for the tag.", pattern);
+                }
+                Some(regex)
+            },
+            None => None
+        };
+
+        Ok(TagParser {
+            hashtag: Regex::new(HASHTAG_PATTERN)?,
+            custom
+        })
+    }
+
+    /// Returns the normalized, deduplicated tags found in `content`, in order of appearance.
+    pub fn parse(&self, content: &str) -> Vec<String> {
+        let mut tags: Vec<String> = Vec::new();
+
+        let patterns = std::iter::once(&self.hashtag).chain(self.custom.iter());
+        for pattern in patterns {
+            for captures in pattern.captures_iter(content) {
+                if let Some(tag) = captures.get(1).and_then(|m| normalize_tag(m.as_str())) {
+                    if !tags.contains(&tag) {
+                        tags.push(tag);
+                    }
+                }
+            }
+        }
+
+        tags
+    }
+}
+
+/// Lowercases a tag and collapses whitespace into dashes, so `#Fan Art` and `#fan-art` are the same tag.
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let normalized = tag
+        .trim()
+        .trim_start_matches('#')
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join("-")
+        .to_lowercase();
+
+    if normalized.is_empty() {
+        None
+    } else {
+        Some(normalized)
+    }
+}
+
+/// Links every post in `posts` to each of `tags`, creating tags that don't exist yet.
+pub async fn link_tags(db: &impl ConnectionTrait, posts: &[Uuid], tags: &[String]) -> Result<(), DbErr> {
+    if posts.is_empty() || tags.is_empty() {
+        return Ok(())
+    }
+
+    // Ingesting the same new tag from two messages at once inserts it once, rather than failing on the unique name
+    let mut insert = tag::Entity::insert_many(tags.iter().map(|name| tag::ActiveModel {
+        name: ActiveValue::Set(name.clone()),
+        ..Default::default()
+    }));
+    insert.query().on_conflict(OnConflict::column(tag::Column::Name).do_nothing().to_owned());
+    db.execute(db.get_database_backend().build(insert.query())).await?;
+
+    let tag_pks = tag::Entity::find()
+        .filter(tag::Column::Name.is_in(tags.iter().map(String::as_str)))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|tag_model| tag_model.pk)
+        .collect::<Vec<Uuid>>();
+
+    let links = posts.iter().flat_map(|post| tag_pks.iter().map(move |tag| gallery_post_tag::ActiveModel {
+        post: ActiveValue::Set(*post),
+        tag: ActiveValue::Set(*tag)
+    }));
+
+    gallery_post_tag::Entity::insert_many(links).exec(db).await?;
+
+    Ok(())
+}
+
+/// Replaces the tags `posts` are linked to with `tags`.
+pub async fn replace_tags(db: &impl ConnectionTrait, posts: &[Uuid], tags: &[String]) -> Result<(), DbErr> {
+    if posts.is_empty() {
+        return Ok(())
+    }
+
+    gallery_post_tag::Entity::delete_many()
+        .filter(gallery_post_tag::Column::Post.is_in(posts.iter().copied()))
+        .exec(db)
+        .await?;
+
+    link_tags(db, posts, tags).await
+}
+
+/// Returns the names of the tags linked to any of `posts`.
+pub async fn find_post_tags(db: &impl ConnectionTrait, posts: &[Uuid]) -> Result<Vec<String>, DbErr> {
+    if posts.is_empty() {
+        return Ok(Vec::new())
+    }
+
+    let tags = tag::Entity::find()
+        .inner_join(gallery_post_tag::Entity)
+        .filter(gallery_post_tag::Column::Post.is_in(posts.iter().copied()))
+        .order_by_asc(tag::Column::Name)
+        .all(db)
+        .await?;
+
+    let mut names: Vec<String> = Vec::new();
+    for tag_model in tags {
+        if !names.contains(&tag_model.name) {
+            names.push(tag_model.name);
+        }
+    }
+    Ok(names)
+}
+
+/// Returns the names of the tags linked to each of `posts`, in alphabetical order. Posts without tags are left out.
+pub async fn find_tags_by_post(db: &impl ConnectionTrait, posts: &[Uuid]) -> Result<HashMap<Uuid, Vec<String>>, DbErr> {
+    let mut tags_by_post: HashMap<Uuid, Vec<String>> = HashMap::new();
+    if posts.is_empty() {
+        return Ok(tags_by_post)
+    }
+
+    let links = gallery_post_tag::Entity::find()
+        .find_also_related(tag::Entity)
+        .filter(gallery_post_tag::Column::Post.is_in(posts.iter().copied()))
+        .order_by_asc(tag::Column::Name)
+        .all(db)
+        .await?;
+
+    for (link, tag_model) in links {
+        if let Some(tag_model) = tag_model {
+            tags_by_post.entry(link.post).or_default().push(tag_model.name);
+        }
+    }
+
+    Ok(tags_by_post)
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::PaginatorTrait;
+
+    use super::*;
+    use crate::test_db::{self, day, insert_gallery, insert_post};
+
+    fn tags(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn hashtags_are_found_anywhere_in_the_text() {
+        let parser = TagParser::new(None).unwrap();
+
+        assert_eq!(parser.parse("#sketch of a fox #wip"), tags(&["sketch", "wip"]));
+        assert_eq!(parser.parse("(#fanart), #oc! #day_12 #2022"), tags(&["fanart", "oc", "day_12", "2022"]));
+        assert_eq!(parser.parse("#über #línea-art"), tags(&["über", "línea-art"]));
+        assert_eq!(parser.parse("no tags here, just a # and ##"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn hashtags_are_case_folded_and_deduplicated() {
+        let parser = TagParser::new(None).unwrap();
+
+        assert_eq!(parser.parse("#Fox #fox #FOX #Fox-Art"), tags(&["fox", "fox-art"]));
+    }
+
+    #[test]
+    fn mentions_entities_and_anchors_are_not_hashtags() {
+        let parser = TagParser::new(None).unwrap();
+
+        assert_eq!(parser.parse("posted in <#123456> by someone"), Vec::<String>::new());
+        assert_eq!(parser.parse("it&#39;s done"), Vec::<String>::new());
+        assert_eq!(parser.parse("see https://example.com/page#section"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn custom_patterns_add_to_hashtags() {
+        let parser = TagParser::new(Some(r"\[([^\]]+)\]")).unwrap();
+
+        assert_eq!(parser.parse("[Fan Art] #wip [wip]"), tags(&["wip", "fan-art"]));
+        assert!(TagParser::new(Some(r"\[[^\]]+\]")).is_err());
+        assert!(TagParser::new(Some(r"([")).is_err());
+    }
+
+    #[test]
+    fn normalizing_lowercases_and_joins_words() {
+        assert_eq!(normalize_tag("  Fan   Art "), Some("fan-art".to_owned()));
+        assert_eq!(normalize_tag("#OC"), Some("oc".to_owned()));
+        assert_eq!(normalize_tag("#"), None);
+        assert_eq!(normalize_tag("   "), None);
+    }
+
+    #[tokio::test]
+    async fn linking_reuses_existing_tags() {
+        let (db, _) = test_db::sqlite().await;
+        let gallery_model = insert_gallery(&db, 1, None).await;
+        let first = insert_post(&db, &gallery_model, 10, 100, "", day(1)).await;
+        let second = insert_post(&db, &gallery_model, 11, 100, "", day(2)).await;
+
+        link_tags(&db, &[first.pk], &tags(&["fox", "wip"])).await.unwrap();
+        link_tags(&db, &[second.pk], &tags(&["wip", "wip", "sketch"])).await.unwrap();
+
+        assert_eq!(tag::Entity::find().count(&db).await.unwrap(), 3);
+        assert_eq!(find_post_tags(&db, &[first.pk]).await.unwrap().len(), 2);
+        let mut second_tags = find_post_tags(&db, &[second.pk]).await.unwrap();
+        second_tags.sort();
+        assert_eq!(second_tags, tags(&["sketch", "wip"]));
+    }
+}
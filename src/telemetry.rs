@@ -0,0 +1,116 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use opentelemetry::{KeyValue, sdk::{trace, Resource}};
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::{EnvFilter, fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, including the fields of the spans it happened in.
+    Json
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {:?}, expected text or json.", s))
+        }
+    }
+}
+
+/// Exports the spans to an OpenTelemetry collector on drop, so none are lost when exiting.
+pub struct TelemetryGuard {
+    exporting: bool
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if self.exporting {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// Sets up logging, filtered by RUST_LOG, and exporting spans over OTLP when a collector endpoint is given.
+/// Logs go to stderr when stdout is used for a command's output.
+pub fn init_tracing(format: LogFormat, otlp_endpoint: Option<&str>, to_stderr: bool) -> Result<TelemetryGuard> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let writer = || if to_stderr { BoxMakeWriter::new(std::io::stderr) } else { BoxMakeWriter::new(std::io::stdout) };
+
+    let (text_layer, json_layer) = match format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer().with_writer(writer())), None),
+        LogFormat::Json => (None, Some(tracing_subscriber::fmt::layer().json().with_current_span(true).with_span_list(true).with_writer(writer())))
+    };
+
+    let otlp_layer = match otlp_endpoint {
+        Some(endpoint) => Some(tracing_opentelemetry::layer().with_tracer(otlp_tracer(endpoint)?)),
+        None => None
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text_layer)
+        .with(json_layer)
+        .with(otlp_layer)
+        .try_init()?;
+
+    Ok(TelemetryGuard { exporting: otlp_endpoint.is_some() })
+}
+
+/// Exports spans in batches to the collector at `endpoint` over OTLP/gRPC.
+fn otlp_tracer(endpoint: &str) -> Result<trace::Tracer> {
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", "galleria"),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION"))
+        ])))
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(tracer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use opentelemetry::trace::{Tracer, TracerProvider};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn log_formats_parse() {
+        assert_eq!("text".parse::<LogFormat>(), Ok(LogFormat::Text));
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert!("JSON".parse::<LogFormat>().unwrap_err().contains("expected text or json"));
+        assert!("".parse::<LogFormat>().is_err());
+    }
+
+    /// There's no collector in the tests, but one listening socket is enough to see spans being sent over gRPC.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_are_exported_to_the_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+        let tracer = otlp_tracer(&endpoint).unwrap();
+        tracer.in_span("exported", |_| {});
+
+        // Flushing waits for a response that never comes, so it's left running
+        let provider = tracer.provider().unwrap();
+        tokio::task::spawn_blocking(move || provider.force_flush());
+
+        let (mut connection, _) = tokio::time::timeout(Duration::from_secs(10), listener.accept()).await.unwrap().unwrap();
+        let mut preface = [0u8; 24];
+        tokio::time::timeout(Duration::from_secs(10), connection.read_exact(&mut preface)).await.unwrap().unwrap();
+        assert_eq!(&preface, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+    }
+}
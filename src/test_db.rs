@@ -0,0 +1,93 @@
+//! Databases and rows for tests.
+//!
+//! Every test gets its own in-memory SQLite database. Tests that also run against Postgres use the database in
+//! `TEST_DATABASE_URL`, which is emptied first, and are skipped when it isn't set.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use migration::{Migrator, MigratorTrait};
+use sea_orm::{ActiveModelTrait, ActiveModelBehavior, ActiveValue, DatabaseConnection};
+use sql_entities::{gallery, gallery_post, sea_orm_active_enums::Visibility};
+use sqlx::postgres::PgListener;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::config::{DatabaseConfig, DatabasePool};
+use crate::events::{GalleryEvent, NOTIFY_CHANNEL};
+
+/// A fresh, migrated in-memory SQLite database.
+pub async fn sqlite() -> (DatabaseConnection, DatabasePool) {
+    // Every connection to :memory: is its own database, so there may only be one
+    let (db, pool) = connect("sqlite::memory:").await;
+    Migrator::up(&db, None).await.unwrap();
+    (db, pool)
+}
+
+/// The database in `TEST_DATABASE_URL`, with every migration rolled back and applied again.
+/// Tests using it run one at a time, for as long as they hold the guard.
+pub async fn postgres() -> Option<(DatabaseConnection, DatabasePool, MutexGuard<'static, ()>)> {
+    static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+
+    let url = std::env::var("TEST_DATABASE_URL").ok()?;
+    let guard = LOCK.get_or_init(|| Mutex::new(())).lock().await;
+    let (db, pool) = connect(&url).await;
+    Migrator::refresh(&db).await.unwrap();
+    Some((db, pool, guard))
+}
+
+async fn connect(url: &str) -> (DatabaseConnection, DatabasePool) {
+    let config = DatabaseConfig { url: url.to_owned(), max_connections: 1, min_connections: 1, auto_migrate: true };
+    config.connect().await.unwrap()
+}
+
+/// Listens for the gallery events published to the database in `TEST_DATABASE_URL`.
+pub async fn listen() -> PgListener {
+    let url = std::env::var("TEST_DATABASE_URL").unwrap();
+    let mut listener = PgListener::connect(&url).await.unwrap();
+    listener.listen(NOTIFY_CHANNEL).await.unwrap();
+    listener
+}
+
+/// The next gallery event a listener receives, failing if none arrives within a few seconds.
+pub async fn next_event(listener: &mut PgListener) -> GalleryEvent {
+    let notification = tokio::time::timeout(Duration::from_secs(5), listener.recv()).await.unwrap().unwrap();
+    serde_json::from_str(notification.payload()).unwrap()
+}
+
+/// Midday on a day in September 2022.
+pub fn day(day: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2022, 9, day, 12, 0, 0).unwrap()
+}
+
+pub async fn insert_gallery(db: &DatabaseConnection, channel_id: i64, guild_id: Option<i64>) -> gallery::Model {
+    gallery::ActiveModel {
+        name: ActiveValue::Set(format!("gallery-{}", channel_id)),
+        discord_channel_id: ActiveValue::Set(channel_id),
+        discord_guild_id: ActiveValue::Set(guild_id),
+        visibility: ActiveValue::Set(Visibility::Public),
+        required_role_id: ActiveValue::Set(None),
+        ingest_error_count: ActiveValue::Set(0),
+        ..gallery::ActiveModel::new()
+    }.insert(db).await.unwrap()
+}
+
+/// Inserts a post of an attachment, as ingestion would.
+pub async fn insert_post(db: &DatabaseConnection, gallery_model: &gallery::Model, message_id: i64, author_id: i64, content: &str, date_created: DateTime<Utc>) -> gallery_post::Model {
+    let attachment_id = message_id * 10;
+    gallery_post::ActiveModel {
+        gallery: ActiveValue::Set(gallery_model.pk),
+        discord_message_id: ActiveValue::Set(message_id),
+        discord_author_id: ActiveValue::Set(Some(author_id)),
+        author_name: ActiveValue::Set(Some(format!("artist-{}", author_id))),
+        content: ActiveValue::Set(Some(content.to_owned())),
+        media_url: ActiveValue::Set(Some(format!(
+            "https://cdn.discordapp.com/attachments/{}/{}/art.png",
+            gallery_model.discord_channel_id, attachment_id
+        ))),
+        date_created: ActiveValue::Set(date_created),
+        hidden: ActiveValue::Set(false),
+        source_key: ActiveValue::Set(format!("attachment:{}", attachment_id)),
+        ..gallery_post::ActiveModel::new()
+    }.insert(db).await.unwrap()
+}
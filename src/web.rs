@@ -1,38 +1,203 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
 
-use maud::html;
-use sea_orm::{DatabaseConnection, EntityTrait, ColumnTrait, prelude::Uuid, JsonValue, query::QuerySelect};
-use serenity::http::StatusCode;
-use sql_entities::{gallery, gallery_post};
-use warp::Filter;
-use tracing::{debug, warn, error};
+use chrono::{DateTime, Utc};
+use maud::{html, Markup};
+use sea_orm::{DatabaseConnection, DbBackend, ConnectionTrait, EntityTrait, ColumnTrait, QueryFilter, QuerySelect, QueryOrder, RelationTrait, PaginatorTrait, ActiveModelTrait, ActiveValue, Condition, DbErr, FromQueryResult, JoinType, Select, Statement, prelude::Uuid, JsonValue, sea_query::{Alias, Expr, Query, SelectStatement}};
+use futures::StreamExt;
+use serde::Deserialize;
+use serenity::http::{Http, StatusCode};
+use sql_entities::{artist_optout, gallery, gallery_post, gallery_post_tag, tag, web_session, sea_orm_active_enums::Visibility};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream, errors::BroadcastStreamRecvError};
+use warp::{Filter, Reply, sse};
+use warp::http::{header, HeaderValue};
+use tracing::{debug, info, warn, error, instrument};
 
+use crate::archive::{write_gallery_archive, sanitize_file_name, ARCHIVE_CHANNEL_CAPACITY};
+use crate::auth::{DiscordAuth, csrf_token, random_token, verify_csrf_token};
+use crate::bot::Handler;
+use crate::config::Features;
+use crate::events::{EventSender, GalleryEvent};
+use crate::feed::{GalleryFeed, FEED_LENGTH};
+use crate::metrics::{GatewayStatus, Metrics};
+use crate::schema::schema_version;
+use crate::share::ShareSigner;
+use crate::shutdown::Shutdown;
+use crate::tags::{self, normalize_tag};
+
+/// Only read through Debug, when warp logs the unhandled rejection.
 #[derive(Debug)]
+#[allow(dead_code)]
 struct DbError(sea_orm::DbErr);
 impl warp::reject::Reject for DbError {}
 
-pub fn galleria_service(db: Arc<DatabaseConnection>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    frontend()
-        .or(api(db))
+pub fn galleria_service(
+    db: Arc<DatabaseConnection>,
+    base_url: String,
+    features: Features,
+    events: EventSender,
+    viewer_auth: ViewerAuth,
+    admin_context: Option<Arc<AdminContext>>,
+    shutdown: Shutdown
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let base_url = Arc::new(base_url);
+    let discord_auth = viewer_auth.discord_auth.clone();
+
+    frontend(db.clone(), base_url.clone(), features, viewer_auth.clone())
+        .or(feeds(db.clone(), base_url, features, viewer_auth.clone()))
+        .or(api(db.clone(), events, viewer_auth.clone(), shutdown))
+        .or(auth(discord_auth))
+        .or(admin(db, viewer_auth, admin_context))
         .or(warp::path("static").and(warp::fs::dir("static")))
 }
 
-fn frontend() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("gallery" / Uuid)
-        .map(|_| render_frontend_gallery_posts())
+fn frontend(db: Arc<DatabaseConnection>, base_url: Arc<String>, features: Features, viewer_auth: ViewerAuth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let gallery_page = warp::path!("gallery" / Uuid)
+        .and(with_viewer(viewer_auth.clone()))
+        .and(with_db(db.clone()))
+        .and(with_base_url(base_url.clone()))
+        .and(warp::any().map(move || features))
+        .and_then(render_frontend_gallery_posts);
+
+    let post_page = warp::path!("gallery" / Uuid / "post" / Uuid)
+        .and(with_viewer(viewer_auth))
+        .and(with_db(db.clone()))
+        .and(with_base_url(base_url.clone()))
+        .and_then(render_frontend_gallery_post);
+
+    let oembed = warp::path!("oembed")
+        .and(warp::query::<OEmbedQuery>())
+        .and(with_db(db))
+        .and(with_base_url(base_url))
+        .and_then(render_oembed);
+
+    gallery_page.or(post_page).or(oembed)
+}
+
+/// What a page looks like when its link is unfurled, as OpenGraph and Twitter card tags.
+pub struct PageMeta {
+    pub title: String,
+    pub description: String,
+    pub url: String,
+    pub image: Option<PageImage>
+}
+
+pub struct PageImage {
+    pub url: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>
+}
+
+impl PageImage {
+    pub fn from_post(post: &gallery_post::Model) -> Option<PageImage> {
+        match (&post.media_url, &post.thumbnail_url) {
+            (Some(media_url), _) => Some(PageImage { url: media_url.clone(), width: post.media_width, height: post.media_height }),
+            (None, Some(thumbnail_url)) => Some(PageImage { url: thumbnail_url.clone(), width: post.thumbnail_width, height: post.thumbnail_height }),
+            (None, None) => None
+        }
+    }
+}
+
+pub fn render_page_meta(meta: &PageMeta, oembed_base_url: Option<&str>) -> Markup {
+    let oembed_url = oembed_base_url
+        .map(|base_url| format!("{}/oembed?format=json&url={}", base_url, urlencoding::encode(&meta.url)));
+
+    html! {
+        title { (meta.title) " - Galleria" }
+        meta name="description" content=(meta.description);
+        meta property="og:site_name" content="Galleria";
+        meta property="og:type" content="website";
+        meta property="og:title" content=(meta.title);
+        meta property="og:description" content=(meta.description);
+        meta property="og:url" content=(meta.url);
+        @if let Some(image) = &meta.image {
+            meta property="og:image" content=(image.url);
+            @if let Some(width) = image.width {
+                meta property="og:image:width" content=(width);
+            }
+            @if let Some(height) = image.height {
+                meta property="og:image:height" content=(height);
+            }
+            meta name="twitter:card" content="summary_large_image";
+            meta name="twitter:image" content=(image.url);
+        } @else {
+            meta name="twitter:card" content="summary";
+        }
+        meta name="twitter:title" content=(meta.title);
+        meta name="twitter:description" content=(meta.description);
+        link rel="canonical" href=(meta.url);
+        @if let Some(oembed_url) = oembed_url {
+            link rel="alternate" type="application/json+oembed" href=(oembed_url) title=(meta.title);
+        }
+    }
 }
 
-fn render_frontend_gallery_posts() -> impl warp::Reply {
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn render_frontend_gallery_posts(gallery_id: Uuid, viewer: Viewer, db: Arc<DatabaseConnection>, base_url: Arc<String>, features: Features) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let gallery_model = match check_gallery(gallery_id, &viewer, db.as_ref()).await? {
+        GalleryAccess::Allowed(gallery_model) => gallery_model,
+        GalleryAccess::LoginRequired => return Ok(Box::new(login_redirect(&format!("/gallery/{}", gallery_id))) as Box<dyn warp::Reply>),
+        GalleryAccess::Denied => return Err(warp::reject::not_found())
+    };
+
+    let post_count = visible_posts(&gallery_model)
+        .count(db.as_ref())
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    let cover_post = gallery_posts(&gallery_model, Vec::new())
+        .filter(Condition::any()
+            .add(gallery_post::Column::MediaUrl.is_not_null())
+            .add(gallery_post::Column::ThumbnailUrl.is_not_null()))
+        .one(db.as_ref())
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    // Feed readers don't share the browser's cookies, so private feeds carry the token in their url
+    let feed_query = match viewer.share_for(gallery_model.pk) {
+        Some(share) if matches!(gallery_model.visibility, Visibility::Members | Visibility::Private) => format!("?token={}", share.token),
+        _ => String::new()
+    };
+
+    let meta = PageMeta {
+        title: gallery_model.name.clone(),
+        description: match post_count {
+            1 => "1 post".to_owned(),
+            n => format!("{} posts", n)
+        },
+        url: format!("{}/gallery/{}", base_url, gallery_model.pk),
+        image: cover_post.as_ref().and_then(PageImage::from_post)
+    };
+
     let markup = html! {
         (maud::DOCTYPE)
         html {
             head {
                 meta name="viewport" content="initial-scale=1";
+                (render_page_meta(&meta, Some(&base_url)))
+                @if gallery_model.visibility != Visibility::Public {
+                    meta name="robots" content="noindex";
+                }
                 link rel="stylesheet" href="/static/galleria.css";
+                @if features.feeds {
+                    link rel="alternate" type="application/atom+xml" href={ "/gallery/" (gallery_id) "/feed.atom" (feed_query) };
+                    link rel="alternate" type="application/rss+xml" href={ "/gallery/" (gallery_id) "/feed.rss" (feed_query) };
+                }
             }
             body {
                 header {
                     h1 { "G-alpha-ria" }
+                    @if features.downloads {
+                        a.download href={ "/gallery/" (gallery_id) "/download.zip" } download { "Download all" }
+                    }
+                    @if let Some(session) = &viewer.session {
+                        p.session {
+                            "Signed in as " (session.discord_username) " · "
+                            a href="/auth/logout" { "Sign out" }
+                        }
+                    }
                 }
                 main #app-container { }
                 script type="module" src="/static/index.mjs" {}
@@ -40,38 +205,2148 @@ fn render_frontend_gallery_posts() -> impl warp::Reply {
             }
         }
     };
-    Ok(warp::reply::with_status(warp::reply::html(markup.into_string()), StatusCode::OK))
+    let reply = warp::reply::with_status(warp::reply::html(markup.into_string()), StatusCode::OK);
+
+    // Remember the share token, so the page's API requests and later visits don't need it in the url
+    match viewer.share_for(gallery_model.pk) {
+        Some(share) => Ok(Box::new(warp::reply::with_header(reply, "set-cookie", share_cookie(share, &base_url))) as Box<dyn warp::Reply>),
+        None => Ok(Box::new(reply) as Box<dyn warp::Reply>)
+    }
 }
 
-fn api(db: Arc<DatabaseConnection>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("api" / "v1")
-        .and(warp::path!("gallery" / "posts" / Uuid)
-            .and(warp::any().map(move || db.clone()))
-            .and_then(load_posts_into_json)
-            .map(render_json_gallery_posts)
-        )
+fn share_cookie(share: &Share, base_url: &str) -> String {
+    let max_age = (share.expires - Utc::now()).num_seconds().max(0);
+    format!(
+        "{}{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        SHARE_COOKIE_PREFIX, share.gallery, share.token, max_age, secure_attribute(base_url)
+    )
 }
 
-fn render_json_gallery_posts(json: Vec<JsonValue>) -> impl warp::Reply {
-    warp::reply::json(&json)
+/// Instances served over https only send their cookies over https.
+fn secure_attribute(base_url: &str) -> &'static str {
+    if base_url.starts_with("https://") {
+        "; Secure"
+    } else {
+        ""
+    }
 }
 
-async fn load_posts_into_json(gallery_id: Uuid, db: Arc<DatabaseConnection>) -> Result<Vec<JsonValue>, warp::Rejection> {
-    if let Err(why) = gallery::Entity::find_by_id(gallery_id).one(db.as_ref()).await {
-        return match why {
-            sea_orm::DbErr::RecordNotFound(_) => Err(warp::reject()),
-            _ => Err(warp::reject::custom(DbError(why)))
-        };
+#[instrument(skip_all, fields(gallery_id = %gallery_id, post_id = %post_id))]
+async fn render_frontend_gallery_post(gallery_id: Uuid, post_id: Uuid, viewer: Viewer, db: Arc<DatabaseConnection>, base_url: Arc<String>) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let gallery_model = match check_gallery(gallery_id, &viewer, db.as_ref()).await? {
+        GalleryAccess::Allowed(gallery_model) => gallery_model,
+        GalleryAccess::LoginRequired => return Ok(Box::new(login_redirect(&format!("/gallery/{}/post/{}", gallery_id, post_id))) as Box<dyn warp::Reply>),
+        GalleryAccess::Denied => return Err(warp::reject::not_found())
+    };
+    let post = find_visible_post(&gallery_model, post_id, db.as_ref()).await?;
+    let (newer_post, older_post) = find_neighbor_posts(&gallery_model, &post, db.as_ref()).await?;
+    let post_tags = tags::find_post_tags(db.as_ref(), &[post.pk]).await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    let title = match &post.author_name {
+        Some(author_name) => format!("Art by {} in {}", author_name, gallery_model.name),
+        None => format!("Art in {}", gallery_model.name)
+    };
+
+    let meta = PageMeta {
+        title,
+        description: post.content.clone().unwrap_or_else(|| gallery_model.name.clone()),
+        url: format!("{}/gallery/{}/post/{}", base_url, gallery_model.pk, post.pk),
+        image: PageImage::from_post(&post)
+    };
+
+    let page = PostPage {
+        gallery: &gallery_model,
+        post: &post,
+        tags: &post_tags,
+        newer_post: newer_post.as_ref(),
+        older_post: older_post.as_ref(),
+        meta: &meta
+    };
+    let markup = render_post_page(&page, &PageLinks::live(gallery_model.pk), Some(&base_url));
+    Ok(Box::new(warp::reply::with_status(warp::reply::html(markup.into_string()), StatusCode::OK)) as Box<dyn warp::Reply>)
+}
+
+/// Where a page's links lead. The live site and static exports lay their pages out differently.
+pub struct PageLinks {
+    /// The gallery page.
+    pub gallery: String,
+    /// The directory `/static` assets are in.
+    pub assets: String,
+    /// Post page links are the post's pk between this prefix and suffix.
+    pub post_prefix: String,
+    pub post_suffix: String
+}
+
+impl PageLinks {
+    fn live(gallery_id: Uuid) -> Self {
+        PageLinks {
+            gallery: format!("/gallery/{}", gallery_id),
+            assets: "/static".to_owned(),
+            post_prefix: format!("/gallery/{}/post/", gallery_id),
+            post_suffix: String::new()
+        }
     }
-    
-    gallery_post::Entity::find()
-        .filter(gallery_post::Column::Gallery.eq(gallery_id))
-        .into_json()
+
+    pub fn post(&self, post_id: Uuid) -> String {
+        format!("{}{}{}", self.post_prefix, post_id, self.post_suffix)
+    }
+
+    pub fn asset(&self, name: &str) -> String {
+        format!("{}/{}", self.assets, name)
+    }
+}
+
+/// Everything shown on a post's page.
+pub struct PostPage<'a> {
+    pub gallery: &'a gallery::Model,
+    pub post: &'a gallery_post::Model,
+    pub tags: &'a [String],
+    pub newer_post: Option<&'a gallery_post::Model>,
+    pub older_post: Option<&'a gallery_post::Model>,
+    pub meta: &'a PageMeta
+}
+
+/// Renders a post's page. Without an oEmbed base url the page doesn't advertise an oEmbed endpoint.
+pub fn render_post_page(page: &PostPage, links: &PageLinks, oembed_base_url: Option<&str>) -> Markup {
+    html! {
+        (maud::DOCTYPE)
+        html {
+            head {
+                meta name="viewport" content="initial-scale=1";
+                (render_page_meta(page.meta, oembed_base_url))
+                @if page.gallery.visibility != Visibility::Public {
+                    meta name="robots" content="noindex";
+                }
+                link rel="stylesheet" href=(links.asset("galleria.css"));
+                @if let Some(newer_post) = page.newer_post {
+                    link rel="prev" href=(links.post(newer_post.pk));
+                }
+                @if let Some(older_post) = page.older_post {
+                    link rel="next" href=(links.post(older_post.pk));
+                }
+                link rel="up" href=(links.gallery);
+            }
+            body {
+                header {
+                    h1 { a href=(links.gallery) { (page.gallery.name) } }
+                }
+                main .post {
+                    // The page's own image, which may be relative unlike the one in its meta
+                    @if let Some(image) = PageImage::from_post(page.post) {
+                        a href=(image.url) rel="noreferrer" target="_blank" {
+                            img src=(image.url) alt=(page.meta.title)
+                                width=[image.width.filter(|w| *w > 0)]
+                                height=[image.height.filter(|h| *h > 0)];
+                        }
+                    }
+                    nav .post-navigation {
+                        @if let Some(newer_post) = page.newer_post {
+                            a rel="prev" href=(links.post(newer_post.pk)) { "← Newer" }
+                        }
+                        a href=(links.gallery) { "Gallery" }
+                        @if let Some(older_post) = page.older_post {
+                            a rel="next" href=(links.post(older_post.pk)) { "Older →" }
+                        }
+                    }
+                    @if let Some(author_name) = &page.post.author_name {
+                        p .post-author { (author_name) }
+                    }
+                    @if let Some(content) = &page.post.content {
+                        p .post-content { (content) }
+                    }
+                    @if !page.tags.is_empty() {
+                        ul .post-tags {
+                            @for tag_name in page.tags {
+                                li .tag { "#" (tag_name) }
+                            }
+                        }
+                    }
+                    ul .post-links {
+                        @if let Some(source_url) = &page.post.source_url {
+                            li { a href=(source_url) rel="noreferrer" target="_blank" { "Source" } }
+                        }
+                        li { a href=(discord_message_url(page.gallery, page.post.discord_message_id)) { "View in Discord" } }
+                    }
+                }
+                script type="module" src=(links.asset("post.mjs")) {}
+            }
+        }
+    }
+}
+
+/// Finds the posts shown before and after `post` in the gallery, as (newer, older).
+async fn find_neighbor_posts(
+    gallery_model: &gallery::Model,
+    post: &gallery_post::Model,
+    db: &DatabaseConnection
+) -> Result<(Option<gallery_post::Model>, Option<gallery_post::Model>), warp::Rejection> {
+    let newer_post = visible_posts(gallery_model)
+        .filter(Condition::any()
+            .add(gallery_post::Column::DateCreated.gt(post.date_created))
+            .add(Condition::all()
+                .add(gallery_post::Column::DateCreated.eq(post.date_created))
+                .add(gallery_post::Column::Pk.gt(post.pk))))
+        .order_by_asc(gallery_post::Column::DateCreated)
+        .order_by_asc(gallery_post::Column::Pk)
+        .one(db)
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    let older_post = visible_posts(gallery_model)
+        .filter(Condition::any()
+            .add(gallery_post::Column::DateCreated.lt(post.date_created))
+            .add(Condition::all()
+                .add(gallery_post::Column::DateCreated.eq(post.date_created))
+                .add(gallery_post::Column::Pk.lt(post.pk))))
+        .order_by_desc(gallery_post::Column::DateCreated)
+        .order_by_desc(gallery_post::Column::Pk)
+        .one(db)
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    Ok((newer_post, older_post))
+}
+
+/// A link that jumps to the message a post was created from.
+fn discord_message_url(gallery_model: &gallery::Model, discord_message_id: i64) -> String {
+    let guild = gallery_model.discord_guild_id
+        .map(|guild_id| guild_id.to_string())
+        .unwrap_or_else(|| "@me".to_owned());
+
+    format!("https://discord.com/channels/{}/{}/{}", guild, gallery_model.discord_channel_id, discord_message_id)
+}
+
+/// Query parameters of an oEmbed request, see <https://oembed.com/#section2.2>.
+#[derive(Debug, Deserialize)]
+struct OEmbedQuery {
+    url: String,
+    format: Option<String>,
+    maxwidth: Option<i32>,
+    maxheight: Option<i32>
+}
+
+#[instrument(skip_all, fields(url = %query.url))]
+async fn render_oembed(query: OEmbedQuery, db: Arc<DatabaseConnection>, base_url: Arc<String>) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if query.format.as_deref().unwrap_or("json") != "json" {
+        return Ok(Box::new(warp::reply::with_status("Only the json format is supported.", StatusCode::NOT_IMPLEMENTED)) as Box<dyn warp::Reply>);
+    }
+
+    // Only urls of this instance can be embedded
+    let path = query.url
+        .strip_prefix(base_url.as_str())
+        .ok_or_else(warp::reject::not_found)?;
+    let segments = path.trim_matches('/').split('/').collect::<Vec<&str>>();
+
+    let (gallery_id, post_id) = match segments.as_slice() {
+        ["gallery", gallery_id] => (parse_uuid(gallery_id)?, None),
+        ["gallery", gallery_id, "post", post_id] => (parse_uuid(gallery_id)?, Some(parse_uuid(post_id)?)),
+        _ => return Err(warp::reject::not_found())
+    };
+
+    // Private and member-only galleries can't be embedded, the oEmbed consumer has no way to present credentials
+    let gallery_model = find_gallery(gallery_id, &Viewer::default(), db.as_ref()).await?;
+    let post = match post_id {
+        Some(post_id) => Some(find_visible_post(&gallery_model, post_id, db.as_ref()).await?),
+        None => gallery_posts(&gallery_model, Vec::new())
+            .filter(gallery_post::Column::MediaUrl.is_not_null())
+            .one(db.as_ref())
+            .await
+            .map_err(|err| warp::reject::custom(DbError(err)))?
+    };
+
+    let mut response = serde_json::json!({
+        "version": "1.0",
+        "type": "link",
+        "title": gallery_model.name,
+        "provider_name": "Galleria",
+        "provider_url": base_url.as_str(),
+    });
+
+    if let Some(image) = post.as_ref().and_then(PageImage::from_post) {
+        let (width, height) = fit_within(image.width, image.height, query.maxwidth, query.maxheight);
+
+        // Photos need their size, so posts whose size isn't known stay links with a thumbnail
+        if let (Some(_), Some(width), Some(height)) = (post_id, width, height) {
+            response["type"] = "photo".into();
+            response["url"] = image.url.clone().into();
+            response["width"] = width.into();
+            response["height"] = height.into();
+        }
+        response["thumbnail_url"] = image.url.into();
+        if let Some(width) = width {
+            response["thumbnail_width"] = width.into();
+        }
+        if let Some(height) = height {
+            response["thumbnail_height"] = height.into();
+        }
+    }
+
+    if let Some(author_name) = post.as_ref().filter(|_| post_id.is_some()).and_then(|p| p.author_name.clone()) {
+        response["author_name"] = author_name.into();
+    }
+
+    Ok(Box::new(warp::reply::json(&response)) as Box<dyn warp::Reply>)
+}
+
+fn parse_uuid(s: &str) -> Result<Uuid, warp::Rejection> {
+    Uuid::parse_str(s).map_err(|_| warp::reject::not_found())
+}
+
+/// Scales image dimensions down to fit the requested bounds, keeping the aspect ratio.
+fn fit_within(width: Option<i32>, height: Option<i32>, max_width: Option<i32>, max_height: Option<i32>) -> (Option<i32>, Option<i32>) {
+    let (width, height) = match (width, height) {
+        (Some(width), Some(height)) if width > 0 && height > 0 => (width as f64, height as f64),
+        _ => return (width, height)
+    };
+
+    let scale = [
+        max_width.map(|m| m as f64 / width),
+        max_height.map(|m| m as f64 / height),
+        Some(1.0)
+    ].into_iter().flatten().fold(f64::INFINITY, f64::min);
+
+    (Some((width * scale).round() as i32), Some((height * scale).round() as i32))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FeedFormat {
+    Atom,
+    Rss
+}
+
+fn feeds(db: Arc<DatabaseConnection>, base_url: Arc<String>, features: Features, viewer_auth: ViewerAuth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let atom = warp::path!("gallery" / Uuid / "feed.atom")
+        .map(|gallery_id| (gallery_id, FeedFormat::Atom))
+        .untuple_one();
+    let rss = warp::path!("gallery" / Uuid / "feed.rss")
+        .map(|gallery_id| (gallery_id, FeedFormat::Rss))
+        .untuple_one();
+
+    let feed = atom.or(rss).unify()
+        .and(require_feature(features.feeds))
+        .and(warp::query::<PostsQuery>())
+        .and(conditional_headers())
+        .and(with_viewer(viewer_auth.clone()))
+        .and(with_db(db.clone()))
+        .and(with_base_url(base_url.clone()))
+        .and_then(render_gallery_feed);
+
+    let download = warp::path!("gallery" / Uuid / "download.zip")
+        .and(require_feature(features.downloads))
+        .and(warp::query::<PostsQuery>())
+        .and(with_viewer(viewer_auth))
+        .and(with_db(db))
+        .and(with_base_url(base_url))
+        .and_then(download_gallery_archive);
+
+    feed.or(download)
+}
+
+#[instrument(skip_all, fields(gallery_id = %gallery_id, format = ?format))]
+async fn render_gallery_feed(
+    gallery_id: Uuid,
+    format: FeedFormat,
+    query: PostsQuery,
+    conditional: ConditionalHeaders,
+    viewer: Viewer,
+    db: Arc<DatabaseConnection>,
+    base_url: Arc<String>
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let gallery_model = find_gallery(gallery_id, &viewer, db.as_ref()).await?;
+
+    let posts = gallery_posts(&gallery_model, query.tags())
+        .limit(FEED_LENGTH)
         .all(db.as_ref())
         .await
-        .map_err(|err| warp::reject::custom(DbError(err)))
-        .map(|posts| {
-            debug!("Loaded {} posts from gallery {}", posts.len(), gallery_id);
-            posts
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    let feed = GalleryFeed { gallery_url: format!("{}/gallery/{}", base_url, gallery_model.pk), gallery: &gallery_model, posts: &posts };
+    let etag = feed.etag();
+    let last_modified = feed.last_modified();
+
+    if conditional.is_not_modified(&etag, last_modified) {
+        debug!("Feed {:?} of gallery {} was not modified", format, gallery_id);
+        let reply = warp::reply::with_status(warp::reply(), StatusCode::NOT_MODIFIED);
+        let reply = warp::reply::with_header(reply, "etag", etag);
+        return Ok(Box::new(warp::reply::with_header(reply, "last-modified", to_http_date(last_modified))) as Box<dyn warp::Reply>);
+    }
+
+    let (content_type, body) = match format {
+        FeedFormat::Atom => ("application/atom+xml; charset=utf-8", feed.to_atom()),
+        FeedFormat::Rss => ("application/rss+xml; charset=utf-8", feed.to_rss())
+    };
+
+    let reply = warp::reply::with_header(body, "content-type", content_type);
+    let reply = warp::reply::with_header(reply, "etag", etag);
+    Ok(Box::new(warp::reply::with_header(reply, "last-modified", to_http_date(last_modified))) as Box<dyn warp::Reply>)
+}
+
+/// Streams a ZIP archive of a gallery's media, optionally filtered by tags, along with a manifest.
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn download_gallery_archive(
+    gallery_id: Uuid,
+    query: PostsQuery,
+    viewer: Viewer,
+    db: Arc<DatabaseConnection>,
+    base_url: Arc<String>
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let gallery_model = find_gallery(gallery_id, &viewer, db.as_ref()).await?;
+
+    // Only the rows are loaded up front, media is fetched as the archive is written
+    let mut posts = gallery_posts(&gallery_model, query.tags())
+        .filter(Condition::any()
+            .add(gallery_post::Column::MediaUrl.is_not_null())
+            .add(gallery_post::Column::ThumbnailUrl.is_not_null()))
+        .all(db.as_ref())
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    posts.reverse();
+
+    let post_pks = posts.iter().map(|post| post.pk).collect::<Vec<Uuid>>();
+    let tags_by_post = tags::find_tags_by_post(db.as_ref(), &post_pks).await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    debug!("Streaming an archive of {} posts from gallery {}", posts.len(), gallery_id);
+    let (sender, receiver) = mpsc::channel(ARCHIVE_CHANNEL_CAPACITY);
+    let file_name = match sanitize_file_name(&gallery_model.name) {
+        name if name.is_empty() => "gallery".to_owned(),
+        name => name
+    };
+    tokio::spawn(async move {
+        write_gallery_archive(&base_url, &gallery_model, posts, tags_by_post, sender).await;
+    });
+
+    let reply = warp::reply::Response::new(warp::hyper::Body::wrap_stream(ReceiverStream::new(receiver)));
+    let reply = warp::reply::with_header(reply, "content-type", "application/zip");
+    Ok(Box::new(warp::reply::with_header(reply, "content-disposition", format!("attachment; filename=\"{}.zip\"", file_name))) as Box<dyn warp::Reply>)
+}
+
+/// The conditional request headers of a feed request.
+#[derive(Debug, Default)]
+struct ConditionalHeaders {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>
+}
+
+fn conditional_headers() -> impl Filter<Extract = (ConditionalHeaders,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("if-none-match")
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .map(|if_none_match, if_modified_since| ConditionalHeaders { if_none_match, if_modified_since })
+}
+
+impl ConditionalHeaders {
+    /// `If-Modified-Since` is only considered when there's no `If-None-Match`, as RFC 7232 requires.
+    fn is_not_modified(&self, etag: &str, last_modified: DateTime<Utc>) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            return if_none_match.split(',')
+                .map(str::trim)
+                .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag.trim_start_matches("W/"));
+        }
+
+        match self.if_modified_since.as_deref().and_then(|since| DateTime::parse_from_rfc2822(since).ok()) {
+            Some(since) => last_modified.timestamp() <= since.timestamp(),
+            None => false
+        }
+    }
+}
+
+fn to_http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn api(db: Arc<DatabaseConnection>, events: EventSender, viewer_auth: ViewerAuth, shutdown: Shutdown) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let posts = warp::path!("gallery" / "posts" / Uuid)
+        .and(warp::query::<PostsQuery>())
+        .and(with_viewer(viewer_auth.clone()))
+        .and(with_db(db.clone()))
+        .and_then(load_posts_into_json)
+        .map(render_json_gallery_posts);
+
+    let tags = warp::path!("gallery" / "tags" / Uuid)
+        .and(with_viewer(viewer_auth.clone()))
+        .and(with_db(db.clone()))
+        .and_then(load_tags_into_json)
+        .map(render_json_gallery_tags);
+
+    let search = warp::path!("gallery" / Uuid / "search")
+        .and(warp::query::<SearchQuery>())
+        .and(with_viewer(viewer_auth.clone()))
+        .and(with_db(db.clone()))
+        .and_then(search_posts_into_json)
+        .map(render_json_gallery_posts);
+
+    let live_events = warp::path!("gallery" / Uuid / "events")
+        .and(with_viewer(viewer_auth))
+        .and(with_db(db))
+        .and(warp::any().map(move || events.subscribe()))
+        .and(warp::any().map(move || shutdown.clone()))
+        .and_then(stream_gallery_events);
+
+    warp::path!("api" / "v1" / ..)
+        .and(posts.or(tags).or(search).or(live_events))
+}
+
+/// Liveness and readiness probes. Readiness reports the schema version, and the gateway is only checked by processes that run the bot.
+pub fn health(db: Arc<DatabaseConnection>, gateway: Option<Arc<GatewayStatus>>, shutdown: Shutdown) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .map(|| "ok");
+
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .and(with_db(db))
+        .and(warp::any().map(move || gateway.clone()))
+        .and(warp::any().map(move || shutdown.clone()))
+        .and_then(check_readiness);
+
+    healthz.or(readyz)
+}
+
+#[instrument(level = "debug", skip_all)]
+async fn check_readiness(db: Arc<DatabaseConnection>, gateway: Option<Arc<GatewayStatus>>, shutdown: Shutdown) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let (database, schema_version) = match schema_version(db.as_ref()).await {
+        Ok(schema_version) => (true, schema_version),
+        Err(why) => {
+            warn!("Readiness check could not reach the database: {:?}", why);
+            (false, None)
+        }
+    };
+    let gateway = gateway.map(|gateway| gateway.is_connected());
+    let shutting_down = shutdown.is_shutting_down();
+
+    let ready = database && gateway.unwrap_or(true) && !shutting_down;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({
+        "ready": ready,
+        "database": database,
+        "schema_version": schema_version,
+        "gateway": gateway,
+        "shutting_down": shutting_down
+    })), status)) as Box<dyn warp::Reply>)
+}
+
+/// Prometheus metrics, meant to be served on a private address.
+pub fn metrics_endpoint(metrics: Arc<Metrics>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .map(move || warp::reply::with_header(
+            metrics.render(),
+            header::CONTENT_TYPE,
+            prometheus::TEXT_FORMAT
+        ))
+}
+
+/// What the admin area needs besides the database: who may use it, and the bot to resync galleries with.
+pub struct AdminContext {
+    pub user_ids: Vec<u64>,
+    pub handler: Arc<Handler>,
+    pub http: Arc<Http>,
+    /// Galleries with a resync in progress.
+    pub resyncing: Mutex<HashSet<Uuid>>
+}
+
+/// The number of posts listed per page of a gallery's admin page.
+const ADMIN_POSTS_PAGE_SIZE: usize = 100;
+
+fn admin(db: Arc<DatabaseConnection>, viewer_auth: ViewerAuth, admin_context: Option<Arc<AdminContext>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    // The admin area is only available when admins can sign in
+    let admin_context = admin_context.filter(|_| viewer_auth.discord_auth.is_some());
+
+    let with_admin_context = warp::any()
+        .and_then(move || {
+            let admin_context = admin_context.clone();
+            async move { admin_context.ok_or_else(warp::reject::not_found) }
+        });
+
+    let with_admin_session = with_viewer(viewer_auth.clone())
+        .and(with_admin_context.clone())
+        .and_then(|viewer: Viewer, admin_context: Arc<AdminContext>| async move {
+            match viewer.session {
+                Some(session) if admin_context.user_ids.contains(&(session.discord_user_id as u64)) => Ok((admin_context, session)),
+                _ => Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one();
+    let with_admin = with_admin_session.clone()
+        .map(|admin_context: Arc<AdminContext>, _: web_session::Model| admin_context);
+
+    let dashboard = warp::path!("admin")
+        .and(warp::get())
+        .and(with_admin.clone())
+        .and(with_db(db.clone()))
+        .and_then(render_admin_dashboard);
+
+    // Anyone else visiting the admin area is asked to sign in first
+    let dashboard_login = warp::path!("admin")
+        .and(warp::get())
+        .and(with_viewer(viewer_auth))
+        .and(with_admin_context)
+        .and_then(|viewer: Viewer, _: Arc<AdminContext>| async move {
+            match viewer.session {
+                Some(_) => Err(warp::reject::not_found()),
+                None => Ok(login_redirect("/admin"))
+            }
+        });
+
+    let gallery_page = warp::path!("admin" / "gallery" / Uuid)
+        .and(warp::get())
+        .and(warp::query::<AdminPostsQuery>())
+        .and(with_admin_session.clone())
+        .and(with_db(db.clone()))
+        .and_then(render_admin_gallery);
+
+    let rename = warp::path!("admin" / "gallery" / Uuid / "rename")
+        .and(warp::post())
+        .and(admin_form::<RenameForm, _>(with_admin_session.clone()))
+        .and(with_db(db.clone()))
+        .and_then(rename_gallery);
+
+    let visibility = warp::path!("admin" / "gallery" / Uuid / "visibility")
+        .and(warp::post())
+        .and(admin_form::<VisibilityForm, _>(with_admin_session.clone()))
+        .and(with_db(db.clone()))
+        .and_then(set_gallery_visibility);
+
+    let resync = warp::path!("admin" / "gallery" / Uuid / "resync")
+        .and(warp::post())
+        .and(admin_form::<CsrfForm, _>(with_admin_session.clone()))
+        .and(with_db(db.clone()))
+        .and_then(start_gallery_resync);
+
+    let delete = warp::path!("admin" / "gallery" / Uuid / "delete")
+        .and(warp::post())
+        .and(admin_form::<DeleteForm, _>(with_admin_session.clone()))
+        .and(with_db(db.clone()))
+        .and_then(delete_gallery);
+
+    let hide_post = warp::path!("admin" / "gallery" / Uuid / "post" / Uuid / "hidden")
+        .and(warp::post())
+        .and(admin_form::<HidePostForm, _>(with_admin_session))
+        .and(with_db(db))
+        .and_then(set_post_hidden);
+
+    dashboard.or(dashboard_login)
+        .or(gallery_page)
+        .or(rename)
+        .or(visibility)
+        .or(resync)
+        .or(delete)
+        .or(hide_post)
+        .recover(|rejection: warp::Rejection| async move {
+            match rejection.find::<CsrfMismatch>() {
+                Some(_) => Ok(warp::reply::with_status("The form expired, please go back, reload and try again.", StatusCode::FORBIDDEN)),
+                None => Err(rejection)
+            }
+        })
+}
+
+/// Reads the form of an admin action, checking its CSRF token belongs to the admin's session.
+/// Signing in uses a SameSite=Lax cookie, which other sites can still send along with forms in some browsers.
+fn admin_form<T: AdminForm + serde::de::DeserializeOwned + Send + 'static, C: Send + 'static>(
+    with_admin_session: impl Filter<Extract = (C, web_session::Model), Error = warp::Rejection> + Clone + Send + Sync
+) -> impl Filter<Extract = (T, C), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(4096)
+        .and(warp::body::form())
+        .and(with_admin_session)
+        .and_then(|form: T, admin_context: C, session: web_session::Model| async move {
+            if verify_csrf_token(&session, form.csrf_token()) {
+                Ok((form, admin_context))
+            } else {
+                warn!("Admin form sent by user {} with a wrong CSRF token", session.discord_user_id);
+                Err(warp::reject::custom(CsrfMismatch))
+            }
         })
+        .untuple_one()
+}
+
+#[derive(Debug)]
+struct CsrfMismatch;
+
+impl warp::reject::Reject for CsrfMismatch {}
+
+/// Forms of the admin area, which all carry a CSRF token.
+trait AdminForm {
+    fn csrf_token(&self) -> &str;
+}
+
+impl AdminForm for CsrfForm {
+    fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+}
+
+impl AdminForm for RenameForm {
+    fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+}
+
+impl AdminForm for VisibilityForm {
+    fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+}
+
+impl AdminForm for DeleteForm {
+    fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+}
+
+impl AdminForm for HidePostForm {
+    fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminPostsQuery {
+    page: Option<usize>
+}
+
+/// The form of actions without fields of their own.
+#[derive(Debug, Deserialize)]
+struct CsrfForm {
+    csrf_token: String
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameForm {
+    csrf_token: String,
+    name: String
+}
+
+#[derive(Debug, Deserialize)]
+struct VisibilityForm {
+    csrf_token: String,
+    visibility: String
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteForm {
+    csrf_token: String,
+    /// The gallery's name, typed again to confirm.
+    confirm_name: String
+}
+
+#[derive(Debug, Deserialize)]
+struct HidePostForm {
+    csrf_token: String,
+    hidden: bool,
+    page: Option<usize>
+}
+
+/// Post counts and storage used by a gallery's posts.
+#[derive(Debug, FromQueryResult)]
+struct GalleryStats {
+    gallery: Uuid,
+    posts: i64,
+    hidden_posts: i64,
+    bytes: i64
+}
+
+#[instrument(level = "debug", skip_all, err)]
+async fn find_gallery_stats(db: &DatabaseConnection) -> Result<HashMap<Uuid, GalleryStats>, DbErr> {
+    let sql = match db.get_database_backend() {
+        DbBackend::Postgres => r#"
+            SELECT
+                "gallery",
+                COUNT(*) AS "posts",
+                COUNT(*) FILTER (WHERE "hidden") AS "hidden_posts",
+                COALESCE(SUM(pg_column_size("gallery_post".*)), 0)::BIGINT AS "bytes"
+            FROM "gallery_post"
+            GROUP BY "gallery"
+        "#,
+        // SQLite can't tell the size of rows, the text they hold is most of it
+        _ => r#"
+            SELECT
+                "gallery",
+                COUNT(*) AS "posts",
+                COUNT(*) FILTER (WHERE "hidden") AS "hidden_posts",
+                COALESCE(SUM(
+                    LENGTH(COALESCE("content", '')) + LENGTH(COALESCE("author_name", '')) + LENGTH(COALESCE("source_url", '')) +
+                    LENGTH(COALESCE("media_url", '')) + LENGTH(COALESCE("thumbnail_url", ''))
+                ), 0) AS "bytes"
+            FROM "gallery_post"
+            GROUP BY "gallery"
+        "#
+    };
+
+    let stats = GalleryStats::find_by_statement(Statement::from_string(db.get_database_backend(), sql.to_owned()))
+        .all(db)
+        .await?;
+
+    Ok(stats.into_iter().map(|stats| (stats.gallery, stats)).collect())
+}
+
+#[instrument(level = "debug", skip_all, err)]
+async fn find_database_size(db: &DatabaseConnection) -> Result<i64, DbErr> {
+    let sql = match db.get_database_backend() {
+        DbBackend::Postgres => "SELECT pg_database_size(current_database()) AS size",
+        _ => "SELECT page_count * page_size AS size FROM pragma_page_count(), pragma_page_size()"
+    };
+    let row = db.query_one(Statement::from_string(db.get_database_backend(), sql.to_owned())).await?;
+
+    match row {
+        Some(row) => row.try_get("", "size"),
+        None => Ok(0)
+    }
+}
+
+fn format_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next_unit in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+fn visibility_name(visibility: Visibility) -> &'static str {
+    match visibility {
+        Visibility::Public => "public",
+        Visibility::Unlisted => "unlisted",
+        Visibility::Members => "members",
+        Visibility::Private => "private"
+    }
+}
+
+fn render_admin_page(title: &str, content: Markup) -> Markup {
+    html! {
+        (maud::DOCTYPE)
+        html {
+            head {
+                meta name="viewport" content="initial-scale=1";
+                meta name="robots" content="noindex";
+                title { (title) " · Galleria admin" }
+                link rel="stylesheet" href="/static/galleria.css";
+            }
+            body {
+                header {
+                    h1 { a href="/admin" { "G-alpha-ria admin" } }
+                    p.session { a href="/auth/logout" { "Sign out" } }
+                }
+                main.admin { (content) }
+            }
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn render_admin_dashboard(admin_context: Arc<AdminContext>, db: Arc<DatabaseConnection>) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let galleries = gallery::Entity::find()
+        .order_by_asc(gallery::Column::Name)
+        .all(db.as_ref())
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    let stats = find_gallery_stats(db.as_ref()).await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    let database_size = find_database_size(db.as_ref()).await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    let resyncing = admin_context.resyncing.lock().unwrap().clone();
+
+    let total_posts: i64 = stats.values().map(|stats| stats.posts).sum();
+
+    let markup = render_admin_page("Galleries", html! {
+        h2 { "Galleries" }
+        p {
+            (galleries.len()) " galleries with " (total_posts) " posts. "
+            "The database uses " (format_bytes(database_size)) "."
+        }
+        table.admin-table {
+            thead {
+                tr {
+                    th { "Name" }
+                    th { "Visibility" }
+                    th { "Posts" }
+                    th { "Hidden" }
+                    th { "Storage" }
+                    th { "Last ingested" }
+                    th { "Errors" }
+                }
+            }
+            tbody {
+                @for gallery_model in &galleries {
+                    @let gallery_stats = stats.get(&gallery_model.pk);
+                    tr {
+                        td { a href={ "/admin/gallery/" (gallery_model.pk) } { (gallery_model.name) } }
+                        td { (visibility_name(gallery_model.visibility)) }
+                        td { (gallery_stats.map(|stats| stats.posts).unwrap_or(0)) }
+                        td { (gallery_stats.map(|stats| stats.hidden_posts).unwrap_or(0)) }
+                        td { (format_bytes(gallery_stats.map(|stats| stats.bytes).unwrap_or(0))) }
+                        td {
+                            @if resyncing.contains(&gallery_model.pk) {
+                                "Resyncing…"
+                            } @else if let Some(date_last_ingested) = gallery_model.date_last_ingested {
+                                (date_last_ingested.format("%Y-%m-%d %H:%M UTC").to_string())
+                            } @else {
+                                "Never"
+                            }
+                        }
+                        td title=[gallery_model.last_ingest_error.as_deref()] { (gallery_model.ingest_error_count) }
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(Box::new(warp::reply::html(markup.into_string())) as Box<dyn warp::Reply>)
+}
+
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn render_admin_gallery(gallery_id: Uuid, query: AdminPostsQuery, admin_context: Arc<AdminContext>, session: web_session::Model, db: Arc<DatabaseConnection>) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let gallery_model = find_admin_gallery(gallery_id, db.as_ref()).await?;
+    let page = query.page.unwrap_or(0);
+
+    let paginator = gallery_post::Entity::find()
+        .filter(gallery_post::Column::Gallery.eq(gallery_id))
+        .order_by_desc(gallery_post::Column::DateCreated)
+        .order_by_desc(gallery_post::Column::Pk)
+        .paginate(db.as_ref(), ADMIN_POSTS_PAGE_SIZE);
+    let page_count = paginator.num_pages().await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    let posts = paginator.fetch_page(page).await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    let is_resyncing = admin_context.resyncing.lock().unwrap().contains(&gallery_id);
+    let newer_page = page.saturating_sub(1);
+    let older_page = page + 1;
+    let csrf_token = csrf_token(&session);
+
+    let markup = render_admin_page(&gallery_model.name, html! {
+        h2 { (gallery_model.name) }
+        p {
+            a href={ "/gallery/" (gallery_model.pk) } { "View gallery" }
+            " · "
+            @if let Some(date_last_ingested) = gallery_model.date_last_ingested {
+                "Last ingested " (date_last_ingested.format("%Y-%m-%d %H:%M UTC").to_string()) ". "
+            }
+            (gallery_model.ingest_error_count) " ingestion errors."
+        }
+        @if let Some(last_ingest_error) = &gallery_model.last_ingest_error {
+            pre.admin-error { (last_ingest_error) }
+        }
+
+        section.admin-actions {
+            form method="post" action={ "/admin/gallery/" (gallery_id) "/rename" } {
+                input type="hidden" name="csrf_token" value=(csrf_token);
+                input type="text" name="name" value=(gallery_model.name) required;
+                button type="submit" { "Rename" }
+            }
+            form method="post" action={ "/admin/gallery/" (gallery_id) "/visibility" } {
+                input type="hidden" name="csrf_token" value=(csrf_token);
+                select name="visibility" {
+                    @for visibility in [Visibility::Public, Visibility::Unlisted, Visibility::Members, Visibility::Private] {
+                        option value=(visibility_name(visibility)) selected[visibility == gallery_model.visibility] { (visibility_name(visibility)) }
+                    }
+                }
+                button type="submit" { "Change visibility" }
+            }
+            form method="post" action={ "/admin/gallery/" (gallery_id) "/resync" } {
+                input type="hidden" name="csrf_token" value=(csrf_token);
+                button type="submit" disabled[is_resyncing] {
+                    @if is_resyncing { "Resyncing…" } @else { "Resync from Discord" }
+                }
+            }
+            form method="post" action={ "/admin/gallery/" (gallery_id) "/delete" } {
+                input type="hidden" name="csrf_token" value=(csrf_token);
+                input type="text" name="confirm_name" placeholder="Type the name to confirm" required;
+                button type="submit" { "Delete gallery" }
+            }
+        }
+
+        h3 { "Posts" }
+        ul.admin-posts {
+            @for post in &posts {
+                li.hidden-post[post.hidden] {
+                    @if let Some(image) = PageImage::from_post(post) {
+                        a href={ "/gallery/" (gallery_id) "/post/" (post.pk) } {
+                            img src=(image.url) loading="lazy" alt="";
+                        }
+                    }
+                    span { (post.author_name.as_deref().unwrap_or("Unknown artist")) }
+                    form method="post" action={ "/admin/gallery/" (gallery_id) "/post/" (post.pk) "/hidden" } {
+                        input type="hidden" name="csrf_token" value=(csrf_token);
+                        input type="hidden" name="hidden" value=(if post.hidden { "false" } else { "true" });
+                        input type="hidden" name="page" value=(page);
+                        button type="submit" { @if post.hidden { "Unhide" } @else { "Hide" } }
+                    }
+                }
+            }
+        }
+        nav.post-navigation {
+            @if page > 0 {
+                a href={ "/admin/gallery/" (gallery_id) "?page=" (newer_page) } { "Newer" }
+            }
+            @if older_page < page_count {
+                a href={ "/admin/gallery/" (gallery_id) "?page=" (older_page) } { "Older" }
+            }
+        }
+    });
+
+    Ok(Box::new(warp::reply::html(markup.into_string())) as Box<dyn warp::Reply>)
+}
+
+async fn find_admin_gallery(gallery_id: Uuid, db: &DatabaseConnection) -> Result<gallery::Model, warp::Rejection> {
+    match gallery::Entity::find_by_id(gallery_id).one(db).await {
+        Ok(Some(gallery_model)) => Ok(gallery_model),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(why) => Err(warp::reject::custom(DbError(why)))
+    }
+}
+
+fn admin_gallery_redirect(gallery_id: Uuid) -> warp::reply::Response {
+    redirect_response(&format!("/admin/gallery/{}", gallery_id), &[])
+}
+
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn rename_gallery(gallery_id: Uuid, form: RenameForm, _admin_context: Arc<AdminContext>, db: Arc<DatabaseConnection>) -> Result<warp::reply::Response, warp::Rejection> {
+    let name = form.name.trim();
+    if name.is_empty() {
+        return Ok(warp::reply::with_status("The name can't be empty.", StatusCode::BAD_REQUEST).into_response());
+    }
+
+    let mut gallery_active_model: gallery::ActiveModel = find_admin_gallery(gallery_id, db.as_ref()).await?.into();
+    gallery_active_model.name = ActiveValue::Set(name.to_owned());
+    gallery_active_model.update(db.as_ref()).await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    info!("Renamed gallery {} to {:?}.", gallery_id, name);
+
+    Ok(admin_gallery_redirect(gallery_id))
+}
+
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn set_gallery_visibility(gallery_id: Uuid, form: VisibilityForm, _admin_context: Arc<AdminContext>, db: Arc<DatabaseConnection>) -> Result<warp::reply::Response, warp::Rejection> {
+    let visibility = match form.visibility.as_str() {
+        "public" => Visibility::Public,
+        "unlisted" => Visibility::Unlisted,
+        "members" => Visibility::Members,
+        "private" => Visibility::Private,
+        _ => return Ok(warp::reply::with_status("Unknown visibility.", StatusCode::BAD_REQUEST).into_response())
+    };
+
+    let mut gallery_active_model: gallery::ActiveModel = find_admin_gallery(gallery_id, db.as_ref()).await?.into();
+    gallery_active_model.visibility = ActiveValue::Set(visibility);
+    gallery_active_model.update(db.as_ref()).await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    info!("Gallery {} is now {:?}.", gallery_id, visibility);
+
+    Ok(admin_gallery_redirect(gallery_id))
+}
+
+/// Starts resyncing a gallery in the background. Its progress shows on the admin pages.
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn start_gallery_resync(gallery_id: Uuid, _form: CsrfForm, admin_context: Arc<AdminContext>, db: Arc<DatabaseConnection>) -> Result<warp::reply::Response, warp::Rejection> {
+    let gallery_model = find_admin_gallery(gallery_id, db.as_ref()).await?;
+
+    if !admin_context.resyncing.lock().unwrap().insert(gallery_id) {
+        debug!("Gallery {} is already resyncing", gallery_id);
+        return Ok(admin_gallery_redirect(gallery_id));
+    }
+
+    tokio::spawn(async move {
+        if let Err(why) = admin_context.handler.resync_gallery(&admin_context.http, &gallery_model).await {
+            error!("Error resyncing gallery {}: {:?}", gallery_model.pk, why);
+        }
+        admin_context.resyncing.lock().unwrap().remove(&gallery_model.pk);
+    });
+
+    Ok(admin_gallery_redirect(gallery_id))
+}
+
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn delete_gallery(gallery_id: Uuid, form: DeleteForm, _admin_context: Arc<AdminContext>, db: Arc<DatabaseConnection>) -> Result<warp::reply::Response, warp::Rejection> {
+    let gallery_model = find_admin_gallery(gallery_id, db.as_ref()).await?;
+    if form.confirm_name.trim() != gallery_model.name {
+        return Ok(warp::reply::with_status("The name doesn't match the gallery's.", StatusCode::BAD_REQUEST).into_response());
+    }
+
+    // Posts and their tags are deleted along with the gallery
+    gallery::Entity::delete_by_id(gallery_id)
+        .exec(db.as_ref())
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    info!("Deleted gallery {} ({:?}).", gallery_id, gallery_model.name);
+
+    Ok(redirect_response("/admin", &[]))
+}
+
+#[instrument(skip_all, fields(gallery_id = %gallery_id, post_id = %post_id))]
+async fn set_post_hidden(gallery_id: Uuid, post_id: Uuid, form: HidePostForm, _admin_context: Arc<AdminContext>, db: Arc<DatabaseConnection>) -> Result<warp::reply::Response, warp::Rejection> {
+    let result = gallery_post::Entity::update_many()
+        .col_expr(gallery_post::Column::Hidden, Expr::value(form.hidden))
+        .filter(gallery_post::Column::Gallery.eq(gallery_id))
+        .filter(gallery_post::Column::Pk.eq(post_id))
+        .exec(db.as_ref())
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    if result.rows_affected == 0 {
+        return Err(warp::reject::not_found());
+    }
+    info!("Post {} of gallery {} is now {}.", post_id, gallery_id, if form.hidden { "hidden" } else { "shown" });
+
+    Ok(redirect_response(&format!("/admin/gallery/{}?page={}", gallery_id, form.page.unwrap_or(0)), &[]))
+}
+
+const SESSION_COOKIE: &str = "galleria_session";
+
+/// Holds the OAuth2 state and where to return to while the viewer is signing in.
+const LOGIN_COOKIE: &str = "galleria_login";
+
+fn auth(discord_auth: Option<Arc<DiscordAuth>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    // Without OAuth2 credentials there's no signing in, and member-only galleries can only be shared
+    let with_discord_auth = warp::any()
+        .and_then(move || {
+            let discord_auth = discord_auth.clone();
+            async move { discord_auth.ok_or_else(warp::reject::not_found) }
+        });
+
+    let login = warp::path!("auth" / "login")
+        .and(warp::query::<LoginQuery>())
+        .and(with_discord_auth.clone())
+        .map(start_login);
+
+    let callback = warp::path!("auth" / "callback")
+        .and(warp::query::<CallbackQuery>())
+        .and(warp::header::optional::<String>("cookie"))
+        .and(with_discord_auth.clone())
+        .and_then(finish_login);
+
+    let logout = warp::path!("auth" / "logout")
+        .and(warp::header::optional::<String>("cookie"))
+        .and(with_discord_auth)
+        .and_then(logout);
+
+    login.or(callback).or(logout)
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    redirect: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>
+}
+
+/// Sends the viewer to the provider's consent screen.
+fn start_login(query: LoginQuery, discord_auth: Arc<DiscordAuth>) -> warp::reply::Response {
+    let redirect = local_path(query.redirect.as_deref().unwrap_or("/"));
+
+    // Paths may contain `;` and `,`, which would end the cookie's value
+    let state = random_token();
+    let login_cookie = format!(
+        "{}={}.{}; Path=/auth; Max-Age=600; HttpOnly; SameSite=Lax{}",
+        LOGIN_COOKIE, state, urlencoding::encode(redirect), secure_attribute(discord_auth.redirect_url())
+    );
+
+    redirect_response(&discord_auth.authorize_url(&state), &[login_cookie])
+}
+
+#[instrument(skip_all)]
+async fn finish_login(query: CallbackQuery, cookies: Option<String>, discord_auth: Arc<DiscordAuth>) -> Result<warp::reply::Response, warp::Rejection> {
+    let cookies = parse_cookies(cookies.as_deref());
+    let login = cookies.iter()
+        .find(|(name, _)| *name == LOGIN_COOKIE)
+        .and_then(|(_, login)| login.split_once('.'));
+    let clear_login_cookie = format!("{}=; Path=/auth; Max-Age=0", LOGIN_COOKIE);
+
+    let (expected_state, redirect) = match login {
+        Some((state, redirect)) => (state, urlencoding::decode(redirect).unwrap_or_default()),
+        None => return Ok(warp::reply::with_status("Sign in expired, please try again.", StatusCode::BAD_REQUEST).into_response())
+    };
+    let redirect = local_path(&redirect);
+
+    if let Some(error) = query.error {
+        debug!("Sign in was not completed: {}", error);
+        return Ok(redirect_response(redirect, &[clear_login_cookie]));
+    }
+
+    let code = match (query.code, query.state) {
+        (Some(code), Some(state)) if state == expected_state => code,
+        _ => {
+            warn!("Sign in callback with a missing or mismatched state");
+            return Ok(warp::reply::with_status("Sign in failed, please try again.", StatusCode::BAD_REQUEST).into_response())
+        }
+    };
+
+    let session = match discord_auth.login(&code).await {
+        Ok(session) => session,
+        Err(why) => {
+            error!("Error signing in: {:?}", why);
+            return Ok(warp::reply::with_status("Sign in failed, please try again.", StatusCode::BAD_GATEWAY).into_response())
+        }
+    };
+
+    let session_cookie = session_cookie(&session, discord_auth.redirect_url());
+    Ok(redirect_response(redirect, &[session_cookie, clear_login_cookie]))
+}
+
+fn session_cookie(session: &web_session::Model, base_url: &str) -> String {
+    let max_age = (session.date_expires - Utc::now()).num_seconds().max(0);
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        SESSION_COOKIE, session.pk, max_age, secure_attribute(base_url)
+    )
+}
+
+#[instrument(skip_all)]
+async fn logout(cookies: Option<String>, discord_auth: Arc<DiscordAuth>) -> Result<warp::reply::Response, warp::Rejection> {
+    let cookies = parse_cookies(cookies.as_deref());
+    if let Some((_, session_id)) = cookies.iter().find(|(name, _)| *name == SESSION_COOKIE) {
+        if let Err(why) = discord_auth.logout(session_id).await {
+            error!("Error signing out: {:?}", why);
+        }
+    }
+
+    let clear_session_cookie = format!("{}=; Path=/; Max-Age=0", SESSION_COOKIE);
+    Ok(redirect_response("/", &[clear_session_cookie]))
+}
+
+/// Only paths on this instance are returned to after signing in, so the login can't be used as an open redirect.
+fn local_path(redirect: &str) -> &str {
+    if redirect.starts_with('/') && !redirect.starts_with("//") && redirect.chars().all(|c| c.is_ascii_graphic()) {
+        redirect
+    } else {
+        "/"
+    }
+}
+
+/// Sends the viewer to sign in, returning to `path` afterwards.
+fn login_redirect(path: &str) -> warp::reply::Response {
+    redirect_response(&format!("/auth/login?redirect={}", urlencoding::encode(path)), &[])
+}
+
+fn redirect_response(location: &str, cookies: &[String]) -> warp::reply::Response {
+    let mut response = warp::reply::with_status(warp::reply(), StatusCode::SEE_OTHER).into_response();
+    let headers = response.headers_mut();
+
+    headers.insert(header::LOCATION, HeaderValue::from_str(location).unwrap_or_else(|_| HeaderValue::from_static("/")));
+    for cookie in cookies {
+        if let Ok(cookie) = HeaderValue::from_str(cookie) {
+            headers.append(header::SET_COOKIE, cookie);
+        }
+    }
+
+    response
+}
+
+fn with_db(db: Arc<DatabaseConnection>) -> impl Filter<Extract = (Arc<DatabaseConnection>,), Error = Infallible> + Clone {
+    warp::any().map(move || db.clone())
+}
+
+/// Collects the credentials a request presents: share tokens, from the `token` query parameter and share cookies,
+/// and the viewer's login session.
+fn with_viewer(viewer_auth: ViewerAuth) -> impl Filter<Extract = (Viewer,), Error = warp::Rejection> + Clone {
+    warp::query::<ShareQuery>()
+        .and(warp::header::optional::<String>("cookie"))
+        .and_then(move |query: ShareQuery, cookies: Option<String>| {
+            let viewer_auth = viewer_auth.clone();
+            async move {
+                let cookies = parse_cookies(cookies.as_deref());
+
+                let shares = match &viewer_auth.share_signer {
+                    Some(share_signer) => {
+                        let cookie_tokens = cookies.iter()
+                            .filter(|(name, _)| name.starts_with(SHARE_COOKIE_PREFIX))
+                            .map(|(_, token)| token.to_string());
+
+                        let now = Utc::now();
+                        query.token.into_iter()
+                            .chain(cookie_tokens)
+                            .filter_map(|token| share_signer.verify(&token, now).map(|(gallery, expires)| Share { gallery, token, expires }))
+                            .collect()
+                    },
+                    None => Vec::new()
+                };
+
+                let session_id = cookies.iter()
+                    .find(|(name, _)| *name == SESSION_COOKIE)
+                    .map(|(_, session_id)| *session_id);
+                let session = match (&viewer_auth.discord_auth, session_id) {
+                    (Some(discord_auth), Some(session_id)) => discord_auth.find_session(session_id).await
+                        .unwrap_or_else(|why| {
+                            error!("Error loading session: {:?}", why);
+                            None
+                        }),
+                    _ => None
+                };
+
+                Ok::<_, warp::Rejection>(Viewer { shares, session, discord_auth: viewer_auth.discord_auth })
+            }
+        })
+}
+
+fn parse_cookies(cookies: Option<&str>) -> Vec<(&str, &str)> {
+    cookies.iter()
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .collect()
+}
+
+fn with_base_url(base_url: Arc<String>) -> impl Filter<Extract = (Arc<String>,), Error = Infallible> + Clone {
+    warp::any().map(move || base_url.clone())
+}
+
+/// Turned off features are not found.
+fn require_feature(enabled: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+const SHARE_COOKIE_PREFIX: &str = "galleria_share_";
+
+#[derive(Debug, Deserialize)]
+struct ShareQuery {
+    token: Option<String>
+}
+
+/// A verified share token.
+#[derive(Debug, Clone)]
+struct Share {
+    gallery: Uuid,
+    token: String,
+    expires: DateTime<Utc>
+}
+
+/// What the web service verifies viewers' credentials with.
+#[derive(Clone)]
+pub struct ViewerAuth {
+    pub share_signer: Option<Arc<ShareSigner>>,
+    pub discord_auth: Option<Arc<DiscordAuth>>
+}
+
+/// The credentials a request presents for viewing private and member-only galleries.
+#[derive(Clone, Default)]
+struct Viewer {
+    shares: Vec<Share>,
+    session: Option<web_session::Model>,
+    discord_auth: Option<Arc<DiscordAuth>>
+}
+
+impl Viewer {
+    async fn can_view(&self, gallery_model: &gallery::Model) -> bool {
+        match gallery_model.visibility {
+            Visibility::Public | Visibility::Unlisted => true,
+            Visibility::Members => self.share_for(gallery_model.pk).is_some() || self.is_member(gallery_model).await,
+            Visibility::Private => self.share_for(gallery_model.pk).is_some()
+        }
+    }
+
+    async fn is_member(&self, gallery_model: &gallery::Model) -> bool {
+        match (&self.discord_auth, &self.session) {
+            (Some(discord_auth), Some(session)) => discord_auth.can_view(session, gallery_model).await,
+            _ => false
+        }
+    }
+
+    /// Whether signing in could let the viewer see a gallery they can't see now.
+    fn could_sign_in(&self, gallery_model: &gallery::Model) -> bool {
+        gallery_model.visibility == Visibility::Members && self.session.is_none() && self.discord_auth.is_some()
+    }
+
+    fn share_for(&self, gallery_id: Uuid) -> Option<&Share> {
+        self.shares.iter().find(|share| share.gallery == gallery_id)
+    }
+}
+
+/// Query parameters accepted by the posts endpoint.
+#[derive(Debug, Default, Deserialize)]
+struct PostsQuery {
+    /// Comma separated list of tags. Only posts with every tag are returned.
+    tags: Option<String>
+}
+
+impl PostsQuery {
+    fn tags(&self) -> Vec<String> {
+        parse_tags_param(self.tags.as_deref())
+    }
+}
+
+/// Query parameters accepted by the search endpoint.
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    /// Search terms, in the syntax accepted by Postgres' `websearch_to_tsquery`.
+    /// On SQLite posts have to contain every word instead.
+    q: String,
+    /// Comma separated list of tags. Only posts with every tag are returned.
+    tags: Option<String>
+}
+
+impl SearchQuery {
+    fn tags(&self) -> Vec<String> {
+        parse_tags_param(self.tags.as_deref())
+    }
+}
+
+fn parse_tags_param(tags: Option<&str>) -> Vec<String> {
+    tags.unwrap_or_default()
+        .split(',')
+        .filter_map(normalize_tag)
+        .collect()
+}
+
+fn render_json_gallery_posts(json: Vec<JsonValue>) -> impl warp::Reply {
+    warp::reply::json(&json)
+}
+
+fn render_json_gallery_tags(json: Vec<JsonValue>) -> impl warp::Reply {
+    warp::reply::json(&json)
+}
+
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn load_posts_into_json(gallery_id: Uuid, query: PostsQuery, viewer: Viewer, db: Arc<DatabaseConnection>) -> Result<Vec<JsonValue>, warp::Rejection> {
+    let gallery_model = find_gallery(gallery_id, &viewer, db.as_ref()).await?;
+    
+    let tag_names = query.tags();
+    let posts = gallery_posts(&gallery_model, tag_names.clone())
+        .filter(gallery_post::Column::Pk.not_in_subquery(collapsed_reposts(&gallery_model, &tag_names)))
+        .into_json()
+        .all(db.as_ref())
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    debug!("Loaded {} posts from gallery {}", posts.len(), gallery_id);
+
+    decorate_posts_json(&gallery_model, posts, db.as_ref()).await
+}
+
+/// Collapses reposts into one tile and adds the tags and Discord link of each post to its JSON, for the lightbox.
+async fn decorate_posts_json(gallery_model: &gallery::Model, posts: Vec<JsonValue>, db: &DatabaseConnection) -> Result<Vec<JsonValue>, warp::Rejection> {
+    let mut posts = collapse_duplicates(gallery_model, posts, db).await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    let post_pks = posts.iter()
+        .filter_map(|post| post["pk"].as_str().and_then(|pk| Uuid::parse_str(pk).ok()))
+        .collect::<Vec<Uuid>>();
+
+    let mut tags_by_post = tags::find_tags_by_post(db, &post_pks).await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+
+    for post in posts.iter_mut() {
+        let post_tags = post["pk"].as_str()
+            .and_then(|pk| Uuid::parse_str(pk).ok())
+            .and_then(|pk| tags_by_post.remove(&pk))
+            .unwrap_or_default();
+        let discord_url = post["discord_message_id"].as_i64()
+            .map(|message_id| discord_message_url(gallery_model, message_id));
+
+        post["tags"] = post_tags.into();
+        post["discord_url"] = discord_url.into();
+    }
+
+    Ok(posts)
+}
+
+/// Collapses the posts of an image that was posted more than once into a single tile, its earliest visible post,
+/// with the others listed under `also_posted_by`. Posts of the image that aren't among `posts`, because of a filter
+/// or because a live update only carries the new post, are looked up so the tile is the same everywhere.
+/// Where the later posts are left out by `collapsed_reposts` already, this only lists them.
+async fn collapse_duplicates(gallery_model: &gallery::Model, posts: Vec<JsonValue>, db: &DatabaseConnection) -> Result<Vec<JsonValue>, DbErr> {
+    let groups = posts.iter()
+        .filter_map(|post| post["duplicate_group"].as_str().and_then(|group| Uuid::parse_str(group).ok()))
+        .collect::<HashSet<Uuid>>();
+    if groups.is_empty() {
+        return Ok(posts);
+    }
+
+    let group_posts = visible_posts(gallery_model)
+        .filter(gallery_post::Column::DuplicateGroup.is_in(groups))
+        .order_by_asc(gallery_post::Column::DateCreated)
+        .order_by_asc(gallery_post::Column::Pk)
+        .into_json()
+        .all(db)
+        .await?;
+
+    let mut posts_by_group: HashMap<String, Vec<JsonValue>> = HashMap::new();
+    for post in group_posts {
+        if let Some(group) = post["duplicate_group"].as_str() {
+            posts_by_group.entry(group.to_owned()).or_default().push(post);
+        }
+    }
+
+    // The tile takes the place of the group's first post in the list
+    let mut collapsed = Vec::with_capacity(posts.len());
+    let mut seen_groups = HashSet::new();
+    for post in posts {
+        let group = match post["duplicate_group"].as_str() {
+            Some(group) => group.to_owned(),
+            None => {
+                collapsed.push(post);
+                continue;
+            }
+        };
+        if !seen_groups.insert(group.clone()) {
+            continue;
+        }
+
+        let mut group_posts = posts_by_group.remove(&group).unwrap_or_default();
+        if group_posts.len() < 2 {
+            collapsed.push(post);
+            continue;
+        }
+
+        let mut tile = group_posts.remove(0);
+        tile["also_posted_by"] = group_posts.iter()
+            .map(|repost| serde_json::json!({
+                "pk": repost["pk"],
+                "author_name": repost["author_name"],
+                "date_created": repost["date_created"],
+                "discord_url": repost["discord_message_id"].as_i64().map(|message_id| discord_message_url(gallery_model, message_id))
+            }))
+            .collect::<Vec<JsonValue>>()
+            .into();
+        collapsed.push(tile);
+    }
+
+    Ok(collapsed)
+}
+
+/// Loads the visible posts of a gallery matching a full-text search, best matches first on Postgres and newest first elsewhere.
+/// Captions, author names, tags and source domains are searched.
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn search_posts_into_json(gallery_id: Uuid, query: SearchQuery, viewer: Viewer, db: Arc<DatabaseConnection>) -> Result<Vec<JsonValue>, warp::Rejection> {
+    let gallery_model = find_gallery(gallery_id, &viewer, db.as_ref()).await?;
+
+    let search_terms = query.q.trim().to_owned();
+    if search_terms.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let posts = filter_tags(visible_posts(&gallery_model), query.tags());
+    let posts = match db.get_database_backend() {
+        DbBackend::Postgres => posts
+            .filter(Expr::cust_with_values(
+                r#""gallery_post"."search_vector" @@ websearch_to_tsquery('simple', ?)"#,
+                vec![search_terms.clone()]
+            ))
+            .order_by_desc(Expr::cust_with_values(
+                r#"ts_rank("gallery_post"."search_vector", websearch_to_tsquery('simple', ?))"#,
+                vec![search_terms]
+            )),
+        _ => posts.filter(search_words_condition(&search_terms))
+    };
+
+    let posts = posts
+        .order_by_desc(gallery_post::Column::DateCreated)
+        .into_json()
+        .all(db.as_ref())
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))?;
+    debug!("Found {} posts in gallery {} matching {:?}", posts.len(), gallery_id, query.q);
+
+    decorate_posts_json(&gallery_model, posts, db.as_ref()).await
+}
+
+/// Matches posts containing every word of the search in their caption, author name, source url or tags.
+/// Used where Postgres' full-text search isn't available.
+fn search_words_condition(search_terms: &str) -> Condition {
+    search_terms.split_whitespace().fold(Condition::all(), |condition, word| {
+        // Wildcards in the search are matched literally
+        let pattern = format!("%{}%", word.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        let matches_word = ["content", "author_name", "source_url"].iter()
+            .fold(Condition::any(), |any, column| any.add(Expr::cust_with_values(
+                &format!(r#""gallery_post"."{}" LIKE ? ESCAPE '\'"#, column),
+                vec![pattern.clone()]
+            )))
+            .add(Expr::cust_with_values(
+                r#""gallery_post"."pk" IN (
+                    SELECT "gallery_post_tag"."post" FROM "gallery_post_tag"
+                    JOIN "tag" ON "tag"."pk" = "gallery_post_tag"."tag"
+                    WHERE "tag"."name" LIKE ? ESCAPE '\'
+                )"#,
+                vec![pattern]
+            ));
+        condition.add(matches_word)
+    })
+}
+
+/// Streams changes to a gallery as Server-Sent Events.
+/// `created` and `updated` carry the posts as JSON, `deleted` their pks. `resync` means events were missed.
+/// Streams end when shutting down, browsers then reconnect to another server or once this one is back.
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn stream_gallery_events(gallery_id: Uuid, viewer: Viewer, db: Arc<DatabaseConnection>, receiver: broadcast::Receiver<GalleryEvent>, shutdown: Shutdown) -> Result<impl warp::Reply, warp::Rejection> {
+    let gallery_model = Arc::new(find_gallery(gallery_id, &viewer, db.as_ref()).await?);
+    debug!("Streaming live updates of gallery {}", gallery_id);
+
+    let stream = BroadcastStream::new(receiver)
+        .filter_map(move |event| {
+            let gallery_model = gallery_model.clone();
+            let db = db.clone();
+            async move { render_sse_event(event, &gallery_model, db.as_ref()).await.map(Ok::<_, Infallible>) }
+        })
+        .take_until(async move { shutdown.wait().await });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+#[instrument(level = "debug", skip_all, fields(gallery_id = %gallery_model.pk))]
+async fn render_sse_event(
+    event: Result<GalleryEvent, BroadcastStreamRecvError>,
+    gallery_model: &gallery::Model,
+    db: &DatabaseConnection
+) -> Option<sse::Event> {
+    let event = match event {
+        Ok(event) if event.gallery() == gallery_model.pk => event,
+        Ok(_) => return None,
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            warn!("Live updates of gallery {} fell behind by {} events", gallery_model.pk, skipped);
+            return Some(sse::Event::default().event("resync").data(""));
+        }
+    };
+
+    let event_name = match &event {
+        GalleryEvent::PostsCreated { .. } => "created",
+        GalleryEvent::PostsUpdated { .. } => "updated",
+        GalleryEvent::PostsDeleted { posts, .. } => {
+            return sse::Event::default().event("deleted").json_data(posts).ok();
+        }
+    };
+
+    let posts = visible_posts(gallery_model)
+        .filter(gallery_post::Column::Pk.is_in(event.posts().iter().copied()))
+        .order_by_desc(gallery_post::Column::DateCreated)
+        .order_by_desc(gallery_post::Column::Pk)
+        .into_json()
+        .all(db)
+        .await;
+
+    let posts = match posts {
+        Ok(posts) if posts.is_empty() => return None,
+        Ok(posts) => decorate_posts_json(gallery_model, posts, db).await.ok()?,
+        Err(why) => {
+            error!("Error loading posts for live update of gallery {}: {:?}", gallery_model.pk, why);
+            return None;
+        }
+    };
+
+    sse::Event::default().event(event_name).json_data(posts).ok()
+}
+
+/// Loads every tag used in a gallery along with the number of visible posts using it, most used first.
+#[instrument(skip_all, fields(gallery_id = %gallery_id))]
+async fn load_tags_into_json(gallery_id: Uuid, viewer: Viewer, db: Arc<DatabaseConnection>) -> Result<Vec<JsonValue>, warp::Rejection> {
+    let gallery_model = find_gallery(gallery_id, &viewer, db.as_ref()).await?;
+
+    tag::Entity::find()
+        .select_only()
+        .column(tag::Column::Name)
+        .column_as(Expr::tbl(gallery_post_tag::Entity, gallery_post_tag::Column::Post).count(), "count")
+        .join(JoinType::InnerJoin, tag::Relation::GalleryPostTag.def())
+        .join(JoinType::InnerJoin, gallery_post_tag::Relation::GalleryPost.def())
+        .filter(visible_posts_condition(&gallery_model))
+        .group_by(tag::Column::Name)
+        .order_by_desc(Expr::cust("\"count\""))
+        .order_by_asc(tag::Column::Name)
+        .into_json()
+        .all(db.as_ref())
+        .await
+        .map_err(|err| warp::reject::custom(DbError(err)))
+}
+
+#[instrument(level = "debug", skip(gallery_model, db), fields(gallery_id = %gallery_model.pk))]
+async fn find_visible_post(gallery_model: &gallery::Model, post_id: Uuid, db: &DatabaseConnection) -> Result<gallery_post::Model, warp::Rejection> {
+    match visible_posts(gallery_model).filter(gallery_post::Column::Pk.eq(post_id)).one(db).await {
+        Ok(Some(post)) => Ok(post),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(why) => Err(warp::reject::custom(DbError(why)))
+    }
+}
+
+/// Loads a gallery the viewer is allowed to see. Galleries they can't see are reported as not found.
+async fn find_gallery(gallery_id: Uuid, viewer: &Viewer, db: &DatabaseConnection) -> Result<gallery::Model, warp::Rejection> {
+    match check_gallery(gallery_id, viewer, db).await? {
+        GalleryAccess::Allowed(gallery_model) => Ok(gallery_model),
+        GalleryAccess::LoginRequired | GalleryAccess::Denied => Err(warp::reject::not_found())
+    }
+}
+
+enum GalleryAccess {
+    Allowed(gallery::Model),
+    /// The gallery is limited to members of its guild, and the viewer hasn't signed in yet.
+    LoginRequired,
+    Denied
+}
+
+#[instrument(level = "debug", skip(viewer, db))]
+async fn check_gallery(gallery_id: Uuid, viewer: &Viewer, db: &DatabaseConnection) -> Result<GalleryAccess, warp::Rejection> {
+    let gallery_model = match gallery::Entity::find_by_id(gallery_id).one(db).await {
+        Ok(Some(gallery_model)) => gallery_model,
+        Ok(None) => return Err(warp::reject::not_found()),
+        Err(why) => return Err(warp::reject::custom(DbError(why)))
+    };
+
+    if viewer.can_view(&gallery_model).await {
+        Ok(GalleryAccess::Allowed(gallery_model))
+    } else if viewer.could_sign_in(&gallery_model) {
+        Ok(GalleryAccess::LoginRequired)
+    } else {
+        debug!("Denied access to {:?} gallery {}", gallery_model.visibility, gallery_id);
+        Ok(GalleryAccess::Denied)
+    }
+}
+
+/// Selects the posts of a gallery that may be shown publicly.
+/// Posts by artists who opted out in the gallery's guild are left out.
+pub fn visible_posts(gallery_model: &gallery::Model) -> Select<gallery_post::Entity> {
+    gallery_post::Entity::find()
+        .filter(visible_posts_condition(gallery_model))
+}
+
+/// Matches the posts of a gallery that may be shown publicly.
+/// Posts hidden by an admin and posts by artists who opted out in the gallery's guild are left out.
+/// Posts from before authors were recorded are shown until their messages are seen again.
+fn visible_posts_condition(gallery_model: &gallery::Model) -> Condition {
+    let condition = Condition::all()
+        .add(gallery_post::Column::Gallery.eq(gallery_model.pk))
+        .add(gallery_post::Column::Hidden.eq(false));
+
+    match gallery_model.discord_guild_id {
+        Some(guild_id) => condition.add(Condition::any()
+            .add(gallery_post::Column::DiscordAuthorId.is_null())
+            .add(gallery_post::Column::DiscordAuthorId.not_in_subquery(opted_out_users(guild_id)))
+        ),
+        None => condition
+    }
+}
+
+/// Selects the ids of the users who opted out in a guild.
+fn opted_out_users(guild_id: i64) -> SelectStatement {
+    Query::select()
+        .column(artist_optout::Column::DiscordUserId)
+        .from(artist_optout::Entity)
+        .and_where(artist_optout::Column::DiscordGuildId.eq(guild_id))
+        .to_owned()
+}
+
+/// Selects the visible posts of a gallery with every tag in `tag_names`, newest first.
+/// This is the query behind both the posts API and the feeds.
+pub fn gallery_posts(gallery_model: &gallery::Model, tag_names: Vec<String>) -> Select<gallery_post::Entity> {
+    filter_tags(visible_posts(gallery_model), tag_names)
+        .order_by_desc(gallery_post::Column::DateCreated)
+        .order_by_desc(gallery_post::Column::Pk)
+}
+
+/// Selects the pks of the posts of a gallery that are shown in the tile of an earlier visible post of the same image,
+/// so listings can leave them out. With `tag_names`, only earlier posts with every tag count, so filtering by a tag
+/// only on the repost still shows the image.
+fn collapsed_reposts(gallery_model: &gallery::Model, tag_names: &[String]) -> SelectStatement {
+    let later = Alias::new("later");
+    let earlier = Alias::new("earlier");
+    let column = |table: &Alias, column: gallery_post::Column| Expr::tbl(table.clone(), column);
+
+    let mut earlier_condition = Condition::all()
+        .add(column(&earlier, gallery_post::Column::Gallery).eq(gallery_model.pk))
+        .add(column(&earlier, gallery_post::Column::Hidden).eq(false))
+        .add(Condition::any()
+            .add(column(&earlier, gallery_post::Column::DateCreated).less_than(column(&later, gallery_post::Column::DateCreated)))
+            .add(Condition::all()
+                .add(column(&earlier, gallery_post::Column::DateCreated).equals(later.clone(), gallery_post::Column::DateCreated))
+                .add(column(&earlier, gallery_post::Column::Pk).less_than(column(&later, gallery_post::Column::Pk)))
+            )
+        );
+    if let Some(guild_id) = gallery_model.discord_guild_id {
+        earlier_condition = earlier_condition.add(Condition::any()
+            .add(column(&earlier, gallery_post::Column::DiscordAuthorId).is_null())
+            .add(column(&earlier, gallery_post::Column::DiscordAuthorId).not_in_subquery(opted_out_users(guild_id)))
+        );
+    }
+    for tag_name in tag_names {
+        earlier_condition = earlier_condition.add(column(&earlier, gallery_post::Column::Pk).in_subquery(posts_with_tag(tag_name.clone())));
+    }
+
+    Query::select()
+        .column((later.clone(), gallery_post::Column::Pk))
+        .from_as(gallery_post::Entity, later.clone())
+        .join_as(
+            JoinType::InnerJoin,
+            gallery_post::Entity,
+            earlier.clone(),
+            column(&earlier, gallery_post::Column::DuplicateGroup).equals(later.clone(), gallery_post::Column::DuplicateGroup)
+        )
+        .and_where(column(&later, gallery_post::Column::Gallery).eq(gallery_model.pk))
+        .cond_where(earlier_condition)
+        .to_owned()
+}
+
+/// Narrows `posts` down to the ones linked to every tag in `tag_names`.
+fn filter_tags(posts: Select<gallery_post::Entity>, tag_names: Vec<String>) -> Select<gallery_post::Entity> {
+    tag_names.into_iter().fold(posts, |posts, tag_name|
+        posts.filter(gallery_post::Column::Pk.in_subquery(posts_with_tag(tag_name)))
+    )
+}
+
+/// Selects the pks of all posts linked to the tag named `tag_name`.
+fn posts_with_tag(tag_name: String) -> SelectStatement {
+    Query::select()
+        .column((gallery_post_tag::Entity, gallery_post_tag::Column::Post))
+        .from(gallery_post_tag::Entity)
+        .inner_join(tag::Entity, Expr::tbl(tag::Entity, tag::Column::Pk).equals(gallery_post_tag::Entity, gallery_post_tag::Column::Tag))
+        .and_where(Expr::tbl(tag::Entity, tag::Column::Name).eq(tag_name))
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use migration::{Migrator, MigratorTrait};
+    use sea_orm::{ActiveModelTrait, ActiveValue};
+    use serenity::gateway::ConnectionStage;
+    use serenity::http::Http;
+
+    use super::*;
+    use crate::auth::DiscordAuthConfig;
+    use crate::tags;
+    use crate::test_db::{self, day, insert_gallery, insert_post};
+
+    fn pks(posts: &[JsonValue]) -> Vec<Uuid> {
+        posts.iter().map(|post| Uuid::parse_str(post["pk"].as_str().unwrap()).unwrap()).collect()
+    }
+
+    async fn search(gallery_model: &gallery::Model, q: &str, tags: Option<&str>, db: Arc<DatabaseConnection>) -> Vec<Uuid> {
+        let query = SearchQuery { q: q.to_owned(), tags: tags.map(str::to_owned) };
+        pks(&search_posts_into_json(gallery_model.pk, query, Viewer::default(), db).await.unwrap())
+    }
+
+    async fn oembed(url: String, db: Arc<DatabaseConnection>) -> JsonValue {
+        let query = OEmbedQuery { url, format: None, maxwidth: Some(400), maxheight: None };
+        let response = render_oembed(query, db, Arc::new("https://galleria.example".to_owned())).await.unwrap().into_response();
+        serde_json::from_slice(&warp::hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+    }
+
+    async fn check_ingest_and_search(db: DatabaseConnection) {
+        let db = Arc::new(db);
+        let gallery_model = insert_gallery(&db, 10, Some(20)).await;
+        let dragon = insert_post(&db, &gallery_model, 1, 100, "A red dragon #Fantasy", day(1)).await;
+        let castle = insert_post(&db, &gallery_model, 2, 200, "A castle at night #fantasy #night", day(2)).await;
+        let cat = insert_post(&db, &gallery_model, 3, 100, "My cat", day(3)).await;
+        tags::link_tags(db.as_ref(), &[dragon.pk], &["fantasy".to_owned()]).await.unwrap();
+        tags::link_tags(db.as_ref(), &[castle.pk], &["fantasy".to_owned(), "night".to_owned()]).await.unwrap();
+
+        let posts = load_posts_into_json(gallery_model.pk, PostsQuery { tags: None }, Viewer::default(), db.clone()).await.unwrap();
+        assert_eq!(pks(&posts), vec![cat.pk, castle.pk, dragon.pk]);
+        assert_eq!(posts[1]["tags"], serde_json::json!(["fantasy", "night"]));
+
+        let posts = load_posts_into_json(gallery_model.pk, PostsQuery { tags: Some("Fantasy".to_owned()) }, Viewer::default(), db.clone()).await.unwrap();
+        assert_eq!(pks(&posts), vec![castle.pk, dragon.pk]);
+
+        assert_eq!(search(&gallery_model, "dragon", None, db.clone()).await, vec![dragon.pk]);
+        assert_eq!(search(&gallery_model, "castle night", None, db.clone()).await, vec![castle.pk]);
+        assert_eq!(search(&gallery_model, "artist-100", None, db.clone()).await.len(), 2);
+        assert_eq!(search(&gallery_model, "a", Some("night"), db.clone()).await, vec![castle.pk]);
+        assert!(search(&gallery_model, "unicorn", None, db.clone()).await.is_empty());
+
+        // Opting out hides the artist's earlier posts too
+        artist_optout::ActiveModel {
+            discord_guild_id: ActiveValue::Set(20),
+            discord_user_id: ActiveValue::Set(100),
+            ..Default::default()
+        }.insert(db.as_ref()).await.unwrap();
+        let posts = load_posts_into_json(gallery_model.pk, PostsQuery { tags: None }, Viewer::default(), db.clone()).await.unwrap();
+        assert_eq!(pks(&posts), vec![castle.pk]);
+        assert!(search(&gallery_model, "dragon", None, db).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sqlite_ingests_and_searches() {
+        let (db, _) = test_db::sqlite().await;
+        check_ingest_and_search(db).await;
+    }
+
+    #[tokio::test]
+    async fn postgres_ingests_and_searches() {
+        let (db, _, _guard) = match test_db::postgres().await {
+            Some(postgres) => postgres,
+            None => return
+        };
+        check_ingest_and_search(db).await;
+    }
+
+    const ETAG: &str = "W/\"1a2b\"";
+
+    fn last_modified() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 9, 2, 12, 0, 0).unwrap()
+    }
+
+    fn conditional(if_none_match: Option<&str>, if_modified_since: Option<&str>) -> ConditionalHeaders {
+        ConditionalHeaders { if_none_match: if_none_match.map(str::to_owned), if_modified_since: if_modified_since.map(str::to_owned) }
+    }
+
+    #[test]
+    fn unconditional_requests_are_modified() {
+        assert!(!conditional(None, None).is_not_modified(ETAG, last_modified()));
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        assert!(conditional(Some(ETAG), None).is_not_modified(ETAG, last_modified()));
+        assert!(conditional(Some("\"1a2b\""), None).is_not_modified(ETAG, last_modified()));
+        assert!(conditional(Some("\"ffff\", W/\"1a2b\""), None).is_not_modified(ETAG, last_modified()));
+        assert!(conditional(Some("*"), None).is_not_modified(ETAG, last_modified()));
+        assert!(!conditional(Some("W/\"ffff\""), None).is_not_modified(ETAG, last_modified()));
+    }
+
+    #[test]
+    fn if_modified_since_compares_dates() {
+        assert!(conditional(None, Some("Fri, 02 Sep 2022 12:00:00 GMT")).is_not_modified(ETAG, last_modified()));
+        assert!(conditional(None, Some("Sat, 03 Sep 2022 12:00:00 GMT")).is_not_modified(ETAG, last_modified()));
+        assert!(!conditional(None, Some("Thu, 01 Sep 2022 12:00:00 GMT")).is_not_modified(ETAG, last_modified()));
+        assert!(!conditional(None, Some("not a date")).is_not_modified(ETAG, last_modified()));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        // A stale ETag means modified, even if the date says otherwise
+        assert!(!conditional(Some("W/\"ffff\""), Some("Sat, 03 Sep 2022 12:00:00 GMT")).is_not_modified(ETAG, last_modified()));
+        // A matching ETag means not modified, even if the date says otherwise
+        assert!(conditional(Some(ETAG), Some("Thu, 01 Sep 2022 12:00:00 GMT")).is_not_modified(ETAG, last_modified()));
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        let date = to_http_date(last_modified());
+        assert_eq!(date, "Fri, 02 Sep 2022 12:00:00 GMT");
+        assert!(conditional(None, Some(&date)).is_not_modified(ETAG, last_modified()));
+    }
+
+    #[test]
+    fn images_fit_within_the_requested_bounds() {
+        assert_eq!(fit_within(Some(800), Some(600), None, None), (Some(800), Some(600)));
+        assert_eq!(fit_within(Some(800), Some(600), Some(400), None), (Some(400), Some(300)));
+        assert_eq!(fit_within(Some(800), Some(600), Some(400), Some(150)), (Some(200), Some(150)));
+        // Images are never scaled up
+        assert_eq!(fit_within(Some(800), Some(600), Some(1600), Some(1200)), (Some(800), Some(600)));
+    }
+
+    #[test]
+    fn unknown_dimensions_are_left_alone() {
+        assert_eq!(fit_within(None, Some(600), Some(400), None), (None, Some(600)));
+        assert_eq!(fit_within(None, None, Some(400), Some(300)), (None, None));
+    }
+
+    #[tokio::test]
+    async fn sign_ins_return_to_paths_with_cookie_separators() {
+        let (db, _) = test_db::sqlite().await;
+        let config = DiscordAuthConfig {
+            client_id: "client".to_owned(),
+            client_secret: "secret".to_owned(),
+            authorize_url: DiscordAuthConfig::DEFAULT_AUTHORIZE_URL.to_owned(),
+            token_url: DiscordAuthConfig::DEFAULT_TOKEN_URL.to_owned(),
+            api_url: DiscordAuthConfig::DEFAULT_API_URL.to_owned()
+        };
+        let discord_auth = Arc::new(DiscordAuth::new(config, "http://galleria.test", Arc::new(Http::new("token")), Arc::new(db)));
+
+        let path = "/gallery/art?tags=a;b,c";
+        let response = start_login(LoginQuery { redirect: Some(path.to_owned()) }, discord_auth.clone());
+        let login_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let login_cookie = login_cookie.split(';').next().unwrap().to_owned();
+
+        // Declining on the consent screen comes straight back, without asking the provider
+        let query = CallbackQuery { code: None, state: None, error: Some("access_denied".to_owned()) };
+        let response = finish_login(query, Some(format!("other=1; {}", login_cookie)), discord_auth).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()[header::LOCATION], path);
+    }
+
+    #[tokio::test]
+    async fn sign_in_cookies_are_secure_on_https() {
+        let (db, _) = test_db::sqlite().await;
+        let config = DiscordAuthConfig {
+            client_id: "client".to_owned(),
+            client_secret: "secret".to_owned(),
+            authorize_url: DiscordAuthConfig::DEFAULT_AUTHORIZE_URL.to_owned(),
+            token_url: DiscordAuthConfig::DEFAULT_TOKEN_URL.to_owned(),
+            api_url: DiscordAuthConfig::DEFAULT_API_URL.to_owned()
+        };
+        let discord_auth = Arc::new(DiscordAuth::new(config, "https://galleria.example", Arc::new(Http::new("token")), Arc::new(db)));
+
+        let response = start_login(LoginQuery { redirect: None }, discord_auth.clone());
+        assert!(response.headers()[header::SET_COOKIE].to_str().unwrap().ends_with("; SameSite=Lax; Secure"));
+
+        let session = web_session::Model {
+            pk: random_token(),
+            discord_user_id: 1,
+            discord_username: "viewer".to_owned(),
+            access_token: "token".to_owned(),
+            date_expires: Utc::now() + chrono::Duration::hours(1),
+            date_created: Utc::now()
+        };
+        assert!(session_cookie(&session, discord_auth.redirect_url()).ends_with("; SameSite=Lax; Secure"));
+        assert!(session_cookie(&session, "http://galleria.test/auth/callback").ends_with("; SameSite=Lax"));
+    }
+
+    #[tokio::test]
+    async fn oembed_photos_need_dimensions() {
+        let (db, _) = test_db::sqlite().await;
+        let db = Arc::new(db);
+        let gallery_model = insert_gallery(&db, 10, Some(20)).await;
+        let measured = insert_post(&db, &gallery_model, 1, 100, "A dragon", day(1)).await;
+        let mut measured: gallery_post::ActiveModel = measured.into();
+        measured.media_width = ActiveValue::Set(Some(800));
+        measured.media_height = ActiveValue::Set(Some(600));
+        let measured = measured.update(db.as_ref()).await.unwrap();
+        let unmeasured = insert_post(&db, &gallery_model, 2, 100, "A castle", day(2)).await;
+        let post_url = |post: &gallery_post::Model| format!("https://galleria.example/gallery/{}/post/{}", gallery_model.pk, post.pk);
+
+        let response = oembed(post_url(&measured), db.clone()).await;
+        assert_eq!(response["type"], "photo");
+        assert_eq!(response["url"], measured.media_url.unwrap());
+        assert_eq!((&response["width"], &response["height"]), (&serde_json::json!(400), &serde_json::json!(300)));
+        assert_eq!((&response["thumbnail_width"], &response["thumbnail_height"]), (&serde_json::json!(400), &serde_json::json!(300)));
+
+        let response = oembed(post_url(&unmeasured), db).await;
+        assert_eq!(response["type"], "link");
+        assert_eq!(response["thumbnail_url"], unmeasured.media_url.unwrap());
+        for field in ["url", "width", "height", "thumbnail_width", "thumbnail_height"] {
+            assert!(response.get(field).is_none(), "{} should be left out", field);
+        }
+    }
+
+    #[test]
+    fn share_cookies_are_secure_on_https() {
+        let share = Share { gallery: Uuid::nil(), token: "token".to_owned(), expires: Utc::now() + chrono::Duration::hours(1) };
+        assert!(share_cookie(&share, "https://galleria.example").ends_with("; SameSite=Lax; Secure"));
+        assert!(share_cookie(&share, "http://localhost:8080").ends_with("; SameSite=Lax"));
+    }
+
+    #[tokio::test]
+    async fn admin_forms_need_the_sessions_csrf_token() {
+        let session = web_session::Model {
+            pk: random_token(),
+            discord_user_id: 1,
+            discord_username: "admin".to_owned(),
+            access_token: "token".to_owned(),
+            date_expires: day(30),
+            date_created: day(1)
+        };
+        let with_session = {
+            let session = session.clone();
+            warp::any()
+                .and_then(move || {
+                    let session = session.clone();
+                    async move { Ok::<_, warp::Rejection>(((), session)) }
+                })
+                .untuple_one()
+        };
+        let rename = admin_form::<RenameForm, _>(with_session).map(|form: RenameForm, _| form.name);
+        let post = |body: String| warp::test::request()
+            .method("POST")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(body);
+
+        let name = post(format!("csrf_token={}&name=Art", csrf_token(&session))).filter(&rename).await.unwrap();
+        assert_eq!(name, "Art");
+
+        let rejection = post("csrf_token=forged&name=Art".to_owned()).filter(&rename).await.unwrap_err();
+        assert!(rejection.find::<CsrfMismatch>().is_some());
+
+        // Forms from before tokens were added don't parse
+        assert!(post("name=Art".to_owned()).filter(&rename).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn readiness_needs_the_database_and_gateway() {
+        let (db, pool) = test_db::sqlite().await;
+        let gateway = Arc::new(GatewayStatus::default());
+        let shutdown = Shutdown::new();
+        let probes = health(Arc::new(db), Some(gateway.clone()), shutdown.clone())
+            .or(metrics_endpoint(Arc::new(Metrics::new(pool))));
+        let ready = || async {
+            let response = warp::test::request().path("/readyz").reply(&probes).await;
+            (response.status(), serde_json::from_slice::<JsonValue>(response.body()).unwrap())
+        };
+
+        assert_eq!(warp::test::request().path("/healthz").reply(&probes).await.status(), StatusCode::OK);
+
+        let (status, body) = ready().await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["database"], true);
+        assert_eq!(body["gateway"], false);
+        let newest_migration = Migrator::migrations().last().map(|migration| migration.name().to_owned());
+        assert_eq!(body["schema_version"], serde_json::json!(newest_migration));
+
+        gateway.update(0, ConnectionStage::Connected);
+        assert_eq!(ready().await.0, StatusCode::OK);
+
+        shutdown.trigger();
+        let (status, body) = ready().await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["shutting_down"], true);
+
+        let metrics = warp::test::request().path("/metrics").reply(&probes).await;
+        assert!(String::from_utf8_lossy(metrics.body()).contains("galleria_db_connections"));
+    }
+
+    async fn group(db: &DatabaseConnection, post: &gallery_post::Model, group: Uuid) {
+        let mut active_post: gallery_post::ActiveModel = post.clone().into();
+        active_post.duplicate_group = ActiveValue::Set(Some(group));
+        active_post.update(db).await.unwrap();
+    }
+
+    async fn check_collapsed_reposts(db: DatabaseConnection) {
+        let db = Arc::new(db);
+        let gallery_model = insert_gallery(&db, 10, Some(20)).await;
+        let original = insert_post(&db, &gallery_model, 1, 100, "A dragon", day(1)).await;
+        let other = insert_post(&db, &gallery_model, 2, 200, "A castle", day(2)).await;
+        let repost = insert_post(&db, &gallery_model, 3, 300, "Look at this dragon", day(3)).await;
+        group(&db, &original, original.pk).await;
+        group(&db, &repost, original.pk).await;
+
+        let posts = load_posts_into_json(gallery_model.pk, PostsQuery { tags: None }, Viewer::default(), db.clone()).await.unwrap();
+        assert_eq!(pks(&posts), vec![other.pk, original.pk]);
+        assert_eq!(posts[1]["also_posted_by"][0]["pk"], serde_json::json!(repost.pk.to_string()));
+        assert_eq!(posts[1]["also_posted_by"].as_array().map(Vec::len), Some(1));
+
+        // A search finding both shows the image once
+        assert_eq!(search(&gallery_model, "dragon", None, db.clone()).await, vec![original.pk]);
+
+        // Filtering by a tag only the repost has still shows the image, as the same tile
+        tags::link_tags(db.as_ref(), &[repost.pk], &["dragons".to_owned()]).await.unwrap();
+        let posts = load_posts_into_json(gallery_model.pk, PostsQuery { tags: Some("dragons".to_owned()) }, Viewer::default(), db.clone()).await.unwrap();
+        assert_eq!(pks(&posts), vec![original.pk]);
+
+        // With the original hidden the repost is the tile
+        let mut hidden: gallery_post::ActiveModel = original.clone().into();
+        hidden.hidden = ActiveValue::Set(true);
+        hidden.update(db.as_ref()).await.unwrap();
+        let posts = load_posts_into_json(gallery_model.pk, PostsQuery { tags: None }, Viewer::default(), db.clone()).await.unwrap();
+        assert_eq!(pks(&posts), vec![repost.pk, other.pk]);
+        assert!(posts[0]["also_posted_by"].is_null());
+    }
+
+    #[tokio::test]
+    async fn sqlite_collapses_reposts() {
+        let (db, _) = test_db::sqlite().await;
+        check_collapsed_reposts(db).await;
+    }
+
+    #[tokio::test]
+    async fn postgres_collapses_reposts() {
+        let (db, _, _guard) = match test_db::postgres().await {
+            Some(postgres) => postgres,
+            None => return
+        };
+        check_collapsed_reposts(db).await;
+    }
 }
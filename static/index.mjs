@@ -1,21 +1,195 @@
 import {Component, html, render} from 'https://unpkg.com/htm/preact/index.mjs?module';
 
+const gallery_id = window.location.pathname.split("/")[2];
+const gallery_path = `/gallery/${gallery_id}`;
+
+/**
+ * Returns the pk of the post the current url points at, if any.
+ * @returns {string?}
+ */
+function postFromLocation() {
+    const match = window.location.pathname.match(/^\/gallery\/[^/]+\/post\/([^/]+)/);
+    return match ? match[1] : null;
+}
+
+/**
+ * @typedef AppState
+ * @type {object}
+ * @property {object[]?} page_data
+ * @property {object[]} tags
+ * @property {string[]} selected_tags
+ * @property {string} search
+ * @property {string?} open_post
+ */
+
+class App extends Component {
+    constructor() {
+        super();
+        /** @type {AppState} */
+        this.state = { page_data: null, tags: [], selected_tags: [], search: "", open_post: postFromLocation() };
+        this.onPopState = () => this.setState({ open_post: postFromLocation() });
+    }
+
+    componentDidMount() {
+        window.addEventListener("popstate", this.onPopState);
+        fetch(`/api/v1/gallery/tags/${gallery_id}`)
+            .then((response) => response.json())
+            .then((tags) => this.setState({ tags }));
+        this.loadPosts([], "");
+        this.subscribe();
+    }
+
+    /**
+     * Keeps the gallery up to date with posts as they are created, edited and deleted in Discord.
+     */
+    subscribe() {
+        this.events = new EventSource(`/api/v1/gallery/${gallery_id}/events`);
+
+        this.events.addEventListener("created", (event) => {
+            const posts = JSON.parse(event.data);
+            // New posts might not match an active search or tag filter, so leave filtered views alone
+            if (this.state.page_data && this.state.search === "" && this.state.selected_tags.length === 0) {
+                // Reposts come as the tile of the earlier post, which is already shown
+                const known = new Set(this.state.page_data.map((post) => post.pk));
+                const updated = new Map(posts.map((post) => [post.pk, post]));
+                this.setState({ page_data: [
+                    ...posts.filter((post) => !known.has(post.pk)),
+                    ...this.state.page_data.map((post) => updated.get(post.pk) ?? post)
+                ] });
+            }
+        });
+
+        this.events.addEventListener("updated", (event) => {
+            const posts = new Map(JSON.parse(event.data).map((post) => [post.pk, post]));
+            if (this.state.page_data) {
+                this.setState({ page_data: this.state.page_data.map((post) => posts.get(post.pk) ?? post) });
+            }
+        });
+
+        this.events.addEventListener("deleted", (event) => {
+            const deleted = new Set(JSON.parse(event.data));
+            if (this.state.page_data) {
+                this.setState({ page_data: this.state.page_data.filter((post) => !deleted.has(post.pk)) });
+            }
+        });
+
+        this.events.addEventListener("resync", () => this.loadPosts(this.state.selected_tags, this.state.search));
+    }
+
+    /**
+     * @param {string[]} selected_tags 
+     * @param {string} search
+     */
+    loadPosts(selected_tags, search) {
+        const params = new URLSearchParams();
+        if (selected_tags.length > 0) {
+            params.set("tags", selected_tags.join(","));
+        }
+
+        let url = `/api/v1/gallery/posts/${gallery_id}`;
+        if (search.trim() !== "") {
+            url = `/api/v1/gallery/${gallery_id}/search`;
+            params.set("q", search);
+        }
+
+        fetch(`${url}?${params}`)
+            .then((response) => response.json())
+            .then((page_data) => this.setState({ page_data, selected_tags, search }));
+    }
+
+    /**
+     * @param {string} tag 
+     */
+    toggleTag(tag) {
+        const selected_tags = this.state.selected_tags.includes(tag)
+            ? this.state.selected_tags.filter((t) => t !== tag)
+            : [...this.state.selected_tags, tag];
+        this.loadPosts(selected_tags, this.state.search);
+    }
+
+    componentWillUnmount() {
+        window.removeEventListener("popstate", this.onPopState);
+        this.events?.close();
+    }
+
+    /**
+     * Opens a post in the lightbox, or closes the lightbox when `pk` is null.
+     * @param {string?} pk 
+     */
+    openPost(pk) {
+        const url = pk ? `${gallery_path}/post/${pk}` : gallery_path;
+        if (window.location.pathname !== url) {
+            window.history.pushState(null, "", url);
+        }
+        this.setState({ open_post: pk });
+    }
+
+    /**
+     * @param {SubmitEvent} event 
+     */
+    submitSearch(event) {
+        event.preventDefault();
+        this.loadPosts(this.state.selected_tags, event.target.elements.q.value);
+    }
+
+    render(_, state) {
+        return html`
+        <form class="search" role="search" onSubmit=${(event) => this.submitSearch(event)}>
+            <input type="search" name="q" placeholder="Search captions, artists and tags" value=${state.search} />
+        </form>
+        <${TagCloud} tags=${state.tags} selected_tags=${state.selected_tags} onToggle=${(tag) => this.toggleTag(tag)} />
+        ${state.page_data && html`<${Gallery} page_data=${state.page_data} onOpen=${(pk) => this.openPost(pk)} />`}
+        ${state.page_data && state.open_post && html`
+        <${Lightbox} posts=${state.page_data} open_post=${state.open_post} onNavigate=${(pk) => this.openPost(pk)} />`}
+        `;
+    }
+}
+
 /**
- * @typedef AppProps
+ * @typedef TagCloudProps
+ * @type {object}
+ * @property {{name: string, count: number}[]} tags
+ * @property {string[]} selected_tags
+ * @property {function(string): void} onToggle
+ */
+
+/**
+ * @param {TagCloudProps} props 
+ */
+function TagCloud(props) {
+    if (props.tags.length === 0) {
+        return null;
+    }
+
+    return html`
+    <nav class="tag-cloud">
+        ${props.tags.map((tag) => html`
+        <button
+            class=${props.selected_tags.includes(tag.name) ? "tag selected" : "tag"}
+            onClick=${() => props.onToggle(tag.name)}>
+            #${tag.name} <span class="tag-count">${tag.count}</span>
+        </button>`)}
+    </nav>
+    `;
+}
+
+/**
+ * @typedef GalleryProps
  * @type {object}
  * @property {object[]} page_data 
+ * @property {function(string): void} onOpen
  */
 
 /**
- * @param {AppProps} props 
+ * @param {GalleryProps} props 
  */
-function App(props) {
+function Gallery(props) {
     if (props.page_data.length === 0) {
         return html`Looks like this gallery has no posts!`;
     } else {
         return html`
         <div class="gallery" role="list">
-            ${props.page_data.map((post) => html`<${GalleryImage} ...${post} />`)}
+            ${props.page_data.map((post) => html`<${GalleryImage} ...${post} onOpen=${props.onOpen} />`)}
         </div>
         `;
     }
@@ -24,11 +198,13 @@ function App(props) {
 /**
  * @typedef GalleryImageProps
  * @type {object}
+ * @property {string} pk
  * @property {string?} source_url
  * @property {string?} media_url
  * @property {number?} media_width
  * @property {number?} media_height
-
+ * @property {object[]?} also_posted_by
+ * @property {function(string): void} onOpen
  */
 
 /**
@@ -54,14 +230,122 @@ function GalleryImage(props) {
     }
 
     let image = html`<img ...${img_props} />`;
+
+    /**
+     * Plain clicks open the lightbox, anything else (new tab, etc.) follows the permalink.
+     * @param {MouseEvent} event 
+     */
+    const onClick = (event) => {
+        if (event.button === 0 && !event.ctrlKey && !event.metaKey && !event.shiftKey && !event.altKey) {
+            event.preventDefault();
+            props.onOpen(props.pk);
+        }
+    };
     
+    const reposts = props.also_posted_by ? props.also_posted_by.length : 0;
+
     return html`<div class="gallery-item" role="listitem">
-        ${props.source_url && html`
-        <a href=${props.source_url} rel="noreferrer" target="_blank">
+        <a href=${`${gallery_path}/post/${props.pk}`} onClick=${onClick}>
             ${image}
-        </a>`}
-        ${!props.source_url && image}
+        </a>
+        ${reposts > 0 && html`<span class="repost-count" title=${`Posted ${reposts + 1} times`}>×${reposts + 1}</span>`}
     </div>`
 }
 
-render(html`<${App} page_data=${page_data} />`, document.getElementById("app-container"));
\ No newline at end of file
+/**
+ * @typedef LightboxProps
+ * @type {object}
+ * @property {object[]} posts
+ * @property {string} open_post
+ * @property {function(string?): void} onNavigate
+ */
+
+/**
+ * Shows a single post over the gallery. The arrow keys move between posts and escape closes it.
+ */
+class Lightbox extends Component {
+    constructor() {
+        super();
+        this.onKeyDown = (event) => this.handleKey(event);
+    }
+
+    componentDidMount() {
+        document.addEventListener("keydown", this.onKeyDown);
+        document.body.classList.add("lightbox-open");
+    }
+
+    componentWillUnmount() {
+        document.removeEventListener("keydown", this.onKeyDown);
+        document.body.classList.remove("lightbox-open");
+    }
+
+    /**
+     * @param {number} offset 
+     * @returns {object?}
+     */
+    neighbor(offset) {
+        const index = this.props.posts.findIndex((post) => post.pk === this.props.open_post);
+        return index === -1 ? null : (this.props.posts[index + offset] ?? null);
+    }
+
+    /**
+     * @param {KeyboardEvent} event 
+     */
+    handleKey(event) {
+        if (event.altKey || event.ctrlKey || event.metaKey || event.shiftKey) {
+            return;
+        }
+
+        let target;
+        if (event.key === "Escape") {
+            this.props.onNavigate(null);
+        } else if (event.key === "ArrowLeft" && (target = this.neighbor(-1))) {
+            this.props.onNavigate(target.pk);
+        } else if (event.key === "ArrowRight" && (target = this.neighbor(1))) {
+            this.props.onNavigate(target.pk);
+        } else {
+            return;
+        }
+        event.preventDefault();
+    }
+
+    render(props) {
+        const post = props.posts.find((post) => post.pk === props.open_post);
+        if (!post) {
+            return null;
+        }
+
+        const newer = this.neighbor(-1);
+        const older = this.neighbor(1);
+
+        return html`
+        <div class="lightbox" role="dialog" aria-modal="true" onClick=${(event) => event.target === event.currentTarget && props.onNavigate(null)}>
+            <button class="lightbox-close" aria-label="Close" onClick=${() => props.onNavigate(null)}>×</button>
+            ${newer && html`<button class="lightbox-prev" aria-label="Newer post" onClick=${() => props.onNavigate(newer.pk)}>‹</button>`}
+            ${older && html`<button class="lightbox-next" aria-label="Older post" onClick=${() => props.onNavigate(older.pk)}>›</button>`}
+            <figure>
+                <img src=${post.media_url || post.thumbnail_url} rel="noreferrer" />
+                <figcaption>
+                    ${post.author_name && html`<p class="post-author">${post.author_name}</p>`}
+                    ${post.content && html`<p class="post-content">${post.content}</p>`}
+                    ${post.tags && post.tags.length > 0 && html`
+                    <ul class="post-tags">
+                        ${post.tags.map((tag) => html`<li class="tag">#${tag}</li>`)}
+                    </ul>`}
+                    ${post.also_posted_by && post.also_posted_by.length > 0 && html`
+                    <p class="post-reposts">Also posted by${" "}
+                        ${post.also_posted_by.map((repost, index) => html`${index > 0 && ", "}<a href=${repost.discord_url}>${repost.author_name || "someone"}</a>`)}
+                    </p>`}
+                    <ul class="post-links">
+                        <li><a href=${`${gallery_path}/post/${post.pk}`}>Permalink</a></li>
+                        ${post.source_url && html`<li><a href=${post.source_url} rel="noreferrer" target="_blank">Source</a></li>`}
+                        ${post.discord_url && html`<li><a href=${post.discord_url}>View in Discord</a></li>`}
+                    </ul>
+                </figcaption>
+            </figure>
+        </div>
+        `;
+    }
+}
+
+render(html`<${App} />`, document.getElementById("app-container"));
\ No newline at end of file
@@ -0,0 +1,23 @@
+/**
+ * Keyboard navigation for post permalink pages.
+ * The arrow keys follow the page's prev and next links, escape goes back up to the gallery.
+ */
+
+const key_rels = {
+    "ArrowLeft": "prev",
+    "ArrowRight": "next",
+    "Escape": "up"
+};
+
+document.addEventListener("keydown", (event) => {
+    if (event.altKey || event.ctrlKey || event.metaKey || event.shiftKey) {
+        return;
+    }
+
+    const rel = key_rels[event.key];
+    const link = rel && document.querySelector(`link[rel="${rel}"]`);
+    if (link) {
+        event.preventDefault();
+        window.location.href = link.href;
+    }
+});